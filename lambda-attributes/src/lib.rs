@@ -8,35 +8,214 @@
 //! accept an argument of type `A` which implements [`serde::Deserialize`], a [`lambda::Context`] and
 //! return a `Result<B, E>`, where `B` implements [`serde::Serializable`]. `E` is
 //! any type that implements `Into<Box<dyn std::error::Error + Send + Sync + 'static>>`.
+//! The context argument can be left out, and the function can return a plain `B`
+//! when it never fails.
+//!
+//! An async function that isn't decorated with `#[tokio::main]` runs through
+//! `lamedh_runtime::main`, on a Tokio runtime sized for the vCPUs of the function.
+//!
+//! A synchronous function can be annotated with `#[lambda]` too. In that case the function
+//! runs on a blocking thread through `lamedh_runtime::run_sync`, and it doesn't need to be
+//! decorated with `#[tokio::main]`.
+//!
+//! One-time initialization can be declared with `#[lambda(init = setup)]`, where `setup` is
+//! an async function that returns a `Result<S, E>`. It runs once before the first invocation,
+//! and its output is passed to the annotated function as a `&S` argument.
+//! If it fails, the error is reported to Lambda as an init error.
+//!
+//! The attribute accepts the following arguments:
+//!
+//! - `http`: the function handles ALB and API Gateway REST and HTTP API events through `lamedh_http`.
+//! - `http(origin = "apigw_v1")`: the function only handles API Gateway REST events, which is
+//!   also the format used by the AWS Invoke API. `http::invoke` is an alias for this form.
+//! - `sync`: the function is synchronous and runs on a blocking thread.
+//! - `stream`: reserved for response streaming, which isn't supported yet.
+//! - `init = path`: the async function that runs the init phase.
+//!
+//! The companion `#[lambda_test("tests/events/*.json")]` attribute generates one test per
+//! event fixture for a handler function. Each test invokes the handler with the fixture, and
+//! compares the output with the sibling `*.expected.json` snapshot.
 //! ```
 
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote_spanned;
-use syn::{spanned::Spanned, AttributeArgs, FnArg, ItemFn, Meta, NestedMeta};
-
-/// Return true if attribute macro args declares http flavor in the form `#[lambda(http)]`
-fn is_http(args: &AttributeArgs) -> bool {
-    args.iter().any(|arg| match arg {
-        NestedMeta::Meta(Meta::Path(path)) => path.is_ident("http"),
-        _ => false,
-    })
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use std::{
+    collections::HashSet,
+    env,
+    path::{Path as FsPath, PathBuf},
+};
+use syn::{
+    parenthesized,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    spanned::Spanned,
+    Attribute, FnArg, ItemFn, Lifetime, LitStr, PatType, Path, ReturnType, Token, Type,
+};
+
+/// The forms accepted by the `#[lambda(...)]` attribute, used in diagnostics.
+const VALID_FORMS: &str =
+    "expected one of: `http`, `http(origin = \"apigw_v1\")`, `http::invoke`, `sync`, `stream`, `init = path`";
+
+/// The form of the init argument, used in diagnostics.
+const VALID_INIT: &str =
+    "expected `init = path`, where `path` is an async setup function; init blocks aren't supported";
+
+/// The event formats that `#[lambda(http(origin = "..."))]` accepts.
+const VALID_ORIGINS: &str = "expected `origin = \"apigw_v1\"`";
+
+/// The event format that an http function handles.
+#[derive(Clone, Copy, PartialEq)]
+enum HttpOrigin {
+    /// Any ALB or API Gateway event, detected at runtime.
+    Any,
+    /// API Gateway REST events only, which is the format used by the AWS Invoke API.
+    ApiGatewayV1,
+}
+
+/// A single argument of the `#[lambda(...)]` attribute.
+enum LambdaArg {
+    /// `http`, `http(origin = "...")` or `http::invoke`.
+    Http(Span, HttpOrigin),
+    /// `sync`.
+    Sync(Span),
+    /// `stream`.
+    Stream(Span),
+    /// `init = setup_fn`.
+    Init(Path),
+}
+
+impl Parse for LambdaArg {
+    fn parse(input: ParseStream<'_>) -> syn::Result<Self> {
+        let key = input.call(Path::parse_mod_style)?;
+        let span = key.span();
+        let segments: Vec<String> = key.segments.iter().map(|s| s.ident.to_string()).collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+        match segments.as_slice() {
+            ["http"] if input.peek(syn::token::Paren) => {
+                let content;
+                parenthesized!(content in input);
+                let name = content.call(Path::parse_mod_style)?;
+                if !name.is_ident("origin") {
+                    return Err(syn::Error::new(name.span(), VALID_ORIGINS));
+                }
+                content.parse::<Token![=]>()?;
+                let origin: LitStr = content.parse()?;
+                match origin.value().as_str() {
+                    "apigw_v1" => Ok(LambdaArg::Http(span, HttpOrigin::ApiGatewayV1)),
+                    _ => Err(syn::Error::new(origin.span(), VALID_ORIGINS)),
+                }
+            }
+            ["http"] => Ok(LambdaArg::Http(span, HttpOrigin::Any)),
+            ["http", "invoke"] => Ok(LambdaArg::Http(span, HttpOrigin::ApiGatewayV1)),
+            ["sync"] => Ok(LambdaArg::Sync(span)),
+            ["stream"] => Ok(LambdaArg::Stream(span)),
+            ["init"] => {
+                if !input.peek(Token![=]) {
+                    return Err(syn::Error::new(span, VALID_INIT));
+                }
+                input.parse::<Token![=]>()?;
+                let path = input.parse().map_err(|e| syn::Error::new(e.span(), VALID_INIT))?;
+                Ok(LambdaArg::Init(path))
+            }
+            _ => Err(syn::Error::new(
+                span,
+                format!("unknown #[lambda] argument, {}", VALID_FORMS),
+            )),
+        }
+    }
+}
+
+/// The arguments of the `#[lambda(...)]` attribute.
+#[derive(Default)]
+struct LambdaArgs {
+    http: Option<HttpOrigin>,
+    sync: Option<Span>,
+    init: Option<Path>,
+}
+
+impl Parse for LambdaArgs {
+    fn parse(input: ParseStream<'_>) -> syn::Result<Self> {
+        let list = Punctuated::<LambdaArg, Token![,]>::parse_terminated(input)?;
+        let mut args = LambdaArgs::default();
+        for arg in list {
+            match arg {
+                LambdaArg::Http(span, _) | LambdaArg::Sync(span) if args.http.is_some() || args.sync.is_some() => {
+                    return Err(syn::Error::new(
+                        span,
+                        "`http` and `sync` can only be declared once, and can't be combined",
+                    ));
+                }
+                LambdaArg::Http(_, origin) => args.http = Some(origin),
+                LambdaArg::Sync(span) => args.sync = Some(span),
+                LambdaArg::Stream(span) => {
+                    return Err(syn::Error::new(
+                        span,
+                        "response streaming isn't supported by lamedh_runtime yet",
+                    ));
+                }
+                LambdaArg::Init(path) if args.init.is_some() => {
+                    return Err(syn::Error::new(path.span(), "init can only be declared once"));
+                }
+                LambdaArg::Init(path) => args.init = Some(path),
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// The role of an argument of the annotated function.
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Event,
+    Context,
+    State,
+}
+
+/// Return true if the function returns a `Result`, and false if it returns a plain value.
+fn returns_result(ret: &ReturnType) -> bool {
+    match ret {
+        ReturnType::Type(_, ty) => match &**ty {
+            Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .map(|segment| segment.ident == "Result")
+                .unwrap_or_default(),
+            _ => false,
+        },
+        ReturnType::Default => false,
+    }
+}
+
+/// Return the state type with a `'static` lifetime, since the state lives for the rest of the process.
+fn static_state_type(ty: &Type) -> Type {
+    let mut ty = ty.clone();
+    if let Type::Reference(reference) = &mut ty {
+        if reference.lifetime.is_none() {
+            reference.lifetime = Some(Lifetime::new("'static", reference.and_token.span));
+        }
+    }
+    ty
 }
 
-/// Return true if attribute macro args declares http flavor in the form `#[lambda(http::invoke)]`
-fn is_http_invoke(args: &AttributeArgs) -> bool {
-    args.iter().any(|arg| match arg {
-        NestedMeta::Meta(Meta::Path(path)) => path.is_ident("http::invoke"),
-        _ => false,
-    })
+/// Return true if the attribute starts an async runtime, like `#[tokio::main]` does.
+fn is_main_attr(attr: &Attribute) -> bool {
+    attr.path
+        .segments
+        .last()
+        .map(|segment| segment.ident == "main")
+        .unwrap_or_default()
 }
 
 #[proc_macro_attribute]
 /// Wrap an async function into the lambda constructs
 pub fn lambda(attr: TokenStream, item: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(item as ItemFn);
-    let args = syn::parse_macro_input!(attr as AttributeArgs);
+    let args = syn::parse_macro_input!(attr as LambdaArgs);
     let ret = &input.sig.output;
     let name = &input.sig.ident;
     let body = &input.block;
@@ -51,78 +230,313 @@ pub fn lambda(attr: TokenStream, item: TokenStream) -> TokenStream {
         return TokenStream::from(tokens);
     }
 
-    if asyncness.is_none() {
+    if let (Some(span), Some(_)) = (args.sync, asyncness) {
+        let tokens = quote_spanned! { span =>
+            compile_error!("#[lambda(sync)] must be placed on a function without the async keyword");
+        };
+        return TokenStream::from(tokens);
+    }
+
+    if asyncness.is_none() && (args.http.is_some() || args.init.is_some()) {
         let tokens = quote_spanned! { input.span() =>
           compile_error!("the async keyword is missing from the function declaration");
         };
         return TokenStream::from(tokens);
     }
 
-    let result = match inputs.len() {
-        2 => {
-            let event = match inputs.first().expect("expected event argument") {
-                FnArg::Typed(arg) => arg,
-                _ => {
-                    let tokens = quote_spanned! { inputs.span() =>
-                        compile_error!("fn main's first argument must be fully formed");
-                    };
-                    return TokenStream::from(tokens);
-                }
+    // The first argument is always the event. A reference is the state from
+    // the init phase, and anything else is the lambda context.
+    let mut typed: Vec<(&PatType, Role)> = Vec::with_capacity(inputs.len());
+    for (idx, arg) in inputs.iter().enumerate() {
+        let arg = match arg {
+            FnArg::Typed(arg) => arg,
+            _ => {
+                let tokens = quote_spanned! { arg.span() =>
+                    compile_error!("fn main's arguments must be fully formed");
+                };
+                return TokenStream::from(tokens);
+            }
+        };
+        let role = match (idx, &*arg.ty) {
+            (0, _) => Role::Event,
+            (_, Type::Reference(_)) if args.init.is_some() => Role::State,
+            _ => Role::Context,
+        };
+        if typed.iter().any(|(_, r)| *r == role) {
+            let tokens = quote_spanned! { arg.span() =>
+                compile_error!("The #[lambda] macro expects a triggered event, an optional lambda context and, with `init = ...`, a reference to the initialized state.");
             };
-            let event_name = &event.pat;
-            let event_type = &event.ty;
-            let context = match inputs.iter().nth(1).expect("expected context argument") {
-                FnArg::Typed(arg) => arg,
-                _ => {
-                    let tokens = quote_spanned! { inputs.span() =>
-                        compile_error!("fn main's second argument must be fully formed");
-                    };
-                    return TokenStream::from(tokens);
-                }
+            return TokenStream::from(tokens);
+        }
+        typed.push((arg, role));
+    }
+
+    if typed.is_empty() {
+        let tokens = quote_spanned! { inputs.span() =>
+            compile_error!("The #[lambda] macro expects at least one argument: a triggered event.");
+        };
+        return TokenStream::from(tokens);
+    }
+
+    let has_state = typed.iter().any(|(_, role)| *role == Role::State);
+    if args.init.is_some() && !has_state {
+        let tokens = quote_spanned! { inputs.span() =>
+            compile_error!("The #[lambda(init = ...)] macro expects a reference to the initialized state as an argument.");
+        };
+        return TokenStream::from(tokens);
+    }
+
+    let (error_path, context_path) = if args.http.is_some() {
+        (quote!(lamedh_http::lambda::Error), quote!(lamedh_http::Context))
+    } else {
+        (quote!(lamedh_runtime::Error), quote!(lamedh_runtime::Context))
+    };
+
+    let params = typed.iter().map(|(arg, _)| arg);
+    let actual = if asyncness.is_none() {
+        quote_spanned! { input.span() => fn actual(#(#params),*) #ret #body }
+    } else {
+        quote_spanned! { input.span() => async fn actual(#(#params),*) #ret #body }
+    };
+
+    // The function is passed to the runtime as is when it has the canonical signature,
+    // otherwise it's wrapped in a closure that adapts it to the runtime's expectations.
+    let canonical_roles: &[Role] = if args.init.is_some() {
+        &[Role::Event, Role::Context, Role::State]
+    } else {
+        &[Role::Event, Role::Context]
+    };
+    let roles: Vec<Role> = typed.iter().map(|(_, role)| *role).collect();
+    let handler = if roles == canonical_roles && returns_result(ret) {
+        quote!(actual)
+    } else {
+        let event_type = &typed[0].0.ty;
+        let context_type = typed
+            .iter()
+            .find(|(_, role)| *role == Role::Context)
+            .map(|(arg, _)| {
+                let ty = &arg.ty;
+                quote!(#ty)
+            })
+            .unwrap_or_else(|| context_path.clone());
+        let call_args = typed.iter().map(|(_, role)| match role {
+            Role::Event => quote!(__event),
+            Role::Context => quote!(__context),
+            Role::State => quote!(__state),
+        });
+        let call = if asyncness.is_none() {
+            quote!(actual(#(#call_args),*))
+        } else {
+            quote!(actual(#(#call_args),*).await)
+        };
+        let output = if returns_result(ret) {
+            call
+        } else {
+            quote!(Ok::<_, #error_path>(#call))
+        };
+
+        if asyncness.is_none() {
+            quote!(|__event: #event_type, __context: #context_type| #output)
+        } else if let Some((state, _)) = typed.iter().find(|(_, role)| *role == Role::State) {
+            let state_type = static_state_type(&state.ty);
+            quote!(|__event: #event_type, __context: #context_type, __state: #state_type| async move { #output })
+        } else {
+            quote!(|__event: #event_type, __context: #context_type| async move { #output })
+        }
+    };
+
+    let run = match (&args.init, args.http) {
+        (Some(init), Some(HttpOrigin::Any)) => quote! {
+            let f = lamedh_http::handler_with_state(#handler);
+            lamedh_http::lambda::run_with_init(#init, f).await
+        },
+        (Some(init), Some(HttpOrigin::ApiGatewayV1)) => {
+            let tokens = quote_spanned! { init.span() =>
+                compile_error!("#[lambda(http(origin = \"apigw_v1\"))] doesn't support init functions");
             };
-            let context_name = &context.pat;
-            let context_type = &context.ty;
+            return TokenStream::from(tokens);
+        }
+        (Some(init), None) => quote! {
+            lamedh_runtime::run_with_init(#init, #handler).await
+        },
+        (None, Some(HttpOrigin::Any)) => quote! {
+            let f = lamedh_http::handler(#handler);
+            lamedh_http::lambda::run(f).await
+        },
+        (None, Some(HttpOrigin::ApiGatewayV1)) => quote! {
+            let f = lamedh_http::proxy_handler(#handler);
+            lamedh_http::lambda::run(f).await
+        },
+        (None, None) if asyncness.is_none() => quote! {
+            lamedh_runtime::run_sync(#handler)
+        },
+        (None, None) => quote! {
+            let f = lamedh_runtime::handler_fn(#handler);
+            lamedh_runtime::run(f).await
+        },
+    };
 
-            if is_http(&args) {
-                quote_spanned! { input.span() =>
+    // An async main function that isn't decorated with `#[tokio::main]` gets a Tokio
+    // runtime sized for the function.
+    let result: TokenStream2 = if asyncness.is_some() && !attrs.iter().any(is_main_attr) {
+        let main_path = if args.http.is_some() {
+            quote!(lamedh_http::lambda::main)
+        } else {
+            quote!(lamedh_runtime::main)
+        };
+        quote_spanned! { input.span() =>
 
-                    #(#attrs)*
-                    #asyncness fn main() {
-                        async fn actual(#event_name: #event_type, #context_name: #context_type) #ret #body
+            #(#attrs)*
+            fn main() -> Result<(), #error_path> {
+                #actual
 
-                        let f = lamedh_http::handler(actual);
-                        lamedh_http::lambda::run(f).await.unwrap();
-                    }
-                }
-            } else if is_http_invoke(&args) {
-                quote_spanned! { input.span() =>
+                #main_path(async { #run })
+            }
+        }
+    } else {
+        quote_spanned! { input.span() =>
 
-                    #(#attrs)*
-                    #asyncness fn main() {
-                        async fn actual(#event_name: #event_type, #context_name: #context_type) #ret #body
+            #(#attrs)*
+            #asyncness fn main() -> Result<(), #error_path> {
+                #actual
 
-                        let f = lamedh_http::proxy_handler(actual);
-                        lamedh_http::lambda::run(f).await.unwrap();
-                    }
-                }
+                #run
+            }
+        }
+    };
+
+    result.into()
+}
+
+/// The suffix of snapshot files, which are never fixtures themselves.
+const SNAPSHOT_SUFFIX: &str = ".expected.json";
+
+/// Return the fixtures that match `pattern`, relative to the crate being compiled, in a stable order.
+fn fixtures(pattern: &LitStr) -> syn::Result<Vec<PathBuf>> {
+    let root = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_owned());
+    let full = FsPath::new(&root).join(pattern.value());
+    let paths = glob::glob(&full.to_string_lossy())
+        .map_err(|e| syn::Error::new(pattern.span(), format!("invalid fixture pattern: {}", e)))?;
+
+    let mut fixtures = Vec::new();
+    for path in paths {
+        let path = path.map_err(|e| syn::Error::new(pattern.span(), format!("unable to read fixture: {}", e)))?;
+        if !path.to_string_lossy().ends_with(SNAPSHOT_SUFFIX) {
+            fixtures.push(path);
+        }
+    }
+    if fixtures.is_empty() {
+        return Err(syn::Error::new(
+            pattern.span(),
+            format!("no fixtures match `{}`", pattern.value()),
+        ));
+    }
+    fixtures.sort();
+    Ok(fixtures)
+}
+
+/// Return a test name for a fixture, unique among `taken`.
+fn test_name(fixture: &FsPath, taken: &mut HashSet<String>) -> String {
+    let stem = fixture.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let mut name: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
             } else {
-                quote_spanned! { input.span() =>
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert_str(0, "fixture_");
+    }
 
-                    #(#attrs)*
-                    #asyncness fn main() {
-                        async fn actual(#event_name: #event_type, #context_name: #context_type) #ret #body
+    let mut unique = name.clone();
+    let mut n = 1;
+    while !taken.insert(unique.clone()) {
+        n += 1;
+        unique = format!("{}_{}", name, n);
+    }
+    unique
+}
 
-                        let f = lamedh_runtime::handler_fn(actual);
-                        lamedh_runtime::run(f).await.unwrap();
-                    }
-                }
+#[proc_macro_attribute]
+/// Generate one test per event fixture for a handler function
+pub fn lambda_test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as ItemFn);
+    let pattern = syn::parse_macro_input!(attr as LitStr);
+    let name = &input.sig.ident;
+    let ret = &input.sig.output;
+    let asyncness = &input.sig.asyncness;
+    let inputs = &input.sig.inputs;
+
+    let mut types = Vec::with_capacity(inputs.len());
+    for arg in inputs {
+        match arg {
+            FnArg::Typed(arg) if !matches!(&*arg.ty, Type::Reference(_)) => types.push(&arg.ty),
+            _ => {
+                let tokens = quote_spanned! { arg.span() =>
+                    compile_error!("#[lambda_test] expects a handler that takes an event and an optional lambda context");
+                };
+                return TokenStream::from(tokens);
             }
         }
-        _ => {
-            let tokens = quote_spanned! { inputs.span() =>
-                compile_error!("The #[lambda] macro can expects two arguments: a triggered event and lambda context.");
-            };
-            return TokenStream::from(tokens);
+    }
+    if types.is_empty() || types.len() > 2 {
+        let tokens = quote_spanned! { inputs.span() =>
+            compile_error!("#[lambda_test] expects a handler that takes an event and an optional lambda context");
+        };
+        return TokenStream::from(tokens);
+    }
+
+    let event_type = types[0];
+    let (context_type, call) = match types.get(1) {
+        Some(ty) => (quote!(#ty), quote!(super::#name(__event, __context))),
+        None => (quote!(lamedh_runtime::Context), quote!(super::#name(__event))),
+    };
+    let call = match asyncness {
+        Some(_) => quote!(#call.await),
+        None => call,
+    };
+    let output = if returns_result(ret) {
+        call
+    } else {
+        quote!(Ok::<_, lamedh_runtime::Error>(#call))
+    };
+
+    let fixtures = match fixtures(&pattern) {
+        Ok(fixtures) => fixtures,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+    let mut taken = HashSet::new();
+    let tests = fixtures.iter().map(|fixture| {
+        let test = format_ident!("{}", test_name(fixture, &mut taken));
+        let stem = fixture.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let snapshot = fixture.with_file_name(format!("{}{}", stem, SNAPSHOT_SUFFIX));
+        let fixture = fixture.to_string_lossy();
+        let snapshot = snapshot.to_string_lossy();
+        quote! {
+            #[tokio::test]
+            async fn #test() {
+                let mut handler = lamedh_runtime::handler_fn(
+                    |__event: #event_type, __context: #context_type| async move { #output }
+                );
+                lamedh_runtime::testing::assert_fixture(&mut handler, include_str!(#fixture), #snapshot).await;
+            }
+        }
+    });
+
+    let module = format_ident!("{}_fixtures", name);
+    let result = quote! {
+        #input
+
+        #[cfg(test)]
+        mod #module {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #(#tests)*
         }
     };
 
@@ -0,0 +1,10 @@
+use lamedh_attributes::lambda;
+
+#[lambda(init {
+    let greeting = String::from("Hello");
+})]
+async fn handler(event: String, greeting: &String) -> Result<String, String> {
+    Ok(format!("{}, {}!", greeting, event))
+}
+
+fn main() {}
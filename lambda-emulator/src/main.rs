@@ -0,0 +1,226 @@
+//! A local emulator of the AWS Lambda service, to run functions end to end without Docker.
+//!
+//! The emulator serves the [Runtime API](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html)
+//! to a function binary, and the `POST /2015-03-31/functions/function/invocations` route of the
+//! Invoke API to callers, like curl or the AWS SDKs. Invocations run one at a time, with the
+//! function timeout and the 6 MB payload limits of synchronous invocations.
+//!
+//! ```bash
+//! $ lamedh_emulator --timeout 10 -- target/debug/my-function
+//! $ curl -d '{"name": "Ferris"}' http://localhost:9001/2015-03-31/functions/function/invocations
+//! ```
+//!
+//! The function is started with `AWS_LAMBDA_RUNTIME_API` pointing at the emulator, and the
+//! other variables that Lambda sets. When no command is given, the emulator waits for a
+//! function started separately.
+#![deny(missing_docs)]
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Server,
+};
+use std::{convert::Infallible, env, net::SocketAddr, process, time::Duration};
+use tokio::process::Command;
+
+mod server;
+
+use server::{Emulator, Options};
+
+const USAGE: &str = "Usage: lamedh_emulator [OPTIONS] [-- COMMAND [ARGS...]]
+
+Options:
+    --port <PORT>             Port to serve the Runtime and Invoke APIs on [default: 9001]
+    --function-name <NAME>    Name of the function [default: function]
+    --memory <MB>             Memory available to the function [default: 128]
+    --timeout <SECONDS>       Function timeout, between 1 and 900 seconds [default: 3]
+    --region <REGION>         Region in the function ARN [default: us-east-1]
+    -h, --help                Print this message";
+
+/// The longest timeout that Lambda allows.
+const MAX_TIMEOUT: u64 = 900;
+
+/// Command line arguments.
+#[derive(Debug, PartialEq)]
+struct Args {
+    port: u16,
+    options: Options,
+    command: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut parsed = Args {
+        port: 9001,
+        options: Options::default(),
+        command: Vec::new(),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--port" => parsed.port = parse_number(&arg, value()?)?,
+            "--function-name" => parsed.options.function_name = value()?,
+            "--memory" => parsed.options.memory = parse_number(&arg, value()?)?,
+            "--timeout" => {
+                let timeout = parse_number(&arg, value()?)?;
+                if timeout == 0 || timeout > MAX_TIMEOUT {
+                    return Err(format!("--timeout must be between 1 and {} seconds", MAX_TIMEOUT));
+                }
+                parsed.options.timeout = Duration::from_secs(timeout);
+            }
+            "--region" => parsed.options.region = value()?,
+            "--" => {
+                parsed.command = args.collect();
+                break;
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    Ok(Some(parsed))
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", arg, value))
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let emulator = Emulator::new(args.options.clone());
+    let make_svc = make_service_fn(move |_| {
+        let emulator = emulator.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| emulator.clone().handle(req))) }
+    });
+    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
+    let server = match Server::try_bind(&addr) {
+        Ok(server) => server.serve(make_svc),
+        Err(e) => {
+            eprintln!("Unable to listen on {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+    let addr = server.local_addr();
+    eprintln!("Listening on http://{}", addr);
+
+    let mut command = args.command.iter();
+    let program = match command.next() {
+        Some(program) => program,
+        None => {
+            if let Err(e) = server.await {
+                eprintln!("Server error: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+    };
+
+    let options = &args.options;
+    let mut function = match Command::new(program)
+        .args(command)
+        .env("AWS_LAMBDA_RUNTIME_API", addr.to_string())
+        .env("AWS_LAMBDA_FUNCTION_NAME", &options.function_name)
+        .env("AWS_LAMBDA_FUNCTION_VERSION", &options.version)
+        .env("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", options.memory.to_string())
+        .env("AWS_LAMBDA_FUNCTION_TIMEOUT", options.timeout.as_secs().to_string())
+        .env(
+            "AWS_LAMBDA_LOG_GROUP_NAME",
+            format!("/aws/lambda/{}", options.function_name),
+        )
+        .env("AWS_LAMBDA_LOG_STREAM_NAME", "$LATEST")
+        .env("AWS_LAMBDA_INITIALIZATION_TYPE", "on-demand")
+        .env("AWS_REGION", &options.region)
+        .env("AWS_DEFAULT_REGION", &options.region)
+        .env("TZ", ":UTC")
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(function) => function,
+        Err(e) => {
+            eprintln!("Unable to start {}: {}", program, e);
+            process::exit(1);
+        }
+    };
+
+    // The emulator lives as long as the function does.
+    tokio::select! {
+        res = server => {
+            if let Err(e) = res {
+                eprintln!("Server error: {}", e);
+            }
+            process::exit(1);
+        }
+        status = function.wait() => {
+            match status {
+                Ok(status) => {
+                    eprintln!("Function exited: {}", status);
+                    process::exit(status.code().unwrap_or(1));
+                }
+                Err(e) => {
+                    eprintln!("Unable to wait for the function: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Option<Args>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_defaults() {
+        let parsed = args(&[]).unwrap().unwrap();
+        assert_eq!(9001, parsed.port);
+        assert_eq!(Options::default(), parsed.options);
+        assert!(parsed.command.is_empty());
+    }
+
+    #[test]
+    fn parses_options_and_command() {
+        let parsed = args(&[
+            "--port",
+            "8080",
+            "--function-name",
+            "greeter",
+            "--timeout",
+            "10",
+            "--",
+            "target/debug/greeter",
+            "--verbose",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(8080, parsed.port);
+        assert_eq!("greeter", parsed.options.function_name);
+        assert_eq!(Duration::from_secs(10), parsed.options.timeout);
+        assert_eq!(vec!["target/debug/greeter", "--verbose"], parsed.command);
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(args(&["--timeout", "901"]).is_err());
+        assert!(args(&["--memory", "lots"]).is_err());
+        assert!(args(&["--port"]).is_err());
+        assert!(args(&["bootstrap"]).is_err());
+        assert_eq!(None, args(&["--help"]).unwrap());
+    }
+}
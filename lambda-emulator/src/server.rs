@@ -0,0 +1,478 @@
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{HeaderValue, CONTENT_TYPE},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde_json::json;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    convert::Infallible,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+
+/// The maximum size of synchronous invocation events and responses, in bytes.
+pub(crate) const MAX_PAYLOAD_SIZE: usize = 6 * 1024 * 1024;
+
+/// The header that tells Invoke API clients that the function failed.
+const FUNCTION_ERROR_HEADER: &str = "x-amz-function-error";
+
+/// Settings of the emulated function.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Options {
+    pub(crate) function_name: String,
+    pub(crate) version: String,
+    pub(crate) memory: u32,
+    pub(crate) timeout: Duration,
+    pub(crate) region: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            function_name: "function".to_owned(),
+            version: "$LATEST".to_owned(),
+            memory: 128,
+            timeout: Duration::from_secs(3),
+            region: "us-east-1".to_owned(),
+        }
+    }
+}
+
+impl Options {
+    /// The ARN that invocations are sent with.
+    pub(crate) fn function_arn(&self) -> String {
+        format!(
+            "arn:aws:lambda:{}:123456789012:function:{}",
+            self.region, self.function_name
+        )
+    }
+}
+
+/// How an invocation ended, as reported by the function.
+#[derive(Debug)]
+enum Outcome {
+    Response(Bytes),
+    Error(Bytes),
+}
+
+/// An invocation waiting for the function to pick it up.
+#[derive(Debug)]
+struct Invocation {
+    request_id: String,
+    body: Bytes,
+    // Receives the deadline once the function picks up the invocation.
+    started: oneshot::Sender<u64>,
+    done: oneshot::Sender<Outcome>,
+}
+
+/// Emulates the Lambda service for a single function: the Invoke API queues
+/// events, and the Runtime API hands them to the function one at a time.
+#[derive(Debug)]
+pub(crate) struct Emulator {
+    options: Options,
+    queue: mpsc::UnboundedSender<Invocation>,
+    pending: AsyncMutex<mpsc::UnboundedReceiver<Invocation>>,
+    in_flight: Mutex<HashMap<String, oneshot::Sender<Outcome>>>,
+    init_error: Mutex<Option<Bytes>>,
+}
+
+impl Emulator {
+    pub(crate) fn new(options: Options) -> Arc<Self> {
+        let (queue, pending) = mpsc::unbounded_channel();
+        Arc::new(Emulator {
+            options,
+            queue,
+            pending: AsyncMutex::new(pending),
+            in_flight: Mutex::default(),
+            init_error: Mutex::default(),
+        })
+    }
+
+    /// Serves both the Runtime API and the Invoke API.
+    pub(crate) async fn handle(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let path = req.uri().path().to_owned();
+        let segments: Vec<&str> = path.split('/').collect();
+        let rsp = match (req.method(), &segments[1..]) {
+            (&Method::GET, ["2018-06-01", "runtime", "invocation", "next"]) => self.next_invocation().await,
+            (&Method::POST, ["2018-06-01", "runtime", "invocation", id, "response"]) => {
+                self.complete_invocation(id, req.into_body()).await
+            }
+            (&Method::POST, ["2018-06-01", "runtime", "invocation", id, "error"]) => {
+                self.fail_invocation(id, req.into_body()).await
+            }
+            (&Method::POST, ["2018-06-01", "runtime", "init", "error"]) => self.fail_init(req.into_body()).await,
+            (&Method::POST, ["2015-03-31", "functions", _, "invocations"]) => self.invoke(req.into_body()).await,
+            _ => error_response(StatusCode::NOT_FOUND, "ResourceNotFoundException", "Unknown route"),
+        };
+        Ok(rsp)
+    }
+
+    /// `POST /2015-03-31/functions/function/invocations`
+    async fn invoke(&self, body: Body) -> Response<Body> {
+        let body = match read_limited(body).await {
+            Some(body) => body,
+            None => {
+                let message = format!(
+                    "Request must be smaller than {} bytes for the InvokeFunction operation",
+                    MAX_PAYLOAD_SIZE
+                );
+                return error_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "RequestEntityTooLargeException",
+                    &message,
+                );
+            }
+        };
+        if let Some(init_error) = self.init_error.lock().expect("Lock was poisoned").clone() {
+            return function_error(init_error);
+        }
+
+        let (started, started_rx) = oneshot::channel();
+        let (done, done_rx) = oneshot::channel();
+        let invocation = Invocation {
+            request_id: generate_request_id(),
+            body,
+            started,
+            done,
+        };
+        let request_id = invocation.request_id.clone();
+        if self.queue.send(invocation).is_err() {
+            return error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceException",
+                "Emulator is shutting down",
+            );
+        }
+
+        // Like in Lambda, the timeout starts when the function receives the event
+        let deadline = match started_rx.await {
+            Ok(deadline) => deadline,
+            Err(_) => {
+                return error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "ServiceException",
+                    "Invocation dropped",
+                )
+            }
+        };
+        let remaining = Duration::from_millis(deadline.saturating_sub(now_millis()));
+        match tokio::time::timeout(remaining, done_rx).await {
+            Ok(Ok(Outcome::Response(body))) => Response::new(Body::from(body)),
+            Ok(Ok(Outcome::Error(body))) => function_error(body),
+            Ok(Err(_)) => error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceException",
+                "Invocation dropped",
+            ),
+            Err(_) => {
+                self.in_flight.lock().expect("Lock was poisoned").remove(&request_id);
+                let message = format!("Task timed out after {:.2} seconds", self.options.timeout.as_secs_f64());
+                eprintln!("{} {}", request_id, message);
+                function_error(json!({ "errorType": "Sandbox.Timedout", "errorMessage": message }).to_string())
+            }
+        }
+    }
+
+    /// `GET /2018-06-01/runtime/invocation/next`
+    async fn next_invocation(&self) -> Response<Body> {
+        let mut pending = self.pending.lock().await;
+        loop {
+            let invocation = match pending.recv().await {
+                Some(invocation) => invocation,
+                None => return error_response(StatusCode::GONE, "ServiceException", "Emulator is shutting down"),
+            };
+
+            let deadline = now_millis() + self.options.timeout.as_millis() as u64;
+            // Skip invocations whose caller went away while they were queued
+            if invocation.started.send(deadline).is_err() {
+                continue;
+            }
+            self.in_flight
+                .lock()
+                .expect("Lock was poisoned")
+                .insert(invocation.request_id.clone(), invocation.done);
+
+            return Response::builder()
+                .header("lambda-runtime-aws-request-id", &invocation.request_id)
+                .header("lambda-runtime-deadline-ms", deadline.to_string())
+                .header("lambda-runtime-invoked-function-arn", self.options.function_arn())
+                .body(Body::from(invocation.body))
+                .expect("Unable to construct response");
+        }
+    }
+
+    /// `POST /2018-06-01/runtime/invocation/{id}/response`
+    async fn complete_invocation(&self, request_id: &str, body: Body) -> Response<Body> {
+        let done = match self.in_flight.lock().expect("Lock was poisoned").remove(request_id) {
+            Some(done) => done,
+            None => return invalid_request_id(),
+        };
+        match read_limited(body).await {
+            Some(body) => {
+                let _ = done.send(Outcome::Response(body));
+                accepted()
+            }
+            None => {
+                let message = format!(
+                    "Response payload size exceeded maximum allowed payload size ({} bytes).",
+                    MAX_PAYLOAD_SIZE
+                );
+                let error = json!({ "errorType": "Function.ResponseSizeTooLarge", "errorMessage": message });
+                let _ = done.send(Outcome::Error(error.to_string().into()));
+                error_response(StatusCode::PAYLOAD_TOO_LARGE, "RequestEntityTooLarge", &message)
+            }
+        }
+    }
+
+    /// `POST /2018-06-01/runtime/invocation/{id}/error`
+    async fn fail_invocation(&self, request_id: &str, body: Body) -> Response<Body> {
+        let done = match self.in_flight.lock().expect("Lock was poisoned").remove(request_id) {
+            Some(done) => done,
+            None => return invalid_request_id(),
+        };
+        let body = read_limited(body).await.unwrap_or_default();
+        let _ = done.send(Outcome::Error(body));
+        accepted()
+    }
+
+    /// `POST /2018-06-01/runtime/init/error`
+    async fn fail_init(&self, body: Body) -> Response<Body> {
+        let body = read_limited(body).await.unwrap_or_default();
+        eprintln!("Function failed to initialize: {}", String::from_utf8_lossy(&body));
+        *self.init_error.lock().expect("Lock was poisoned") = Some(body);
+        accepted()
+    }
+}
+
+/// Reads a body, unless it's larger than the payload limit.
+async fn read_limited(mut body: Body) -> Option<Bytes> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.ok()?;
+        if buf.len() + chunk.len() > MAX_PAYLOAD_SIZE {
+            return None;
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Some(buf.into())
+}
+
+fn accepted() -> Response<Body> {
+    json_response(StatusCode::ACCEPTED, json!({ "status": "OK" }).to_string())
+}
+
+fn invalid_request_id() -> Response<Body> {
+    error_response(StatusCode::BAD_REQUEST, "InvalidRequestID", "Invalid request ID")
+}
+
+fn error_response(status: StatusCode, error_type: &str, message: &str) -> Response<Body> {
+    let body = json!({ "errorType": error_type, "errorMessage": message });
+    json_response(status, body.to_string())
+}
+
+/// A successful Invoke API response for an invocation that failed in the function.
+fn function_error(body: impl Into<Body>) -> Response<Body> {
+    let mut rsp = json_response(StatusCode::OK, body);
+    rsp.headers_mut()
+        .insert(FUNCTION_ERROR_HEADER, HeaderValue::from_static("Unhandled"));
+    rsp
+}
+
+fn json_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut rsp = Response::new(body.into());
+    *rsp.status_mut() = status;
+    rsp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    rsp
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the Unix epoch")
+        .as_millis() as u64
+}
+
+/// Generates a random request id, formatted like the version 4 UUIDs that Lambda uses.
+///
+/// This is the same generator as the one behind `lamedh_runtime::Context::builder`,
+/// copied so that the emulator doesn't depend on the runtime.
+fn generate_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // `RandomState` is seeded randomly, so hashing a counter gives unique
+    // random-looking values without pulling in a random number generator.
+    let hash = |n: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(n);
+        hasher.finish()
+    };
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let high = hash(count);
+    let low = hash(count.wrapping_add(u64::MAX / 2));
+
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0x0fff,
+        (low >> 48) & 0x3fff | 0x8000,
+        low & 0xffff_ffff_ffff,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Client, Server,
+    };
+    use serde_json::Value;
+    use std::net::SocketAddr;
+
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn start(options: Options) -> SocketAddr {
+        let emulator = Emulator::new(options);
+        let make_svc = make_service_fn(move |_| {
+            let emulator = emulator.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| emulator.clone().handle(req))) }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn invoke(addr: SocketAddr, body: impl Into<Body>) -> Result<Response<Body>, Error> {
+        let uri = format!("http://{}/2015-03-31/functions/function/invocations", addr);
+        let req = Request::post(uri).body(body.into())?;
+        Ok(Client::new().request(req).await?)
+    }
+
+    async fn next(addr: SocketAddr) -> Result<Response<Body>, Error> {
+        let uri = format!("http://{}/2018-06-01/runtime/invocation/next", addr).parse()?;
+        Ok(Client::new().get(uri).await?)
+    }
+
+    async fn post(addr: SocketAddr, path: &str, body: impl Into<Body>) -> Result<Response<Body>, Error> {
+        let uri = format!("http://{}/2018-06-01/runtime/{}", addr, path);
+        let req = Request::post(uri).body(body.into())?;
+        Ok(Client::new().request(req).await?)
+    }
+
+    fn request_id(rsp: &Response<Body>) -> String {
+        rsp.headers()["lambda-runtime-aws-request-id"]
+            .to_str()
+            .expect("Request id is not a string")
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn invokes_the_function() -> Result<(), Error> {
+        let addr = start(Options::default());
+        let invocation = tokio::spawn(invoke(addr, r#"{"name":"Ferris"}"#));
+
+        let event = next(addr).await?;
+        let id = request_id(&event);
+        assert_eq!(
+            "arn:aws:lambda:us-east-1:123456789012:function:function",
+            event.headers()["lambda-runtime-invoked-function-arn"]
+        );
+        assert!(event.headers().contains_key("lambda-runtime-deadline-ms"));
+        assert_eq!(
+            &b"{\"name\":\"Ferris\"}"[..],
+            hyper::body::to_bytes(event.into_body()).await?
+        );
+
+        let rsp = post(addr, &format!("invocation/{}/response", id), "\"Hello, Ferris!\"").await?;
+        assert_eq!(StatusCode::ACCEPTED, rsp.status());
+
+        let rsp = invocation.await??;
+        assert_eq!(StatusCode::OK, rsp.status());
+        assert!(!rsp.headers().contains_key(FUNCTION_ERROR_HEADER));
+        assert_eq!(
+            &b"\"Hello, Ferris!\""[..],
+            hyper::body::to_bytes(rsp.into_body()).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_function_errors() -> Result<(), Error> {
+        let addr = start(Options::default());
+        let invocation = tokio::spawn(invoke(addr, "{}"));
+
+        let id = request_id(&next(addr).await?);
+        let error = r#"{"errorType":"MyError","errorMessage":"missing name"}"#;
+        let rsp = post(addr, &format!("invocation/{}/error", id), error).await?;
+        assert_eq!(StatusCode::ACCEPTED, rsp.status());
+
+        let rsp = invocation.await??;
+        assert_eq!(StatusCode::OK, rsp.status());
+        assert_eq!("Unhandled", rsp.headers()[FUNCTION_ERROR_HEADER]);
+        assert_eq!(error.as_bytes(), &hyper::body::to_bytes(rsp.into_body()).await?[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_functions() -> Result<(), Error> {
+        let options = Options {
+            timeout: Duration::from_millis(50),
+            ..Options::default()
+        };
+        let addr = start(options);
+        let invocation = tokio::spawn(invoke(addr, "{}"));
+
+        let id = request_id(&next(addr).await?);
+        let rsp = invocation.await??;
+        assert_eq!("Unhandled", rsp.headers()[FUNCTION_ERROR_HEADER]);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(rsp.into_body()).await?)?;
+        assert_eq!("Sandbox.Timedout", body["errorType"]);
+
+        // Late responses are rejected
+        let rsp = post(addr, &format!("invocation/{}/response", id), "{}").await?;
+        assert_eq!(StatusCode::BAD_REQUEST, rsp.status());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn enforces_payload_limits() -> Result<(), Error> {
+        let addr = start(Options::default());
+        let large = vec![b'a'; MAX_PAYLOAD_SIZE + 1];
+
+        let rsp = invoke(addr, large.clone()).await?;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, rsp.status());
+
+        let invocation = tokio::spawn(invoke(addr, "{}"));
+        let id = request_id(&next(addr).await?);
+        let rsp = post(addr, &format!("invocation/{}/response", id), large).await?;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, rsp.status());
+
+        let rsp = invocation.await??;
+        assert_eq!("Unhandled", rsp.headers()[FUNCTION_ERROR_HEADER]);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(rsp.into_body()).await?)?;
+        assert_eq!("Function.ResponseSizeTooLarge", body["errorType"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_invocations_after_init_errors() -> Result<(), Error> {
+        let addr = start(Options::default());
+        let error = r#"{"errorType":"InitError","errorMessage":"Unable to initialize"}"#;
+        let rsp = post(addr, "init/error", error).await?;
+        assert_eq!(StatusCode::ACCEPTED, rsp.status());
+
+        let rsp = invoke(addr, "{}").await?;
+        assert_eq!("Unhandled", rsp.headers()[FUNCTION_ERROR_HEADER]);
+        assert_eq!(error.as_bytes(), &hyper::body::to_bytes(rsp.into_body()).await?[..]);
+        Ok(())
+    }
+}
@@ -18,8 +18,9 @@
 //!
 //! Adding an `#[lambda(http)]` attribute to a `#[tokio::run]`-decorated `main` function will setup and run the Lambda function.
 //!
-//! Note: this comes at the expense of any onetime initialization your lambda task might find value in.
-//! The full body of your `main` function will be executed on **every** invocation of your lambda task.
+//! Note: the full body of your `main` function will be executed on **every** invocation of your lambda task.
+//! Onetime initialization can be declared with `#[lambda(http, init = setup)]`, where `setup` is an async
+//! function whose output is passed to `main` as a third argument.
 //!
 //! ```rust,no_run
 //! use lamedh_http::{
@@ -34,6 +35,30 @@
 //! }
 //! ```
 //!
+//! ```rust,no_run
+//! use lamedh_http::{
+//!    lambda::{lambda, Context, Error},
+//!    IntoResponse, Request,
+//! };
+//!
+//! struct State {
+//!     greeting: String,
+//! }
+//!
+//! async fn setup() -> Result<State, Error> {
+//!     Ok(State { greeting: "👋".to_string() })
+//! }
+//!
+//! #[lambda(http, init = setup)]
+//! #[tokio::main]
+//! async fn main(_: Request, _: Context, state: &State) -> Result<impl IntoResponse, Error> {
+//!     Ok(format!("{} world!", state.greeting))
+//! }
+//! ```
+//!
+//! Functions that are only invoked through API Gateway REST APIs, or through the AWS Invoke API,
+//! can use `#[lambda(http(origin = "apigw_v1"))]` to skip the detection of the event format.
+//!
 //! ## Hello World, Without Macros
 //!
 //! For cases where your lambda might benfit from one time function initializiation might
@@ -97,6 +122,7 @@ pub mod ext;
 pub mod request;
 mod response;
 mod strmap;
+pub mod testing;
 pub use crate::{ext::RequestExt, response::IntoResponse, strmap::StrMap};
 use crate::{
     request::{self as lambda_request, LambdaRequest, RequestOrigin},
@@ -130,7 +156,7 @@ impl<F, R, Fut> Handler for F
 where
     F: Fn(Request, Context) -> Fut,
     R: IntoResponse,
-    Fut: Future<Output = Result<R, Error>> + Send + 'static,
+    Fut: Future<Output = Result<R, Error>> + 'static,
 {
     type Response = R;
     type Error = Error;
@@ -169,6 +195,49 @@ pub fn handler<H: Handler>(handler: H) -> Adapter<H> {
     Adapter { handler }
 }
 
+/// Adapts a closure that borrows the state of an init phase to the `lamedh_runtime::run_with_init` interface
+///
+/// This is an abstract interface that tries to deserialize the request payload
+/// in any possible [`request::LambdaRequest`] value, like [`handler`](fn.handler.html) does.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use lamedh_http::{handler_with_state, lambda::{self, Context, Error}, IntoResponse, Request};
+///
+/// struct State {
+///     greeting: String,
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Error> {
+///     lamedh_runtime::run_with_init(init, handler_with_state(hello)).await
+/// }
+///
+/// async fn init() -> Result<State, Error> {
+///     Ok(State { greeting: "👋".to_string() })
+/// }
+///
+/// async fn hello(_: Request, _: Context, state: &State) -> Result<impl IntoResponse, Error> {
+///     Ok(format!("{} world!", state.greeting))
+/// }
+/// ```
+pub fn handler_with_state<S, F, R, Fut>(
+    handler: F,
+) -> impl Fn(LambdaRequest, Context, &'static S) -> TransformResponse<R, Error>
+where
+    S: 'static,
+    F: Fn(Request, Context, &'static S) -> Fut,
+    R: IntoResponse,
+    Fut: Future<Output = Result<R, Error>> + 'static,
+{
+    move |event, context, state| {
+        let request_origin = event.request_origin();
+        let fut = Box::pin(handler(event.into(), context, state));
+        TransformResponse { request_origin, fut }
+    }
+}
+
 /// Exists only to satisfy the trait cover rule for `lambda::Handler` impl
 ///
 /// User code should never need to interact with this type directly. Since `Adapter` implements `Handler`
@@ -197,6 +266,11 @@ impl<H: Handler> LambdaHandler<LambdaRequest, LambdaResponse> for Adapter<H> {
         let fut = Box::pin(self.handler.call(event.into(), context));
         TransformResponse { request_origin, fut }
     }
+
+    #[cfg(feature = "opentelemetry")]
+    fn trigger(&self, event: &LambdaRequest) -> lambda::otel::Trigger {
+        event.trigger()
+    }
 }
 
 /// Adapts a [`Handler`](trait.Handler.html) to the `lamedh_runtime::run` interface
@@ -237,4 +311,9 @@ impl<H: Handler> LambdaHandler<ApiGatewayProxyRequest, LambdaResponse> for Proxy
         let fut = Box::pin(self.handler.call(req, context));
         TransformResponse { request_origin, fut }
     }
+
+    #[cfg(feature = "opentelemetry")]
+    fn trigger(&self, event: &ApiGatewayProxyRequest) -> lambda::otel::Trigger {
+        lambda_request::trigger(&[&event.headers, &event.multi_value_headers])
+    }
 }
@@ -13,7 +13,11 @@ use aws_lambda_events::event::apigw::{
     ApiGatewayProxyRequest, ApiGatewayProxyRequestContext, ApiGatewayV2httpRequest, ApiGatewayV2httpRequestContext,
 };
 use http::header::HeaderName;
-use serde::Deserialize;
+#[cfg(feature = "opentelemetry")]
+use http::HeaderMap;
+#[cfg(feature = "opentelemetry")]
+use lamedh_runtime::otel::{self, Trigger, TriggerKind};
+use serde::{Deserialize, Serialize};
 use serde_json::error::Error as JsonError;
 use std::{io::Read, mem};
 
@@ -23,7 +27,7 @@ use std::{io::Read, mem};
 /// This is not intended to be a type consumed by crate users directly. The order
 /// of the variants are notable. Serde will try to deserialize in this order.
 #[doc(hidden)]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(untagged)]
 pub enum LambdaRequest {
     ApiGatewayV1(ApiGatewayProxyRequest),
@@ -42,11 +46,31 @@ impl LambdaRequest {
             LambdaRequest::Alb { .. } => RequestOrigin::Alb,
         }
     }
+
+    /// Return the `Trigger` of the request, which continues the trace of its `traceparent` header.
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) fn trigger(&self) -> Trigger {
+        match self {
+            LambdaRequest::ApiGatewayV1(ag) => trigger(&[&ag.headers, &ag.multi_value_headers]),
+            LambdaRequest::ApiGatewayV2(ag) => trigger(&[&ag.headers]),
+            LambdaRequest::Alb(alb) => trigger(&[&alb.headers, &alb.multi_value_headers]),
+        }
+    }
+}
+
+/// Describes an HTTP request as the trigger of an invocation, with the trace of the
+/// first of its header maps that has one.
+#[cfg(feature = "opentelemetry")]
+pub(crate) fn trigger(headers: &[&HeaderMap]) -> Trigger {
+    let trigger = Trigger::new(TriggerKind::Http);
+    match headers.iter().find_map(|headers| otel::parent_from_headers(headers)) {
+        Some(parent) => trigger.with_parent(parent),
+        None => trigger,
+    }
 }
 
 /// Represents the origin from which the lambda was requested from.
-#[doc(hidden)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestOrigin {
     /// API Gateway proxy request origin
     ApiGatewayV1,
@@ -0,0 +1,628 @@
+//! Helpers to test HTTP handlers with the events that ALB and API Gateway send to Lambda.
+//!
+//! [`EventBuilder`] turns an `http::Request` into the JSON event of a [`RequestOrigin`],
+//! with the headers, query strings, request context and body encoding that AWS uses.
+//! [`decode_response`] turns the JSON response of a handler back into an `http::Response`,
+//! and [`invoke`] does both around a single call of a handler, so tests cover the full
+//! serialization round trip.
+//!
+//! ```rust
+//! use lamedh_http::{
+//!     http::{Request, StatusCode},
+//!     lambda::{Context, Error},
+//!     testing::{self, EventBuilder},
+//!     IntoResponse, RequestExt,
+//! };
+//!
+//! async fn hello(request: lamedh_http::Request, _: Context) -> Result<impl IntoResponse, Error> {
+//!     let name = request.query_string_parameters().get("name").unwrap_or("stranger").to_string();
+//!     Ok(format!("Hello, {}!", name))
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     let request = Request::get("https://example.com/hello?name=Ferris").body(())?;
+//!     let response = testing::invoke(&EventBuilder::api_gateway_v2(), hello, request).await?;
+//!     assert_eq!(StatusCode::OK, response.status());
+//!     assert_eq!("Hello, Ferris!".as_bytes(), response.body().as_ref());
+//!     Ok(())
+//! }
+//! ```
+//!
+//! [`EventBuilder`]: struct.EventBuilder.html
+//! [`RequestOrigin`]: ../request/enum.RequestOrigin.html
+//! [`decode_response`]: fn.decode_response.html
+//! [`invoke`]: fn.invoke.html
+use crate::{handler, request::RequestOrigin, Error, Handler};
+use aws_lambda_events::{
+    encodings::Body,
+    event::{
+        alb::{AlbTargetGroupRequest, AlbTargetGroupRequestContext, AlbTargetGroupResponse, ElbContext},
+        apigw::{
+            ApiGatewayProxyRequest, ApiGatewayProxyRequestContext, ApiGatewayProxyResponse, ApiGatewayRequestIdentity,
+            ApiGatewayV2httpRequest, ApiGatewayV2httpRequestContext, ApiGatewayV2httpRequestContextHttpDescription,
+            ApiGatewayV2httpResponse,
+        },
+    },
+};
+use http::{
+    header::{HeaderName, HeaderValue, COOKIE, HOST, SET_COOKIE, USER_AGENT},
+    HeaderMap, Response, StatusCode,
+};
+use lamedh_runtime::clock::civil_from_days;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The account that events are sent from.
+const ACCOUNT_ID: &str = "123456789012";
+
+/// The id of the API in API Gateway events.
+const API_ID: &str = "1234567890";
+
+/// The target group in ALB events.
+const TARGET_GROUP_ARN: &str =
+    "arn:aws:elasticloadbalancing:us-east-1:123456789012:targetgroup/lambda-target/abcdef0123456789";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Builds the events that ALB and API Gateway send to Lambda for an `http::Request`.
+///
+/// Like AWS does, events carry the `Host` and `X-Forwarded-*` headers, query strings parsed
+/// from the URI, a request context for the origin, and binary bodies encoded in base64.
+/// API Gateway HTTP API events join repeated headers with commas and move cookies to
+/// their own field, and ALB events keep query strings URL-encoded.
+#[derive(Debug, Clone)]
+pub struct EventBuilder {
+    origin: RequestOrigin,
+    stage: Option<String>,
+    resource: Option<String>,
+    path_parameters: HashMap<String, String>,
+    stage_variables: HashMap<String, String>,
+    source_ip: String,
+}
+
+impl EventBuilder {
+    /// Returns a builder of events from `origin`.
+    pub fn new(origin: RequestOrigin) -> Self {
+        EventBuilder {
+            origin,
+            stage: None,
+            resource: None,
+            path_parameters: HashMap::new(),
+            stage_variables: HashMap::new(),
+            source_ip: "127.0.0.1".to_string(),
+        }
+    }
+
+    /// Returns a builder of API Gateway REST API events.
+    pub fn api_gateway_v1() -> Self {
+        Self::new(RequestOrigin::ApiGatewayV1)
+    }
+
+    /// Returns a builder of API Gateway HTTP API events, in the 2.0 payload format.
+    pub fn api_gateway_v2() -> Self {
+        Self::new(RequestOrigin::ApiGatewayV2)
+    }
+
+    /// Returns a builder of ALB events, with multi-value headers enabled.
+    pub fn alb() -> Self {
+        Self::new(RequestOrigin::Alb)
+    }
+
+    /// Returns the origin of the events.
+    pub fn origin(&self) -> RequestOrigin {
+        self.origin
+    }
+
+    /// Sets the API Gateway stage, `prod` for REST APIs and `$default` for HTTP APIs unless it's set.
+    pub fn stage(mut self, stage: impl Into<String>) -> Self {
+        self.stage = Some(stage.into());
+        self
+    }
+
+    /// Sets the API Gateway resource that matched the request, like `/pets/{id}`, or the
+    /// route key of HTTP APIs, like `GET /pets/{id}`.
+    ///
+    /// Unless it's set, the resource is the path of the request, and the route key is `$default`.
+    pub fn resource(mut self, resource: impl Into<String>) -> Self {
+        self.resource = Some(resource.into());
+        self
+    }
+
+    /// Adds an API Gateway path parameter.
+    pub fn path_parameter(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.path_parameters.insert(name.into(), value.into());
+        self
+    }
+
+    /// Adds an API Gateway stage variable.
+    pub fn stage_variable(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.stage_variables.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the IP address of the client, `127.0.0.1` unless it's set.
+    pub fn source_ip(mut self, source_ip: impl Into<String>) -> Self {
+        self.source_ip = source_ip.into();
+        self
+    }
+
+    /// Returns the JSON event for `request`.
+    pub fn build<B: Into<Body>>(&self, request: http::Request<B>) -> Value {
+        let (parts, body) = request.into_parts();
+        let (body, is_base64_encoded) = match body.into() {
+            Body::Empty => (None, false),
+            Body::Text(text) => (Some(text), false),
+            Body::Binary(data) => (Some(base64::encode(data)), true),
+        };
+
+        let mut headers = parts.headers;
+        if !headers.contains_key(HOST) {
+            let host = parts.uri.host().unwrap_or("localhost");
+            headers.insert(HOST, header_value(host));
+        }
+        let forwarded_port = parts.uri.port_u16().unwrap_or(443).to_string();
+        headers.insert(x_forwarded("for"), header_value(&self.source_ip));
+        headers.insert(x_forwarded("port"), header_value(&forwarded_port));
+        headers.insert(
+            x_forwarded("proto"),
+            header_value(parts.uri.scheme_str().unwrap_or("https")),
+        );
+        let host = headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("localhost")
+            .to_string();
+        let user_agent = headers
+            .get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_string);
+
+        let path = parts.uri.path().to_string();
+        let raw_query = parts.uri.query().unwrap_or_default().to_string();
+        let query: Vec<(String, String)> = serde_urlencoded::from_str(&raw_query).unwrap_or_default();
+        let (epoch, time) = request_time();
+
+        let event = match self.origin {
+            RequestOrigin::ApiGatewayV1 => {
+                let resource = self.resource.clone().unwrap_or_else(|| path.clone());
+                serde_json::to_value(ApiGatewayProxyRequest {
+                    resource: Some(resource.clone()),
+                    path: Some(path),
+                    http_method: parts.method.clone(),
+                    headers: last_values(&headers),
+                    multi_value_headers: headers,
+                    query_string_parameters: query.iter().cloned().collect(),
+                    multi_value_query_string_parameters: multi_values(query),
+                    path_parameters: self.path_parameters.clone(),
+                    stage_variables: self.stage_variables.clone(),
+                    request_context: ApiGatewayProxyRequestContext {
+                        account_id: Some(ACCOUNT_ID.to_string()),
+                        resource_id: Some("abc123".to_string()),
+                        stage: Some(self.stage.clone().unwrap_or_else(|| "prod".to_string())),
+                        domain_name: Some(host.clone()),
+                        domain_prefix: host.split('.').next().map(str::to_string),
+                        request_id: Some(request_id()),
+                        protocol: Some("HTTP/1.1".to_string()),
+                        identity: ApiGatewayRequestIdentity {
+                            source_ip: Some(self.source_ip.clone()),
+                            user_agent,
+                            ..Default::default()
+                        },
+                        resource_path: Some(resource),
+                        http_method: parts.method,
+                        request_time: Some(time),
+                        request_time_epoch: epoch,
+                        apiid: Some(API_ID.to_string()),
+                        ..Default::default()
+                    },
+                    body,
+                    is_base64_encoded: Some(is_base64_encoded),
+                })
+            }
+            RequestOrigin::ApiGatewayV2 => {
+                let cookies: Vec<String> = headers
+                    .get_all(COOKIE)
+                    .iter()
+                    .filter_map(|cookie| cookie.to_str().ok())
+                    .flat_map(|cookie| cookie.split(';'))
+                    .map(|cookie| cookie.trim().to_string())
+                    .filter(|cookie| !cookie.is_empty())
+                    .collect();
+                headers.remove(COOKIE);
+
+                let route_key = self.resource.clone().unwrap_or_else(|| "$default".to_string());
+                serde_json::to_value(ApiGatewayV2httpRequest {
+                    version: Some("2.0".to_string()),
+                    route_key: Some(route_key.clone()),
+                    raw_path: Some(path.clone()),
+                    raw_query_string: Some(raw_query),
+                    cookies: if cookies.is_empty() { None } else { Some(cookies) },
+                    headers: joined_values(&headers),
+                    query_string_parameters: joined_query(query),
+                    path_parameters: self.path_parameters.clone(),
+                    request_context: ApiGatewayV2httpRequestContext {
+                        route_key: Some(route_key),
+                        account_id: Some(ACCOUNT_ID.to_string()),
+                        stage: Some(self.stage.clone().unwrap_or_else(|| "$default".to_string())),
+                        request_id: Some(request_id()),
+                        authorizer: None,
+                        apiid: Some(API_ID.to_string()),
+                        domain_prefix: host.split('.').next().map(str::to_string),
+                        domain_name: Some(host),
+                        time: Some(time),
+                        time_epoch: epoch,
+                        http: ApiGatewayV2httpRequestContextHttpDescription {
+                            method: parts.method,
+                            path: Some(path),
+                            protocol: Some("HTTP/1.1".to_string()),
+                            source_ip: Some(self.source_ip.clone()),
+                            user_agent,
+                        },
+                    },
+                    stage_variables: self.stage_variables.clone(),
+                    body,
+                    is_base64_encoded,
+                })
+            }
+            RequestOrigin::Alb => {
+                // ALB doesn't decode query strings.
+                let query: Vec<(String, String)> = raw_query
+                    .split('&')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let mut pair = pair.splitn(2, '=');
+                        let name = pair.next().unwrap_or_default().to_string();
+                        (name, pair.next().unwrap_or_default().to_string())
+                    })
+                    .collect();
+                serde_json::to_value(AlbTargetGroupRequest {
+                    http_method: parts.method,
+                    path: Some(path),
+                    query_string_parameters: query.iter().cloned().collect(),
+                    multi_value_query_string_parameters: multi_values(query),
+                    headers: last_values(&headers),
+                    multi_value_headers: headers,
+                    request_context: AlbTargetGroupRequestContext {
+                        elb: ElbContext {
+                            target_group_arn: Some(TARGET_GROUP_ARN.to_string()),
+                        },
+                    },
+                    is_base64_encoded,
+                    body,
+                })
+            }
+        };
+        event.expect("events are always serializable")
+    }
+}
+
+/// Decodes the JSON `response` that a handler returned for an event from `origin`
+/// into an `http::Response`, like ALB and API Gateway do.
+///
+/// Multi-value headers are preferred over single value ones, the cookies of API Gateway
+/// HTTP API responses become `Set-Cookie` headers, and bodies encoded in base64 are
+/// decoded into binary bodies.
+pub fn decode_response(origin: RequestOrigin, response: Value) -> Result<Response<Body>, Error> {
+    let (status_code, headers, multi_value_headers, body, is_base64_encoded, cookies) = match origin {
+        RequestOrigin::ApiGatewayV1 => {
+            let res: ApiGatewayProxyResponse = serde_json::from_value(response)?;
+            let base64 = res.is_base64_encoded.unwrap_or_default();
+            (
+                res.status_code,
+                res.headers,
+                res.multi_value_headers,
+                res.body,
+                base64,
+                Vec::new(),
+            )
+        }
+        RequestOrigin::ApiGatewayV2 => {
+            let res: ApiGatewayV2httpResponse = serde_json::from_value(response)?;
+            let base64 = res.is_base64_encoded.unwrap_or_default();
+            (
+                res.status_code,
+                res.headers,
+                res.multi_value_headers,
+                res.body,
+                base64,
+                res.cookies,
+            )
+        }
+        RequestOrigin::Alb => {
+            let res: AlbTargetGroupResponse = serde_json::from_value(response)?;
+            let base64 = res.is_base64_encoded;
+            (
+                res.status_code,
+                res.headers,
+                res.multi_value_headers,
+                res.body,
+                base64,
+                Vec::new(),
+            )
+        }
+    };
+
+    let body = match body {
+        None => Body::Empty,
+        Some(Body::Text(text)) if is_base64_encoded => Body::Binary(base64::decode(text)?),
+        Some(body) => body,
+    };
+
+    let mut response = Response::new(body);
+    *response.status_mut() = StatusCode::from_u16(status_code as u16)?;
+    *response.headers_mut() = if multi_value_headers.is_empty() {
+        headers
+    } else {
+        multi_value_headers
+    };
+    for cookie in cookies {
+        response
+            .headers_mut()
+            .append(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+    }
+    Ok(response)
+}
+
+/// Invokes `handler` once with the event that `events` builds for `request`, and decodes
+/// its response, like [`decode_response`] does.
+///
+/// The event and the response go through JSON, like they do in Lambda. Errors of the
+/// handler are returned as a [`MockError`], with the type and message that Lambda reports.
+///
+/// [`decode_response`]: fn.decode_response.html
+/// [`MockError`]: ../lambda/testing/struct.MockError.html
+pub async fn invoke<H, B>(
+    events: &EventBuilder,
+    handler_fn: H,
+    request: http::Request<B>,
+) -> Result<Response<Body>, Error>
+where
+    H: Handler,
+    H::Error: fmt::Display,
+    B: Into<Body>,
+{
+    let event = events.build(request);
+    let response = lamedh_runtime::testing::invoke(&mut handler(handler_fn), event).await?;
+    decode_response(events.origin, response)
+}
+
+/// Returns a map with the last value of each header, like the `headers` of REST API and
+/// ALB events.
+fn last_values(headers: &HeaderMap) -> HeaderMap {
+    let mut last = HeaderMap::new();
+    for name in headers.keys() {
+        if let Some(value) = headers.get_all(name).iter().next_back() {
+            last.insert(name.clone(), value.clone());
+        }
+    }
+    last
+}
+
+/// Returns a map with the values of each header joined with commas, like the `headers` of
+/// HTTP API events.
+fn joined_values(headers: &HeaderMap) -> HeaderMap {
+    let mut joined = HeaderMap::new();
+    for name in headers.keys() {
+        let values: Vec<&[u8]> = headers.get_all(name).iter().map(HeaderValue::as_bytes).collect();
+        if let Ok(value) = HeaderValue::from_bytes(&values.join(&b","[..])) {
+            joined.insert(name.clone(), value);
+        }
+    }
+    joined
+}
+
+fn multi_values(pairs: Vec<(String, String)>) -> HashMap<String, Vec<String>> {
+    let mut values: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in pairs {
+        values.entry(name).or_default().push(value);
+    }
+    values
+}
+
+fn joined_query(pairs: Vec<(String, String)>) -> HashMap<String, String> {
+    multi_values(pairs)
+        .into_iter()
+        .map(|(name, values)| (name, values.join(",")))
+        .collect()
+}
+
+fn x_forwarded(suffix: &str) -> HeaderName {
+    HeaderName::from_bytes(format!("x-forwarded-{}", suffix).as_bytes()).expect("valid header name")
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static("invalid"))
+}
+
+/// Returns a request id in the format of API Gateway, unique within the process.
+fn request_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    format!("c6af9ac6-7b61-11e6-9a41-{:012x}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Returns the current time in milliseconds since the epoch, and formatted like
+/// `09/Apr/2015:12:34:56 +0000`.
+fn request_time() -> (i64, String) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let secs_of_day = secs.rem_euclid(86_400);
+    let time = format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    );
+    (now.as_millis() as i64, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        request::{from_str, LambdaRequest, RequestContext},
+        Context, IntoResponse, Request, RequestExt,
+    };
+
+    fn request() -> http::Request<&'static str> {
+        http::Request::post("https://api.example.com/pets/7?color=red&color=blue&q=a%20b")
+            .header("user-agent", "curl/7.64.1")
+            .header("cookie", "session=abc; theme=dark")
+            .body("{\"name\":\"Ferris\"}")
+            .unwrap()
+    }
+
+    async fn echo(request: Request, _: Context) -> Result<impl IntoResponse, Error> {
+        let params = request.query_string_parameters();
+        let colors = params.get_all("color").unwrap_or_default().join(",");
+        Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("x-colors", colors)
+            .header("set-cookie", "a=1")
+            .header("set-cookie", "b=2")
+            .body(request.body().to_vec())
+            .unwrap())
+    }
+
+    #[test]
+    fn builds_events_for_each_origin() {
+        for origin in &[
+            RequestOrigin::ApiGatewayV1,
+            RequestOrigin::ApiGatewayV2,
+            RequestOrigin::Alb,
+        ] {
+            let event = EventBuilder::new(*origin).build(request());
+            let parsed: LambdaRequest = serde_json::from_value(event.clone()).expect("failed to parse event");
+            assert_eq!(*origin, parsed.request_origin(), "{}", event);
+
+            let request = from_str(&event.to_string()).expect("failed to parse request");
+            assert_eq!("POST", request.method());
+            assert_eq!("/pets/7", request.uri().path());
+            assert_eq!(Some("api.example.com"), request.uri().host());
+            assert_eq!("curl/7.64.1", request.headers()["user-agent"]);
+            // HTTP APIs join repeated query string parameters with commas.
+            let colors = match origin {
+                RequestOrigin::ApiGatewayV2 => vec!["red,blue"],
+                _ => vec!["red", "blue"],
+            };
+            assert_eq!(Some(colors), request.query_string_parameters().get_all("color"));
+            assert_eq!(b"{\"name\":\"Ferris\"}", request.body().as_ref());
+        }
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn events_are_http_triggers_with_the_trace_of_their_traceparent_header() {
+        use lamedh_runtime::otel::TriggerKind;
+
+        for origin in &[
+            RequestOrigin::ApiGatewayV1,
+            RequestOrigin::ApiGatewayV2,
+            RequestOrigin::Alb,
+        ] {
+            let event = EventBuilder::new(*origin).build(request());
+            let parsed: LambdaRequest = serde_json::from_value(event).expect("failed to parse event");
+            assert_eq!(TriggerKind::Http, parsed.trigger().kind());
+            assert!(parsed.trigger().parent().is_none(), "{:?}", origin);
+
+            let mut traced = request();
+            traced.headers_mut().insert(
+                "traceparent",
+                http::HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            );
+            let event = EventBuilder::new(*origin).build(traced);
+            let parsed: LambdaRequest = serde_json::from_value(event).expect("failed to parse event");
+            assert!(parsed.trigger().parent().is_some(), "{:?}", origin);
+        }
+    }
+
+    #[test]
+    fn builds_api_gateway_v1_events() {
+        let event = EventBuilder::api_gateway_v1()
+            .stage("test")
+            .resource("/pets/{id}")
+            .path_parameter("id", "7")
+            .stage_variable("table", "pets")
+            .build(request());
+        assert_eq!("/pets/{id}", event["resource"]);
+        assert_eq!("a b", event["queryStringParameters"]["q"]);
+        assert_eq!("blue", event["queryStringParameters"]["color"]);
+
+        let request = from_str(&event.to_string()).unwrap();
+        assert_eq!(Some("7"), request.path_parameters().get("id"));
+        assert_eq!(Some("pets"), request.stage_variables().get("table"));
+        match request.request_context() {
+            RequestContext::ApiGatewayV1(ctx) => {
+                assert_eq!(Some("test"), ctx.stage.as_deref());
+                assert_eq!(Some("127.0.0.1"), ctx.identity.source_ip.as_deref());
+            }
+            ctx => panic!("unexpected request context: {:?}", ctx),
+        }
+    }
+
+    #[test]
+    fn builds_api_gateway_v2_events() {
+        let event = EventBuilder::api_gateway_v2()
+            .resource("POST /pets/{id}")
+            .build(request());
+        assert_eq!("2.0", event["version"]);
+        assert_eq!("POST /pets/{id}", event["routeKey"]);
+        assert_eq!("$default", event["requestContext"]["stage"]);
+        assert_eq!("red,blue", event["queryStringParameters"]["color"]);
+        assert_eq!(serde_json::json!(["session=abc", "theme=dark"]), event["cookies"]);
+        assert!(event["headers"].get("cookie").is_none());
+    }
+
+    #[test]
+    fn builds_alb_events_with_binary_bodies() {
+        let request = http::Request::put("http://localhost:8080/upload?q=a%20b")
+            .body(vec![0u8, 159, 146, 150])
+            .unwrap();
+        let event = EventBuilder::alb().build(request);
+        assert_eq!(true, event["isBase64Encoded"]);
+        assert_eq!(base64::encode([0u8, 159, 146, 150]), event["body"]);
+        assert_eq!("a%20b", event["queryStringParameters"]["q"]);
+        assert_eq!("8080", event["headers"]["x-forwarded-port"]);
+        assert_eq!("http", event["headers"]["x-forwarded-proto"]);
+    }
+
+    #[tokio::test]
+    async fn round_trips_responses_for_each_origin() -> Result<(), Error> {
+        for origin in &[
+            RequestOrigin::ApiGatewayV1,
+            RequestOrigin::ApiGatewayV2,
+            RequestOrigin::Alb,
+        ] {
+            let response = invoke(&EventBuilder::new(*origin), echo, request()).await?;
+            assert_eq!(StatusCode::CREATED, response.status());
+            assert_eq!("red,blue", response.headers()["x-colors"]);
+            let cookies: Vec<_> = response.headers().get_all(SET_COOKIE).iter().collect();
+            assert_eq!(vec!["a=1", "b=2"], cookies);
+            assert_eq!(b"{\"name\":\"Ferris\"}", response.body().as_ref());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_handler_errors() {
+        async fn fail(_: Request, _: Context) -> Result<&'static str, Error> {
+            Err("boom".into())
+        }
+
+        let err = invoke(&EventBuilder::api_gateway_v2(), fail, request())
+            .await
+            .expect_err("handler should fail");
+        assert!(err.to_string().contains("boom"));
+    }
+}
@@ -0,0 +1,100 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use http::HeaderMap;
+use lamedh_runtime::{
+    handler_fn,
+    testing::{MockEvent, MockRuntime},
+    Config, Context, Error,
+};
+use serde_json::{json, Value};
+use std::{convert::TryFrom, env, sync::Arc};
+
+async fn echo(event: Value, _: Context) -> Result<Value, Error> {
+    Ok(event)
+}
+
+/// Measures the overhead of the runtime around each invocation, with a handler
+/// that does nothing, over the in-memory Runtime API of `MockRuntime`.
+fn invocations(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let event = MockEvent::new(&json!({ "name": "Ferris" })).unwrap();
+
+    let mut group = c.benchmark_group("simulated");
+    for &count in &[1u64, 100] {
+        group.throughput(Throughput::Elements(count));
+        group.bench_function(format!("{}_invocations", count), |b| {
+            b.iter_batched(
+                || {
+                    let mut runtime = MockRuntime::new();
+                    for _ in 0..count {
+                        runtime.push_event(event.clone());
+                    }
+                    runtime
+                },
+                |mut runtime| rt.block_on(runtime.run(handler_fn(echo))).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Measures the cost of handing a context to a handler.
+fn contexts(c: &mut Criterion) {
+    let ctx = Context::builder().build();
+    c.bench_function("context_clone", |b| b.iter(|| ctx.clone()));
+}
+
+/// Measures building the context of each invocation from the headers of the Runtime API,
+/// with the configuration loaded from a populated environment on every invocation, as
+/// the runtime used to, against the configuration loaded once and shared.
+fn configs(c: &mut Criterion) {
+    for (name, value) in &[
+        ("AWS_LAMBDA_RUNTIME_API", "127.0.0.1:9001"),
+        ("AWS_LAMBDA_FUNCTION_NAME", "my-function"),
+        ("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "1024"),
+        ("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST"),
+        ("AWS_LAMBDA_LOG_STREAM_NAME", "2021/01/01/[$LATEST]abcdef"),
+        ("AWS_LAMBDA_LOG_GROUP_NAME", "/aws/lambda/my-function"),
+        ("AWS_REGION", "eu-west-1"),
+        ("_HANDLER", "bootstrap"),
+        ("LAMBDA_TASK_ROOT", "/var/task"),
+        ("LAMBDA_RUNTIME_DIR", "/var/runtime"),
+        ("AWS_LAMBDA_INITIALIZATION_TYPE", "on-demand"),
+        ("AWS_LAMBDA_FUNCTION_TIMEOUT", "30"),
+    ] {
+        env::set_var(name, value);
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert("lambda-runtime-aws-request-id", "52fdfc07".parse().unwrap());
+    headers.insert("lambda-runtime-deadline-ms", "1542409706888".parse().unwrap());
+    headers.insert(
+        "lambda-runtime-invoked-function-arn",
+        "arn:aws:lambda:eu-west-1:123456789012:function:my-function"
+            .parse()
+            .unwrap(),
+    );
+
+    let mut group = c.benchmark_group("context_per_invocation");
+    group.bench_function("config_from_env", |b| {
+        b.iter(|| {
+            let mut ctx = Context::try_from(headers.clone()).unwrap();
+            ctx.env_config = Arc::new(Config::from_env().unwrap());
+            ctx
+        })
+    });
+    let config = Arc::new(Config::from_env().unwrap());
+    group.bench_function("shared_config", |b| {
+        b.iter(|| {
+            let mut ctx = Context::try_from(headers.clone()).unwrap();
+            ctx.env_config = config.clone();
+            ctx
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, invocations, contexts, configs);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 /// See https://github.com/awslabs/aws-lambda-rust-runtime for more info on Rust runtime for AWS Lambda
-use lamedh_runtime::{handler_fn, run, Context, Error};
+use lamedh_runtime::{handler_fn, logging, run, Context, Error};
 use serde::de::IntoDeserializer;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -50,14 +50,9 @@ impl std::fmt::Display for CustomError {
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        // this needs to be set to false, otherwise ANSI color codes will
-        // show up in a confusing manner in CloudWatch logs.
-        .with_ansi(false)
-        // disabling time is handy because CloudWatch will add the ingestion time.
-        .without_time()
-        .init();
+    // writes logs as JSON, with the request id and the other fields of each invocation,
+    // at the level of the AWS_LAMBDA_LOG_LEVEL environment variable.
+    logging::init()?;
 
     // call the actual handler of the request
     run(handler_fn(func)).await?;
@@ -0,0 +1,20 @@
+use lamedh_runtime::{handler_fn, run_local, Context, Error};
+use serde_json::{json, Value};
+use std::{cell::RefCell, rc::Rc};
+
+// `run_local` polls for events on a single thread, so handler state
+// can live in an `Rc<RefCell<_>>` instead of an `Arc<Mutex<_>>`.
+
+fn main() -> Result<(), Error> {
+    let invocations = Rc::new(RefCell::new(0_u64));
+    run_local(handler_fn(move |event: Value, ctx: Context| {
+        func(event, ctx, invocations.clone())
+    }))
+}
+
+async fn func(_: Value, ctx: Context, invocations: Rc<RefCell<u64>>) -> Result<Value, Error> {
+    *invocations.borrow_mut() += 1;
+    let count = *invocations.borrow();
+
+    Ok(json!({ "req_id": ctx.request_id, "invocations": count }))
+}
@@ -3,10 +3,10 @@ use serde_json::Value;
 
 // #[lambda] attribute removes the need for boilerplate code
 // required by `lambda::run(func).await?` as demonstrated in other
-// examples.
+// examples. Without #[tokio::main], it also starts a Tokio runtime
+// sized for the vCPUs that Lambda allocates to the function.
 
 #[lambda]
-#[tokio::main]
 async fn main(event: Value, _: Context) -> Result<Value, Error> {
     Ok(event)
 }
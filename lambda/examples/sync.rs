@@ -0,0 +1,13 @@
+use lamedh_runtime::{lambda, Context, Error};
+use serde_json::{json, Value};
+
+// A synchronous main function runs the handler on a blocking thread,
+// which suits CPU-bound handlers that never await anything.
+// There is no need for a `#[tokio::main]` attribute in this case.
+
+#[lambda]
+fn main(event: Value, _: Context) -> Result<Value, Error> {
+    let words = event["text"].as_str().unwrap_or_default().split_whitespace().count();
+
+    Ok(json!({ "words": words }))
+}
@@ -1,14 +1,68 @@
 use crate::Error;
-use http::{uri::Scheme, Request, Response, Uri};
-use hyper::{client::HttpConnector, Body};
+use http::{Request, Response, Uri};
+use hyper::Body;
+use std::{future::Future, pin::Pin};
+
+#[cfg(any(test, feature = "minimal-client", not(feature = "hyper-client")))]
+mod minimal;
+#[cfg(any(test, feature = "minimal-client", not(feature = "hyper-client")))]
+pub(crate) use minimal::MinimalClient;
+
+/// The response of a client, which is `Send` so that the runtime's future is too.
+pub(crate) type ResponseFuture<'a> = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send + 'a>>;
+
+/// A client for the Runtime API.
+pub(crate) trait Client {
+    /// Sends a request to the Runtime API. The URI of the request only has a path.
+    fn call(&self, req: Request<Body>) -> ResponseFuture<'_>;
+}
+
+/// Creates the client for the Runtime API at `base` that the runtime uses, hyper's
+/// client by default, or a minimal HTTP/1.1 client with the `minimal-client` feature.
+#[cfg(all(feature = "hyper-client", not(feature = "minimal-client")))]
+pub(crate) fn connect(base: Uri) -> Result<impl Client, Error> {
+    Ok(HyperClient::with(base, hyper::Client::new()))
+}
+
+/// Creates the client for the Runtime API at `base` that the runtime uses, hyper's
+/// client by default, or a minimal HTTP/1.1 client with the `minimal-client` feature.
+#[cfg(any(feature = "minimal-client", not(feature = "hyper-client")))]
+pub(crate) fn connect(base: Uri) -> Result<impl Client, Error> {
+    let authority = base.authority().ok_or("The Runtime API endpoint has no authority")?;
+    MinimalClient::tcp(authority.as_str())
+}
+
+/// Creates the client that the runtime uses, over an in-memory connection.
+#[cfg(all(feature = "simulated", feature = "hyper-client", not(feature = "minimal-client")))]
+pub(crate) fn simulated(stream: crate::simulated::SimStream) -> impl Client {
+    let connector = crate::simulated::Connector { inner: stream };
+    HyperClient::with(
+        Uri::from_static("http://localhost:9001"),
+        hyper::Client::builder().build(connector),
+    )
+}
 
+/// Creates the client that the runtime uses, over an in-memory connection.
+#[cfg(all(
+    feature = "simulated",
+    any(feature = "minimal-client", not(feature = "hyper-client"))
+))]
+pub(crate) fn simulated(stream: crate::simulated::SimStream) -> impl Client {
+    MinimalClient::simulated(stream)
+}
+
+/// A client for the Runtime API that uses hyper's client.
+#[cfg(feature = "hyper-client")]
+#[cfg_attr(feature = "minimal-client", allow(dead_code))]
 #[derive(Debug)]
-pub(crate) struct Client<C = HttpConnector> {
+pub(crate) struct HyperClient<C = hyper::client::HttpConnector> {
     base: Uri,
     client: hyper::Client<C>,
 }
 
-impl<C> Client<C>
+#[cfg(feature = "hyper-client")]
+#[cfg_attr(feature = "minimal-client", allow(dead_code))]
+impl<C> HyperClient<C>
 where
     C: hyper::client::connect::Connect + Sync + Send + Clone + 'static,
 {
@@ -19,7 +73,7 @@ where
     fn set_origin<B>(&self, req: Request<B>) -> Result<Request<B>, Error> {
         let (mut parts, body) = req.into_parts();
         let (scheme, authority) = {
-            let scheme = self.base.scheme().unwrap_or(&Scheme::HTTP);
+            let scheme = self.base.scheme().unwrap_or(&http::uri::Scheme::HTTP);
             let authority = self.base.authority().expect("Authority not found");
             (scheme, authority)
         };
@@ -35,31 +89,35 @@ where
         parts.uri = uri;
         Ok(Request::from_parts(parts, body))
     }
+}
 
-    pub(crate) async fn call(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
-        let req = self.set_origin(req)?;
-        let (parts, body) = req.into_parts();
-        let body = Body::from(body);
-        let req = Request::from_parts(parts, body);
-        let response = self.client.request(req).await?;
-        Ok(response)
+#[cfg(feature = "hyper-client")]
+impl<C> Client for HyperClient<C>
+where
+    C: hyper::client::connect::Connect + Sync + Send + Clone + 'static,
+{
+    fn call(&self, req: Request<Body>) -> ResponseFuture<'_> {
+        Box::pin(async move {
+            let req = self.set_origin(req)?;
+            let response = self.client.request(req).await?;
+            Ok(response)
+        })
     }
 }
 
 #[cfg(test)]
 mod endpoint_tests {
+    use super::{Client, MinimalClient};
     use crate::{
         requests::{
-            EventCompletionRequest, EventErrorRequest, IntoRequest, IntoResponse, NextEventRequest, NextEventResponse,
+            EventCompletionRequest, EventErrorRequest, InitErrorRequest, IntoRequest, IntoResponse, NextEventRequest,
+            NextEventResponse,
         },
-        simulated::Connector,
+        simulated::{Faults, SimStream},
         types::Diagnostic,
-        Error,
-    };
-    use http::{
-        uri::{PathAndQuery, Scheme},
-        HeaderValue, Method, Request, Response, StatusCode, Uri,
+        Error, Payload,
     };
+    use http::{uri::PathAndQuery, HeaderValue, Method, Request, Response, StatusCode};
     use hyper::{server::conn::Http, service::service_fn, Body};
     use serde_json::json;
     use std::convert::TryFrom;
@@ -87,7 +145,7 @@ mod endpoint_tests {
             ["2018-06-01", "runtime", "invocation", "next"] => next_event(&req).await,
             ["2018-06-01", "runtime", "invocation", id, "response"] => complete_event(&req, id).await,
             ["2018-06-01", "runtime", "invocation", id, "error"] => event_err(&req, id).await,
-            ["2018-06-01", "runtime", "init", "error"] => unimplemented!(),
+            ["2018-06-01", "runtime", "init", "error"] => init_err(&req).await,
             _ => unimplemented!(),
         }
     }
@@ -115,6 +173,18 @@ mod endpoint_tests {
         }
     }
 
+    /// Serves every request with `status` and `body`, like a Runtime API that is failing.
+    async fn handle_with_status<I>(io: I, status: StatusCode, body: &'static [u8]) -> Result<(), hyper::Error>
+    where
+        I: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let service =
+            service_fn(
+                move |_: Request<Body>| async move { Response::builder().status(status).body(Body::from(body)) },
+            );
+        Http::new().serve_connection(io, service).await
+    }
+
     async fn next_event(req: &Request<Body>) -> Result<Response<Body>, Error> {
         let path = "/2018-06-01/runtime/invocation/next";
         assert_eq!(req.method(), Method::GET);
@@ -157,42 +227,71 @@ mod endpoint_tests {
         Ok(rsp)
     }
 
-    fn set_origin<B>(base: Uri, req: Request<B>) -> Result<Request<B>, Error> {
-        let (mut parts, body) = req.into_parts();
-        let (scheme, authority) = {
-            let scheme = base.scheme().unwrap_or(&Scheme::HTTP);
-            let authority = base.authority().expect("Authority not found");
-            (scheme, authority)
-        };
-        let path = parts.uri.path_and_query().expect("PathAndQuery not found");
+    async fn init_err(req: &Request<Body>) -> Result<Response<Body>, Error> {
+        assert_eq!(req.method(), Method::POST);
+        let header = "lambda-runtime-function-error-type";
+        let expected = "unhandled";
+        assert_eq!(req.headers()[header], HeaderValue::try_from(expected)?);
 
-        let uri = Uri::builder()
-            .scheme(scheme.clone())
-            .authority(authority.clone())
-            .path_and_query(path.clone())
-            .build()
-            .expect("Unable to build URI");
+        let rsp = Response::builder().status(StatusCode::ACCEPTED).body(Body::empty())?;
+        Ok(rsp)
+    }
 
-        parts.uri = uri;
-        Ok(Request::from_parts(parts, body))
+    /// Runs the tests with every client, over an in-memory connection to the test server.
+    macro_rules! client_tests {
+        ($($name:ident),* $(,)?) => {
+            #[cfg(feature = "hyper-client")]
+            mod hyper_client {
+                $(
+                    #[tokio::test]
+                    async fn $name() -> Result<(), crate::Error> {
+                        super::$name(|stream| {
+                            let connector = crate::simulated::Connector { inner: stream };
+                            crate::client::HyperClient::with(
+                                http::Uri::from_static("http://localhost:9001"),
+                                hyper::Client::builder().build(connector),
+                            )
+                        })
+                        .await
+                    }
+                )*
+            }
+
+            mod minimal_client {
+                $(
+                    #[tokio::test]
+                    async fn $name() -> Result<(), crate::Error> {
+                        super::$name(super::MinimalClient::simulated).await
+                    }
+                )*
+            }
+        };
     }
 
-    #[tokio::test]
-    async fn test_next_event() -> Result<(), Error> {
+    client_tests!(
+        test_next_event,
+        ok_response,
+        error_response,
+        init_error_response,
+        server_error_status,
+        payload_too_large_status,
+        connection_closed_mid_request,
+        truncated_response_body,
+        reuses_the_connection,
+    );
+
+    async fn test_next_event<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
         let (client, server) = crate::simulated::chan();
-        let base = Uri::from_static("http://localhost:9001");
 
         let (tx, rx) = sync::oneshot::channel();
         let server = tokio::spawn(async {
             handle(server, rx).await.expect("Unable to handle request");
         });
 
-        let conn = Connector { inner: client };
-        let client = hyper::Client::builder().build(conn);
+        let client = connect(client);
 
         let req = NextEventRequest.into_req()?;
-        let req = set_origin(base, req)?;
-        let rsp = client.request(req).await.expect("Unable to send request");
+        let rsp = client.call(req).await.expect("Unable to send request");
 
         assert_eq!(rsp.status(), StatusCode::OK);
         let header = "lambda-runtime-deadline-ms";
@@ -202,55 +301,48 @@ mod endpoint_tests {
         tx.send(()).expect("Receiver has been dropped");
         match server.await {
             Ok(_) => Ok(()),
-            Err(e) if e.is_panic() => return Err::<(), Error>(e.into()),
+            Err(e) if e.is_panic() => Err::<(), Error>(e.into()),
             Err(_) => unreachable!("This branch shouldn't be reachable"),
         }
     }
 
-    #[tokio::test]
-    async fn ok_response() -> Result<(), Error> {
+    async fn ok_response<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
         let (client, server) = crate::simulated::chan();
         let (tx, rx) = sync::oneshot::channel();
-        let base = Uri::from_static("http://localhost:9001");
 
         let server = tokio::spawn(async {
             handle(server, rx).await.expect("Unable to handle request");
         });
 
-        let conn = Connector { inner: client };
-        let client = hyper::Client::builder().build(conn);
+        let client = connect(client);
 
         let req = EventCompletionRequest {
             request_id: "156cb537-e2d4-11e8-9b34-d36013741fb9",
-            body: "done",
+            body: Payload::from(r#""done""#),
         };
         let req = req.into_req()?;
-        let req = set_origin(base, req)?;
 
-        let rsp = client.request(req).await?;
+        let rsp = client.call(req).await?;
         assert_eq!(rsp.status(), StatusCode::ACCEPTED);
 
         // shutdown server
         tx.send(()).expect("Receiver has been dropped");
         match server.await {
             Ok(_) => Ok(()),
-            Err(e) if e.is_panic() => return Err::<(), Error>(e.into()),
+            Err(e) if e.is_panic() => Err::<(), Error>(e.into()),
             Err(_) => unreachable!("This branch shouldn't be reachable"),
         }
     }
 
-    #[tokio::test]
-    async fn error_response() -> Result<(), Error> {
+    async fn error_response<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
         let (client, server) = crate::simulated::chan();
         let (tx, rx) = sync::oneshot::channel();
-        let base = Uri::from_static("http://localhost:9001");
 
         let server = tokio::spawn(async {
             handle(server, rx).await.expect("Unable to handle request");
         });
 
-        let conn = Connector { inner: client };
-        let client = hyper::Client::builder().build(conn);
+        let client = connect(client);
 
         let req = EventErrorRequest {
             request_id: "156cb537-e2d4-11e8-9b34-d36013741fb9",
@@ -260,19 +352,146 @@ mod endpoint_tests {
             },
         };
         let req = req.into_req()?;
-        let req = set_origin(base, req)?;
-        let rsp = client.request(req).await?;
+        let rsp = client.call(req).await?;
         assert_eq!(rsp.status(), StatusCode::ACCEPTED);
 
         // shutdown server
         tx.send(()).expect("Receiver has been dropped");
         match server.await {
             Ok(_) => Ok(()),
-            Err(e) if e.is_panic() => return Err::<(), Error>(e.into()),
+            Err(e) if e.is_panic() => Err::<(), Error>(e.into()),
             Err(_) => unreachable!("This branch shouldn't be reachable"),
         }
     }
 
+    async fn init_error_response<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
+        let (client, server) = crate::simulated::chan();
+        let (tx, rx) = sync::oneshot::channel();
+
+        let server = tokio::spawn(async {
+            handle(server, rx).await.expect("Unable to handle request");
+        });
+
+        let client = connect(client);
+
+        let req = InitErrorRequest {
+            diagnostic: Diagnostic {
+                error_type: "InitError".to_string(),
+                error_message: "Unable to initialize".to_string(),
+            },
+        };
+        let req = req.into_req()?;
+        let rsp = client.call(req).await?;
+        assert_eq!(rsp.status(), StatusCode::ACCEPTED);
+
+        // shutdown server
+        tx.send(()).expect("Receiver has been dropped");
+        match server.await {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_panic() => Err::<(), Error>(e.into()),
+            Err(_) => unreachable!("This branch shouldn't be reachable"),
+        }
+    }
+
+    async fn server_error_status<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
+        let (client, server) = crate::simulated::chan();
+
+        let server = tokio::spawn(handle_with_status(server, StatusCode::INTERNAL_SERVER_ERROR, b""));
+
+        let client = connect(client);
+
+        let req = NextEventRequest.into_req()?;
+        let rsp = client.call(req).await?;
+        assert_eq!(rsp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(rsp.headers().get("lambda-runtime-aws-request-id").is_none());
+
+        server.abort();
+        Ok(())
+    }
+
+    async fn payload_too_large_status<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
+        let (client, server) = crate::simulated::chan();
+
+        let body = br#"{"errorMessage":"Exceeded maximum allowed payload size","errorType":"RequestEntityTooLarge"}"#;
+        let server = tokio::spawn(handle_with_status(server, StatusCode::PAYLOAD_TOO_LARGE, body));
+
+        let client = connect(client);
+
+        let req = EventCompletionRequest {
+            request_id: "156cb537-e2d4-11e8-9b34-d36013741fb9",
+            body: Payload::from(r#""done""#),
+        };
+        let req = req.into_req()?;
+        let rsp = client.call(req).await?;
+        assert_eq!(rsp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        server.abort();
+        Ok(())
+    }
+
+    async fn connection_closed_mid_request<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
+        let (mut client, server) = crate::simulated::chan();
+        let (_tx, rx) = sync::oneshot::channel();
+
+        let server = tokio::spawn(handle(server, rx));
+
+        client.set_faults(Faults::new().close_after(16));
+        let client = connect(client);
+
+        let req = EventCompletionRequest {
+            request_id: "156cb537-e2d4-11e8-9b34-d36013741fb9",
+            body: Payload::from(r#""done""#),
+        };
+        let req = req.into_req()?;
+        assert!(client.call(req).await.is_err());
+
+        // The server sees the truncated request as a failed connection
+        assert!(server.await?.is_err());
+        Ok(())
+    }
+
+    async fn truncated_response_body<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
+        let (client, mut server) = crate::simulated::chan();
+
+        // Enough room for the status line and headers, but not the whole body
+        server.set_faults(Faults::new().close_after(256));
+        let server = tokio::spawn(handle_with_status(server, StatusCode::OK, &[b'a'; 1024]));
+
+        let client = connect(client);
+
+        let req = NextEventRequest.into_req()?;
+        // hyper fails when the body is read, and the minimal client reads it right away
+        let body = async {
+            let rsp = client.call(req).await?;
+            assert_eq!(rsp.status(), StatusCode::OK);
+            Ok::<_, Error>(hyper::body::to_bytes(rsp.into_body()).await?)
+        };
+        assert!(body.await.is_err());
+
+        server.abort();
+        Ok(())
+    }
+
+    async fn reuses_the_connection<C: Client>(connect: impl Fn(SimStream) -> C) -> Result<(), Error> {
+        let (client, server) = crate::simulated::chan();
+        let (_tx, rx) = sync::oneshot::channel();
+        let server = tokio::spawn(handle(server, rx));
+
+        // The in-memory connection can't be opened twice, so every request goes through it.
+        let client = connect(client);
+        for _ in 0..3 {
+            let rsp = client.call(NextEventRequest.into_req()?).await?;
+            assert_eq!(rsp.status(), StatusCode::OK);
+            assert_eq!(
+                br#"{"message":"hello"}"#,
+                &hyper::body::to_bytes(rsp.into_body()).await?[..]
+            );
+        }
+
+        server.abort();
+        Ok(())
+    }
+
     // #[tokio::test]
     // async fn run_end_to_end() -> Result<(), Error> {
     //     use serde_json::Value;
@@ -312,7 +531,7 @@ mod endpoint_tests {
     //         handle(server, rx).await.expect("Unable to handle request");
     //     });
 
-    //     let rsp = client.request(req).await.expect("Unable to send request");
+    //     let rsp = client.call(req).await.expect("Unable to send request");
     //     assert_eq!(rsp.status(), http::StatusCode::OK);
 
     //     // shutdown server
@@ -0,0 +1,452 @@
+use super::{Client, ResponseFuture};
+use crate::Error;
+use bytes::{Buf, Bytes, BytesMut};
+use http::{
+    header::{CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+    request::Parts,
+    HeaderValue, Request, Response, StatusCode, Version,
+};
+use hyper::Body;
+use std::{fmt, future::Future, io, pin::Pin};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tracing::trace;
+
+/// The most headers that a response of the Runtime API can have.
+const MAX_HEADERS: usize = 64;
+
+type Connect<S> = Box<dyn Fn() -> Pin<Box<dyn Future<Output = io::Result<S>> + Send>> + Send + Sync>;
+
+/// A minimal HTTP/1.1 client for the Runtime API.
+///
+/// The Runtime API is plain HTTP/1.1 over localhost, and the runtime sends one request
+/// at a time, so the client keeps a single persistent connection. It reconnects when
+/// the connection fails, or when the Runtime API closes it. A request that can't be written
+/// to a reused connection is sent again on a new one. Requests that were written aren't,
+/// even when their response is lost, since posting a result twice could deliver it twice.
+pub(crate) struct MinimalClient<S = TcpStream> {
+    host: HeaderValue,
+    connect: Connect<S>,
+    conn: Mutex<Option<Connection<S>>>,
+}
+
+impl<S> fmt::Debug for MinimalClient<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MinimalClient").field("host", &self.host).finish()
+    }
+}
+
+impl MinimalClient {
+    /// Creates a client for the Runtime API at `authority`, like `127.0.0.1:9001`.
+    #[cfg_attr(
+        all(test, feature = "hyper-client", not(feature = "minimal-client")),
+        allow(dead_code)
+    )]
+    pub(crate) fn tcp(authority: &str) -> Result<Self, Error> {
+        let addr = authority.to_owned();
+        Ok(Self::with(
+            HeaderValue::from_str(authority)?,
+            Box::new(move || {
+                let addr = addr.clone();
+                Box::pin(async move {
+                    let stream = TcpStream::connect(addr).await?;
+                    stream.set_nodelay(true)?;
+                    Ok(stream)
+                })
+            }),
+        ))
+    }
+}
+
+#[cfg(any(test, feature = "simulated"))]
+impl MinimalClient<crate::simulated::SimStream> {
+    /// Creates a client over an in-memory connection. Reconnecting reuses the connection.
+    pub(crate) fn simulated(stream: crate::simulated::SimStream) -> Self {
+        Self::with(
+            HeaderValue::from_static("localhost:9001"),
+            Box::new(move || {
+                let stream = stream.clone();
+                Box::pin(async move { Ok(stream) })
+            }),
+        )
+    }
+}
+
+impl<S> MinimalClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Creates a client that opens its connections with `connect`.
+    pub(crate) fn with(host: HeaderValue, connect: Connect<S>) -> Self {
+        MinimalClient {
+            host,
+            connect,
+            conn: Mutex::new(None),
+        }
+    }
+}
+
+impl<S> Client for MinimalClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    fn call(&self, req: Request<Body>) -> ResponseFuture<'_> {
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+
+            let mut conn = self.conn.lock().await;
+            let (mut current, reused) = match conn.take() {
+                Some(current) => (current, true),
+                None => (Connection::new((self.connect)().await?), false),
+            };
+            let (rsp, keep_alive) = match current.send(&self.host, &parts, &body).await {
+                // The Runtime API may have closed a kept-alive connection since the
+                // last call. Nothing was sent, so the request is sent again.
+                Err(e) if reused && !current.sent => {
+                    trace!("Retrying on a new connection: {}", e);
+                    current = Connection::new((self.connect)().await?);
+                    current.send(&self.host, &parts, &body).await?
+                }
+                res => res?,
+            };
+            // A connection that failed is dropped above, and replaced on the next call.
+            if keep_alive {
+                *conn = Some(current);
+            }
+            Ok(rsp)
+        })
+    }
+}
+
+/// A connection to the Runtime API, with the bytes read from it that haven't been parsed yet.
+struct Connection<S> {
+    stream: S,
+    buf: BytesMut,
+    /// Whether any bytes of the current request were written to the connection.
+    sent: bool,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: S) -> Self {
+        Connection {
+            stream,
+            buf: BytesMut::with_capacity(8 * 1024),
+            sent: false,
+        }
+    }
+
+    /// Sends `req`, and reads the whole response. Returns whether the connection can be reused.
+    async fn send(&mut self, host: &HeaderValue, parts: &Parts, body: &[u8]) -> Result<(Response<Body>, bool), Error> {
+        self.sent = false;
+        let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+
+        let mut head = Vec::with_capacity(256);
+        head.extend_from_slice(format!("{} {} HTTP/1.1\r\n", parts.method, path).as_bytes());
+        if !parts.headers.contains_key(HOST) {
+            write_header(&mut head, HOST.as_str(), host);
+        }
+        for (name, value) in &parts.headers {
+            write_header(&mut head, name.as_str(), value);
+        }
+        if !body.is_empty() || parts.method == http::Method::POST {
+            write_header(&mut head, CONTENT_LENGTH.as_str(), &HeaderValue::from(body.len()));
+        }
+        head.extend_from_slice(b"\r\n");
+        head.extend_from_slice(body);
+
+        let mut unsent = &head[..];
+        while !unsent.is_empty() {
+            let written = self.stream.write(unsent).await?;
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            self.sent = true;
+            unsent = &unsent[written..];
+        }
+        self.stream.flush().await?;
+        self.read_response().await
+    }
+
+    async fn read_response(&mut self) -> Result<(Response<Body>, bool), Error> {
+        let (builder, head) = loop {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut parsed = httparse::Response::new(&mut headers);
+            if let httparse::Status::Complete(len) = parsed.parse(&self.buf)? {
+                let status = StatusCode::from_u16(parsed.code.unwrap_or_default())?;
+                if status.is_informational() {
+                    // Interim responses come before the final one, which is read next.
+                    self.buf.advance(len);
+                    continue;
+                }
+                let mut head = Head {
+                    len,
+                    keep_alive: parsed.version == Some(1),
+                    // These responses never have a body, whatever their headers say.
+                    empty: status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED,
+                    chunked: false,
+                    content_length: None,
+                };
+                let mut builder = Response::builder().status(status).version(if head.keep_alive {
+                    Version::HTTP_11
+                } else {
+                    Version::HTTP_10
+                });
+                for header in parsed.headers.iter() {
+                    let value = std::str::from_utf8(header.value)?.trim();
+                    if header.name.eq_ignore_ascii_case(CONNECTION.as_str()) && value.eq_ignore_ascii_case("close") {
+                        head.keep_alive = false;
+                    } else if header.name.eq_ignore_ascii_case(TRANSFER_ENCODING.as_str()) {
+                        head.chunked = value.to_ascii_lowercase().ends_with("chunked");
+                    } else if header.name.eq_ignore_ascii_case(CONTENT_LENGTH.as_str()) {
+                        head.content_length = Some(value.parse::<usize>()?);
+                    }
+                    builder = builder.header(header.name, header.value);
+                }
+                break (builder, head);
+            }
+            self.fill().await?;
+        };
+        self.buf.advance(head.len);
+
+        let mut keep_alive = head.keep_alive;
+        let body = if head.empty {
+            Bytes::new()
+        } else if head.chunked {
+            self.read_chunked().await?.into()
+        } else if let Some(length) = head.content_length {
+            self.read_exact(length).await?
+        } else {
+            // Without a length, the body ends with the connection.
+            keep_alive = false;
+            self.read_to_end().await?.into()
+        };
+
+        Ok((builder.body(Body::from(body))?, keep_alive))
+    }
+
+    async fn read_exact(&mut self, len: usize) -> io::Result<Bytes> {
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buf.split_to(len).freeze())
+    }
+
+    async fn read_chunked(&mut self) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            let size = line.split(|b| *b == b';').next().unwrap_or_default();
+            let size = usize::from_str_radix(std::str::from_utf8(size)?.trim(), 16)?;
+            if size == 0 {
+                // Skip the trailers, up to the empty line that ends the body.
+                while !self.read_line().await?.is_empty() {}
+                return Ok(body);
+            }
+            body.extend_from_slice(&self.read_exact(size).await?);
+            if !self.read_line().await?.is_empty() {
+                return Err("Invalid chunk in the response of the Runtime API".into());
+            }
+        }
+    }
+
+    /// Reads a line, without its line ending.
+    async fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buf.split_to(end).to_vec();
+                self.buf.advance(2);
+                return Ok(line);
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn read_to_end(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = self.buf.split().to_vec();
+        self.stream.read_to_end(&mut body).await?;
+        Ok(body)
+    }
+
+    /// Reads more bytes from the connection. The connection closing counts as an error.
+    async fn fill(&mut self) -> io::Result<()> {
+        if self.stream.read_buf(&mut self.buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The Runtime API closed the connection",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The parts of a response head that frame the body.
+struct Head {
+    len: usize,
+    keep_alive: bool,
+    empty: bool,
+    chunked: bool,
+    content_length: Option<usize>,
+}
+
+fn write_header(head: &mut Vec<u8>, name: &str, value: &HeaderValue) {
+    head.extend_from_slice(name.as_bytes());
+    head.extend_from_slice(b": ");
+    head.extend_from_slice(value.as_bytes());
+    head.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulated::{chan, Faults};
+    use std::sync::Arc;
+
+    /// Sends a request to a server that answers with `raw`, then closes the connection if `close`.
+    async fn respond(raw: &'static [u8], close: bool) -> Result<(Bytes, bool), Error> {
+        let (client, mut server) = chan();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let _ = server.read(&mut buf).await;
+            server.write_all(raw).await.unwrap();
+            if close {
+                server.close();
+            }
+        });
+        let mut conn = Connection::new(client);
+        let (parts, _) = Request::get("/2018-06-01/runtime/invocation/next")
+            .body(())?
+            .into_parts();
+        let (rsp, keep_alive) = conn
+            .send(&HeaderValue::from_static("localhost:9001"), &parts, &[])
+            .await?;
+        Ok((hyper::body::to_bytes(rsp.into_body()).await?, keep_alive))
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_bodies() -> Result<(), Error> {
+        let (body, keep_alive) = respond(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n",
+            false,
+        )
+        .await?;
+        assert_eq!(&b"hello world"[..], &body[..]);
+        assert!(keep_alive);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_up_to_the_end_of_the_connection() -> Result<(), Error> {
+        let (body, keep_alive) = respond(b"HTTP/1.1 200 OK\r\n\r\nhello", true).await?;
+        assert_eq!(&b"hello"[..], &body[..]);
+        assert!(!keep_alive);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responses_without_bodies_keep_the_connection() -> Result<(), Error> {
+        for raw in &[
+            &b"HTTP/1.1 204 No Content\r\n\r\n"[..],
+            &b"HTTP/1.1 304 Not Modified\r\n\r\n"[..],
+            &b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n"[..],
+        ] {
+            // The connection stays open, so reading up to its end would never return.
+            let (body, keep_alive) = respond(raw, false).await?;
+            assert!(body.is_empty());
+            assert!(keep_alive);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retries_requests_on_stale_connections() -> Result<(), Error> {
+        const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+        // The first connection closes once it has sent its first response, like a
+        // kept-alive connection that the Runtime API closed between two calls.
+        let (stale, mut server) = chan();
+        server.set_faults(Faults::new().close_after(RESPONSE.len()));
+        let (fresh, second) = chan();
+        for mut server in [server, second] {
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while server.read(&mut buf).await.unwrap_or_default() > 0 {
+                    if server.write_all(RESPONSE).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let connections = std::sync::Mutex::new(vec![fresh, stale]);
+        let client = MinimalClient::with(
+            HeaderValue::from_static("localhost:9001"),
+            Box::new(move || {
+                let stream = connections.lock().unwrap().pop();
+                Box::pin(async move { stream.ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused)) })
+            }),
+        );
+
+        for _ in 0..2 {
+            let req = Request::get("/2018-06-01/runtime/invocation/next").body(Body::empty())?;
+            let rsp = client.call(req).await?;
+            assert_eq!(&b"ok"[..], &hyper::body::to_bytes(rsp.into_body()).await?[..]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_requests_that_were_sent() -> Result<(), Error> {
+        const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+        // The server answers the first request, then reads the second one in full and
+        // drops the connection before responding.
+        let (client, mut server) = chan();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let _ = server.read(&mut buf).await;
+            server.write_all(RESPONSE).await.unwrap();
+            let _ = server.read(&mut buf).await;
+            server.close();
+        });
+        // A retry would get an answer on a fresh connection.
+        let (fresh, mut second) = chan();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let _ = second.read(&mut buf).await;
+            let _ = second.write_all(RESPONSE).await;
+        });
+        let connections = Arc::new(std::sync::Mutex::new(vec![fresh, client]));
+        let client = MinimalClient::with(HeaderValue::from_static("localhost:9001"), {
+            let connections = connections.clone();
+            Box::new(move || {
+                let stream = connections.lock().unwrap().pop();
+                Box::pin(async move { stream.ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused)) })
+            })
+        });
+
+        let req = Request::get("/2018-06-01/runtime/invocation/next").body(Body::empty())?;
+        client.call(req).await?;
+        let req = Request::post("/2018-06-01/runtime/invocation/1/response").body(Body::from("{}"))?;
+        assert!(client.call(req).await.is_err());
+        assert_eq!(1, connections.lock().unwrap().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closes_connections_the_runtime_api_closes() -> Result<(), Error> {
+        let (body, keep_alive) = respond(
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok",
+            true,
+        )
+        .await?;
+        assert_eq!(&b"ok"[..], &body[..]);
+        assert!(!keep_alive);
+        Ok(())
+    }
+}
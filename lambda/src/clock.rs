@@ -0,0 +1,289 @@
+//! Clocks that the deadline helpers of [`Context`] read the time from.
+//!
+//! The deadline of an invocation is wall-clock time, in Unix milliseconds. By default,
+//! the runtime reads the current time from a [`TokioClock`], which follows Tokio's clock:
+//! when a test calls [`tokio::time::pause`], the time only moves with [`tokio::time::advance`],
+//! or when every task is waiting on a timer, so deadline-dependent logic can be tested
+//! deterministically.
+//!
+//! ```
+//! use lamedh_runtime::Context;
+//! use std::time::Duration;
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() {
+//!     tokio::time::pause();
+//!     let ctx = Context::builder().timeout(Duration::from_secs(10)).build();
+//!
+//!     tokio::time::advance(Duration::from_secs(4)).await;
+//!     assert_eq!(Duration::from_secs(6), ctx.remaining_time());
+//! }
+//! ```
+//!
+//! A [`ManualClock`] can be set on a context with [`ContextBuilder::clock`] instead, to
+//! control the time outside of a Tokio runtime.
+//!
+//! [`Context`]: ../struct.Context.html
+//! [`ContextBuilder::clock`]: ../struct.ContextBuilder.html#method.clock
+//! [`TokioClock`]: struct.TokioClock.html
+//! [`ManualClock`]: struct.ManualClock.html
+//! [`tokio::time::pause`]: https://docs.rs/tokio/1/tokio/time/fn.pause.html
+//! [`tokio::time::advance`]: https://docs.rs/tokio/1/tokio/time/fn.advance.html
+use std::{
+    cell::Cell,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A source of wall-clock time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// A clock that follows Tokio's clock, so it stops when time is paused in tests.
+///
+/// While Tokio's clock runs in real time, this is the system time, read on every call, so
+/// the clock follows the jumps of the wall clock when the execution environment is frozen
+/// and thawed, or restored from a snapshot. Once Tokio's time is paused or advanced, the
+/// clock moves with `tokio::time::Instant` from the last system time it read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> SystemTime {
+        // Tokio's time can only be paused on a current-thread runtime, so each thread
+        // keeps its own anchor, and tests on other runtimes don't move it.
+        thread_local!(static ANCHOR: Cell<Option<(SystemTime, Instant)>> = const { Cell::new(None) });
+        let tokio_now = tokio::time::Instant::now().into_std();
+        let std_now = Instant::now();
+        ANCHOR.with(|anchor| anchored_now(anchor, SystemTime::now(), tokio_now, std_now))
+    }
+}
+
+/// How far Tokio's clock can be from the monotonic clock before it's considered paused.
+/// Both are read one after the other, so they differ by a few nanoseconds otherwise.
+const PAUSED_DRIFT: Duration = Duration::from_millis(1);
+
+/// Returns `wall` while Tokio's clock, read as `tokio_now`, follows the monotonic clock,
+/// and records both as the anchor of the clock. When Tokio's time is paused or advanced,
+/// returns the wall-clock time of the anchor plus the time that Tokio moved since then.
+fn anchored_now(
+    anchor: &Cell<Option<(SystemTime, Instant)>>,
+    wall: SystemTime,
+    tokio_now: Instant,
+    std_now: Instant,
+) -> SystemTime {
+    let drift = match tokio_now.checked_duration_since(std_now) {
+        Some(ahead) => ahead,
+        None => std_now.duration_since(tokio_now),
+    };
+    match anchor.get() {
+        Some((anchor_wall, anchor_instant)) if drift > PAUSED_DRIFT => {
+            match tokio_now.checked_duration_since(anchor_instant) {
+                Some(elapsed) => anchor_wall + elapsed,
+                None => anchor_wall - anchor_instant.duration_since(tokio_now),
+            }
+        }
+        _ => {
+            anchor.set(Some((wall, tokio_now)));
+            wall
+        }
+    }
+}
+
+/// A clock that only moves when it's told to.
+///
+/// Clones share the same time, so a test can keep a clone to advance the clock of a
+/// context it built.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    /// Creates a clock stopped at `now`.
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.lock() += duration;
+    }
+
+    /// Sets the time of the clock.
+    pub fn set(&self, now: SystemTime) {
+        *self.lock() = now;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SystemTime> {
+        self.now.lock().expect("Lock was poisoned when reading a manual clock")
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.lock()
+    }
+}
+
+/// The clock of a context. Clocks don't take part in the comparison of contexts.
+///
+/// The default clock doesn't allocate, since a context is created for every invocation.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum ContextClock {
+    #[default]
+    Tokio,
+    Custom(Arc<dyn Clock>),
+}
+
+impl ContextClock {
+    pub(crate) fn new(clock: impl Clock + 'static) -> Self {
+        ContextClock::Custom(Arc::new(clock))
+    }
+
+    pub(crate) fn now(&self) -> SystemTime {
+        match self {
+            ContextClock::Tokio => TokioClock.now(),
+            ContextClock::Custom(clock) => clock.now(),
+        }
+    }
+}
+
+impl PartialEq for ContextClock {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// Converts days since the epoch into a (year, month, day) date of the proleptic Gregorian
+/// calendar, with Howard Hinnant's `civil_from_days` algorithm.
+#[doc(hidden)]
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test]
+    async fn tokio_clock_follows_paused_time() {
+        tokio::time::pause();
+        let start = TokioClock.now();
+        tokio::time::advance(Duration::from_secs(90)).await;
+        assert_eq!(Duration::from_secs(90), TokioClock.now().duration_since(start).unwrap());
+
+        // Timers fire on the next millisecond when time is paused.
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(120, TokioClock.now().duration_since(start).unwrap().as_secs());
+    }
+
+    #[test]
+    fn tokio_clock_follows_wall_clock_jumps() {
+        let anchor = Cell::new(None);
+        let instant = Instant::now();
+        let wall = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert_eq!(wall, anchored_now(&anchor, wall, instant, instant));
+
+        // A freeze of the execution environment moves the wall clock, but not the
+        // monotonic clock.
+        let thawed = wall + Duration::from_secs(3_600);
+        assert_eq!(thawed, anchored_now(&anchor, thawed, instant, instant));
+    }
+
+    #[test]
+    fn tokio_clock_moves_from_the_anchor_when_paused() {
+        let anchor = Cell::new(None);
+        let instant = Instant::now();
+        let wall = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        anchored_now(&anchor, wall, instant, instant);
+
+        let advanced = instant + Duration::from_secs(90);
+        let later = wall + Duration::from_secs(3_600);
+        assert_eq!(
+            wall + Duration::from_secs(90),
+            anchored_now(&anchor, later, advanced, instant + Duration::from_millis(5))
+        );
+    }
+
+    #[tokio::test]
+    async fn remaining_time_follows_paused_time() {
+        tokio::time::pause();
+        let ctx = Context::builder().timeout(Duration::from_secs(10)).build();
+        assert_eq!(Duration::from_secs(10), ctx.remaining_time());
+
+        tokio::time::advance(Duration::from_millis(9_500)).await;
+        assert_eq!(Duration::from_millis(500), ctx.remaining_time());
+        assert!(!ctx.is_near_deadline(Duration::ZERO));
+        assert!(ctx.is_near_deadline(Duration::from_secs(1)));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(Duration::ZERO, ctx.remaining_time());
+        assert!(ctx.is_near_deadline(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn soft_timeouts_leave_a_margin_before_the_deadline() {
+        tokio::time::pause();
+        let ctx = Context::builder().timeout(Duration::from_secs(10)).build();
+        let start = tokio::time::Instant::now();
+
+        let fast = ctx
+            .soft_timeout(Duration::from_secs(1), tokio::time::sleep(Duration::from_secs(5)))
+            .await;
+        assert!(fast.is_ok());
+
+        let slow = ctx
+            .soft_timeout(Duration::from_secs(1), tokio::time::sleep(Duration::from_secs(60)))
+            .await;
+        assert!(slow.is_err());
+        assert_eq!(9, start.elapsed().as_secs());
+    }
+
+    #[test]
+    fn converts_days_to_civil_dates() {
+        assert_eq!((1970, 1, 1), civil_from_days(0));
+        assert_eq!((2015, 4, 9), civil_from_days(16_534));
+        assert_eq!((2020, 2, 29), civil_from_days(18_321));
+        assert_eq!((1969, 12, 31), civil_from_days(-1));
+    }
+
+    #[test]
+    fn manual_clocks_drive_contexts() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let ctx = Context::builder()
+            .clock(clock.clone())
+            .timeout(Duration::from_secs(3))
+            .build();
+        assert_eq!(1_600_000_003_000, ctx.deadline);
+        assert_eq!(Duration::from_secs(3), ctx.remaining_time());
+
+        clock.advance(Duration::from_millis(2_250));
+        assert_eq!(Duration::from_millis(750), ctx.remaining_time());
+
+        clock.set(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(Duration::ZERO, ctx.remaining_time());
+    }
+}
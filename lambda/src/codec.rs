@@ -0,0 +1,404 @@
+//! Codecs that decode events and encode responses.
+//!
+//! Events are decoded, and responses encoded, as JSON with [`serde_json`] by default.
+//! A different [`Codec`] can be installed when the function starts, and it's then used
+//! for every event and response type that goes through serde, so handlers don't change:
+//!
+//! ```no_run
+//! use lamedh_runtime::{codec::{self, PassthroughCodec}, handler_fn, Context, Error};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     codec::install(PassthroughCodec);
+//!     lamedh_runtime::run(handler_fn(func)).await
+//! }
+//!
+//! async fn func(event: String, _: Context) -> Result<String, Error> {
+//!     Ok(event.to_uppercase())
+//! }
+//! ```
+//!
+//! The codec can be chosen at run time, since codecs are trait objects. The codecs
+//! available are:
+//!
+//! - [`JsonCodec`], with [`serde_json`], which is the default.
+//! - [`SimdJsonCodec`], with [`simd-json`], when the `simd-json` feature is enabled.
+//!   It's faster than `serde_json` on large payloads.
+//! - [`PassthroughCodec`], which passes the bytes of the events and responses as is.
+//!
+//! Handlers that take or return a [`Payload`] skip the codec.
+//!
+//! [`Codec`]: trait.Codec.html
+//! [`JsonCodec`]: struct.JsonCodec.html
+//! [`PassthroughCodec`]: struct.PassthroughCodec.html
+//! [`Payload`]: ../struct.Payload.html
+//! [`serde_json`]: https://docs.rs/serde_json
+//! [`simd-json`]: https://docs.rs/simd-json
+#![cfg_attr(feature = "simd-json", doc = "[`SimdJsonCodec`]: struct.SimdJsonCodec.html")]
+#![cfg_attr(
+    not(feature = "simd-json"),
+    doc = "[`SimdJsonCodec`]: https://docs.rs/lamedh_runtime/*/lamedh_runtime/codec/struct.SimdJsonCodec.html"
+)]
+use crate::{Error, Payload};
+use serde::{
+    de::{value::BytesDeserializer, DeserializeOwned},
+    ser::{self, Impossible},
+    Serialize,
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// The callback that a [`Codec`] passes its deserializer to, in [`Codec::decode`].
+///
+/// [`Codec`]: trait.Codec.html
+/// [`Codec::decode`]: trait.Codec.html#tymethod.decode
+pub type Visit<'a> = dyn FnMut(&mut dyn erased_serde::Deserializer<'_>) -> Result<(), erased_serde::Error> + 'a;
+
+/// A format that events are decoded from, and responses are encoded to.
+///
+/// Codecs are trait objects, so they work with [`erased_serde`] instead of serde's
+/// generic traits.
+///
+/// [`erased_serde`]: https://docs.rs/erased-serde
+pub trait Codec: fmt::Debug + Send + Sync {
+    /// Decodes an event, by passing a deserializer for `payload` to `visit`.
+    fn decode(&self, payload: Payload, visit: &mut Visit<'_>) -> Result<(), Error>;
+
+    /// Encodes a response.
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Payload, Error>;
+
+    /// Whether this is the [`JsonCodec`], which the runtime calls `serde_json` for
+    /// directly, without going through `erased_serde`.
+    ///
+    /// [`JsonCodec`]: struct.JsonCodec.html
+    #[doc(hidden)]
+    fn is_json(&self) -> bool {
+        false
+    }
+}
+
+/// Decodes an event of type `T` with `codec`.
+pub(crate) fn decode<T: DeserializeOwned>(codec: &dyn Codec, payload: Payload) -> Result<T, Error> {
+    if codec.is_json() {
+        let mut deserializer = serde_json::Deserializer::from_slice(&payload);
+        let value = serde_path_to_error::deserialize(&mut deserializer)?;
+        deserializer.end()?;
+        return Ok(value);
+    }
+    let mut value = None;
+    codec.decode(payload, &mut |deserializer| {
+        value = Some(erased_serde::deserialize(deserializer)?);
+        Ok(())
+    })?;
+    value.ok_or_else(|| format!("{:?} didn't decode the event", codec).into())
+}
+
+/// Encodes a response with `codec`.
+pub(crate) fn encode<T: Serialize>(codec: &dyn Codec, value: &T) -> Result<Payload, Error> {
+    if codec.is_json() {
+        return Ok(Payload::new(serde_json::to_vec(value)?));
+    }
+    codec.encode(value)
+}
+
+/// A codec for JSON with [`serde_json`]. This is the default codec.
+///
+/// Decoding errors report the path of the field that failed.
+///
+/// [`serde_json`]: https://docs.rs/serde_json
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn decode(&self, payload: Payload, visit: &mut Visit<'_>) -> Result<(), Error> {
+        let mut deserializer = serde_json::Deserializer::from_slice(&payload);
+        let mut track = serde_path_to_error::Track::new();
+        let tracked = serde_path_to_error::Deserializer::new(&mut deserializer, &mut track);
+        visit(&mut <dyn erased_serde::Deserializer<'_>>::erase(tracked))
+            .map_err(|e| serde_path_to_error::Error::new(track.path(), e))?;
+        Ok(deserializer.end()?)
+    }
+
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Payload, Error> {
+        Ok(Payload::new(serde_json::to_vec(value)?))
+    }
+
+    fn is_json(&self) -> bool {
+        true
+    }
+}
+
+/// A codec for JSON with [`simd-json`], which uses SIMD instructions to parse
+/// large payloads faster than `serde_json` does.
+///
+/// [`simd-json`]: https://docs.rs/simd-json
+#[cfg(feature = "simd-json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimdJsonCodec;
+
+#[cfg(feature = "simd-json")]
+impl Codec for SimdJsonCodec {
+    fn decode(&self, payload: Payload, visit: &mut Visit<'_>) -> Result<(), Error> {
+        // simd-json parses in place. This doesn't copy the body when the payload
+        // is its only owner.
+        let mut body = Vec::from(payload.into_bytes());
+        let mut deserializer = simd_json::Deserializer::from_slice(&mut body)?;
+        visit(&mut <dyn erased_serde::Deserializer<'_>>::erase(&mut deserializer))?;
+        Ok(())
+    }
+
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Payload, Error> {
+        Ok(Payload::new(simd_json::serde::to_vec(value)?))
+    }
+}
+
+/// A codec that passes the bytes of events and responses as is.
+///
+/// Events can be decoded into a `String`, when they are valid UTF-8, or into any type
+/// that deserializes from bytes, like `serde_bytes::ByteBuf`. Responses can be strings
+/// or bytes, and `None` or `()` send an empty response. Other types can't be encoded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PassthroughCodec;
+
+impl Codec for PassthroughCodec {
+    fn decode(&self, payload: Payload, visit: &mut Visit<'_>) -> Result<(), Error> {
+        let deserializer = BytesDeserializer::<'_, serde::de::value::Error>::new(&payload);
+        visit(&mut <dyn erased_serde::Deserializer<'_>>::erase(deserializer))?;
+        Ok(())
+    }
+
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Payload, Error> {
+        Ok(value.serialize(PassthroughSerializer)?)
+    }
+}
+
+/// Serializes strings and bytes into a payload as is.
+struct PassthroughSerializer;
+
+macro_rules! unsupported {
+    ($($method:ident($($arg:ty),*) -> $ok:ty;)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<$ok, Self::Error> {
+                Err(ser::Error::custom(concat!(
+                    "The passthrough codec only encodes strings and bytes, and can't call ",
+                    stringify!($method)
+                )))
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for PassthroughSerializer {
+    type Ok = Payload;
+    type Error = serde::de::value::Error;
+    type SerializeSeq = Impossible<Payload, Self::Error>;
+    type SerializeTuple = Impossible<Payload, Self::Error>;
+    type SerializeTupleStruct = Impossible<Payload, Self::Error>;
+    type SerializeTupleVariant = Impossible<Payload, Self::Error>;
+    type SerializeMap = Impossible<Payload, Self::Error>;
+    type SerializeStruct = Impossible<Payload, Self::Error>;
+    type SerializeStructVariant = Impossible<Payload, Self::Error>;
+
+    fn serialize_str(self, v: &str) -> Result<Payload, Self::Error> {
+        Ok(Payload::new(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Payload, Self::Error> {
+        Ok(Payload::new(v.to_vec()))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Payload, Self::Error> {
+        Ok(Payload::new(v.to_string()))
+    }
+
+    fn serialize_none(self) -> Result<Payload, Self::Error> {
+        Ok(Payload::default())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Payload, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Payload, Self::Error> {
+        Ok(Payload::default())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Payload, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Payload, Self::Error> {
+        Err(ser::Error::custom(
+            "The passthrough codec only encodes strings and bytes, and can't call serialize_newtype_variant",
+        ))
+    }
+
+    unsupported! {
+        serialize_bool(bool) -> Payload;
+        serialize_i8(i8) -> Payload;
+        serialize_i16(i16) -> Payload;
+        serialize_i32(i32) -> Payload;
+        serialize_i64(i64) -> Payload;
+        serialize_u8(u8) -> Payload;
+        serialize_u16(u16) -> Payload;
+        serialize_u32(u32) -> Payload;
+        serialize_u64(u64) -> Payload;
+        serialize_f32(f32) -> Payload;
+        serialize_f64(f64) -> Payload;
+        serialize_unit_struct(&'static str) -> Payload;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Payload;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+static INSTALLED: Mutex<Option<Arc<dyn Codec>>> = Mutex::new(None);
+
+/// Installs the codec that the runtime decodes events and encodes responses with,
+/// instead of [`JsonCodec`].
+///
+/// Install the codec before the runtime starts, since the runtime reads it once.
+///
+/// [`JsonCodec`]: struct.JsonCodec.html
+pub fn install(codec: impl Codec + 'static) {
+    *INSTALLED.lock().expect("Lock was poisoned when installing a codec") = Some(Arc::new(codec));
+}
+
+/// Returns the installed codec, or [`JsonCodec`].
+///
+/// [`JsonCodec`]: struct.JsonCodec.html
+pub(crate) fn installed() -> Arc<dyn Codec> {
+    INSTALLED
+        .lock()
+        .expect("Lock was poisoned when reading the installed codec")
+        .clone()
+        .unwrap_or_else(|| Arc::new(JsonCodec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Event {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    /// The `JsonCodec` through `erased_serde`, without the fast path of the runtime.
+    #[derive(Debug)]
+    struct ErasedJsonCodec;
+
+    impl Codec for ErasedJsonCodec {
+        fn decode(&self, payload: Payload, visit: &mut Visit<'_>) -> Result<(), Error> {
+            JsonCodec.decode(payload, visit)
+        }
+
+        fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Payload, Error> {
+            JsonCodec.encode(value)
+        }
+    }
+
+    fn codecs() -> Vec<Box<dyn Codec>> {
+        vec![
+            Box::new(JsonCodec),
+            Box::new(ErasedJsonCodec),
+            #[cfg(feature = "simd-json")]
+            Box::new(SimdJsonCodec),
+        ]
+    }
+
+    #[test]
+    fn json_codecs_round_trip() -> Result<(), Error> {
+        for codec in codecs() {
+            let event: Event = decode(&*codec, Payload::from(r#"{"name": "ferris", "tags": ["crab"]}"#))?;
+            assert_eq!(
+                Event {
+                    name: "ferris".to_owned(),
+                    tags: vec!["crab".to_owned()]
+                },
+                event,
+                "{:?}",
+                codec
+            );
+
+            let encoded = encode(&*codec, &event)?;
+            let value: Value = serde_json::from_slice(&encoded)?;
+            assert_eq!(json!({ "name": "ferris", "tags": ["crab"] }), value, "{:?}", codec);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json_codecs_reject_invalid_events() {
+        for codec in codecs() {
+            assert!(decode::<Event>(&*codec, Payload::from(r#"{"name": "ferris"}"#)).is_err());
+            assert!(decode::<Event>(&*codec, Payload::from(r#"{"name": "ferris", "tags": []} {}"#)).is_err());
+        }
+    }
+
+    #[test]
+    fn json_codec_errors_report_the_path() {
+        let codecs: [&dyn Codec; 2] = [&JsonCodec, &ErasedJsonCodec];
+        for codec in codecs {
+            let err = decode::<Event>(codec, Payload::from(r#"{"name": "ferris", "tags": [1]}"#))
+                .err()
+                .unwrap();
+            assert!(err.to_string().starts_with("tags[0]"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn passthrough_codec_passes_bytes_as_is() -> Result<(), Error> {
+        let event: String = decode(&PassthroughCodec, Payload::from("not json"))?;
+        assert_eq!("not json", event);
+        assert!(decode::<String>(&PassthroughCodec, Payload::from(&b"\xff"[..])).is_err());
+
+        assert_eq!(Payload::from("ok"), encode(&PassthroughCodec, &"ok")?);
+        assert_eq!(Payload::from("ok"), encode(&PassthroughCodec, &Some("ok".to_owned()))?);
+        assert_eq!(Payload::default(), encode(&PassthroughCodec, &())?);
+        assert!(encode(&PassthroughCodec, &json!({ "a": 1 })).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "simulated")]
+    #[tokio::test]
+    async fn the_runtime_uses_the_codec() -> Result<(), Error> {
+        use crate::{
+            handler_fn,
+            testing::{MockEvent, MockRuntime},
+            Context,
+        };
+
+        let mut runtime = MockRuntime::new();
+        runtime.codec(PassthroughCodec);
+        runtime.push_event(MockEvent::from_slice(&b"hello"[..]));
+        runtime
+            .run(handler_fn(|event: String, _: Context| async move {
+                Ok::<_, Error>(event.to_uppercase())
+            }))
+            .await?;
+
+        assert_eq!(b"HELLO", &runtime.responses()[0].body[..]);
+        Ok(())
+    }
+}
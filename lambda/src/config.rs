@@ -0,0 +1,428 @@
+use std::{
+    collections::HashMap,
+    env::{self, VarError},
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+pub(crate) static DEFAULT_LOG_GROUP: &str = "/aws/lambda/Functions";
+pub(crate) static DEFAULT_LOG_STREAM: &str = "$LATEST";
+
+/// Configuration derived from environment variables.
+///
+/// See the [variables that Lambda sets](https://docs.aws.amazon.com/lambda/latest/dg/configuration-envvars.html#configuration-envvars-runtime)
+/// in the execution environment.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    /// The host and port of the [runtime API](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html).
+    pub endpoint: String,
+    /// The name of the function.
+    pub function_name: String,
+    /// The amount of memory available to the function in MB.
+    pub memory: i32,
+    /// The version of the function being executed.
+    pub version: String,
+    /// The name of the Amazon CloudWatch Logs stream for the function.
+    pub log_stream: String,
+    /// The name of the Amazon CloudWatch Logs group for the function.
+    pub log_group: String,
+    /// The AWS Region where the function runs, from `AWS_REGION`.
+    pub region: Option<String>,
+    /// The handler location configured on the function, from `_HANDLER`.
+    pub handler: Option<String>,
+    /// The path to the function code, from `LAMBDA_TASK_ROOT`.
+    pub task_root: Option<PathBuf>,
+    /// The path to the runtime libraries, from `LAMBDA_RUNTIME_DIR`.
+    pub runtime_dir: Option<PathBuf>,
+    /// How the execution environment was initialized, from `AWS_LAMBDA_INITIALIZATION_TYPE`.
+    /// It's on-demand unless the variable says otherwise.
+    pub initialization_type: InitializationType,
+    /// The time zone of the environment, from `TZ`. Lambda sets it to `:UTC`.
+    pub tz: Option<String>,
+    /// The timeout of the function, from `AWS_LAMBDA_FUNCTION_TIMEOUT` in seconds.
+    /// Lambda itself doesn't set it, but emulators do.
+    pub timeout: Option<Duration>,
+}
+
+impl Config {
+    /// Attempts to read configuration from environment variables.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|name| match env::var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(VarError::NotPresent) => Ok(None),
+            Err(VarError::NotUnicode(value)) => Err(ConfigError::Invalid {
+                name,
+                value: value.to_string_lossy().into_owned(),
+                reason: "it isn't valid unicode".to_owned(),
+            }),
+        })
+    }
+
+    /// Reads configuration from a map of environment variables, like `from_env` does.
+    /// This is meant for tests, which shouldn't change the environment of the process.
+    ///
+    /// # Example
+    /// ```
+    /// use lamedh_runtime::{Config, InitializationType};
+    ///
+    /// let config = Config::from_map(vec![
+    ///     ("AWS_LAMBDA_RUNTIME_API", "127.0.0.1:9001"),
+    ///     ("AWS_LAMBDA_FUNCTION_NAME", "my-function"),
+    ///     ("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "512"),
+    ///     ("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST"),
+    ///     ("AWS_LAMBDA_INITIALIZATION_TYPE", "provisioned-concurrency"),
+    /// ])?;
+    /// assert_eq!(512, config.memory);
+    /// assert_eq!(InitializationType::ProvisionedConcurrency, config.initialization_type);
+    /// # Ok::<(), lamedh_runtime::ConfigError>(())
+    /// ```
+    pub fn from_map<K, V>(vars: impl IntoIterator<Item = (K, V)>) -> Result<Self, ConfigError>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let vars: HashMap<String, String> = vars.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        Self::from_lookup(|name| Ok(vars.get(name).cloned()))
+    }
+
+    /// Returns a builder for a `Config`, with the settings of a function called
+    /// `mock-function` in a Lambda execution environment.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    fn from_lookup<F>(lookup: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&'static str) -> Result<Option<String>, ConfigError>,
+    {
+        let required = |name| lookup(name)?.ok_or(ConfigError::Missing { name });
+
+        let memory_var = "AWS_LAMBDA_FUNCTION_MEMORY_SIZE";
+        let timeout_var = "AWS_LAMBDA_FUNCTION_TIMEOUT";
+        Ok(Config {
+            endpoint: required("AWS_LAMBDA_RUNTIME_API")?,
+            function_name: required("AWS_LAMBDA_FUNCTION_NAME")?,
+            memory: parse(memory_var, required(memory_var)?)?,
+            version: required("AWS_LAMBDA_FUNCTION_VERSION")?,
+            log_stream: lookup("AWS_LAMBDA_LOG_STREAM_NAME")?.unwrap_or_else(|| DEFAULT_LOG_STREAM.to_owned()),
+            log_group: lookup("AWS_LAMBDA_LOG_GROUP_NAME")?.unwrap_or_else(|| DEFAULT_LOG_GROUP.to_owned()),
+            region: lookup("AWS_REGION")?,
+            handler: lookup("_HANDLER")?,
+            task_root: lookup("LAMBDA_TASK_ROOT")?.map(PathBuf::from),
+            runtime_dir: lookup("LAMBDA_RUNTIME_DIR")?.map(PathBuf::from),
+            initialization_type: lookup("AWS_LAMBDA_INITIALIZATION_TYPE")?
+                .map(|value| InitializationType::from(value.as_str()))
+                .unwrap_or_default(),
+            tz: lookup("TZ")?,
+            timeout: match lookup(timeout_var)? {
+                Some(value) => Some(Duration::from_secs(parse(timeout_var, value)?)),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Parses the value of a variable, or returns an error naming it.
+fn parse<T>(name: &'static str, value: String) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::Invalid {
+        name,
+        reason: e.to_string(),
+        value,
+    })
+}
+
+/// How a Lambda execution environment was initialized.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum InitializationType {
+    /// The environment was started for an invocation.
+    #[default]
+    OnDemand,
+    /// The environment was started ahead of invocations, for provisioned concurrency.
+    ProvisionedConcurrency,
+    /// The environment was restored from a SnapStart snapshot.
+    SnapStart,
+    /// An initialization type that this version of the runtime doesn't know about.
+    Other(String),
+}
+
+impl From<&str> for InitializationType {
+    fn from(s: &str) -> Self {
+        match s {
+            "on-demand" => InitializationType::OnDemand,
+            "provisioned-concurrency" => InitializationType::ProvisionedConcurrency,
+            "snap-start" => InitializationType::SnapStart,
+            other => InitializationType::Other(other.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for InitializationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitializationType::OnDemand => f.write_str("on-demand"),
+            InitializationType::ProvisionedConcurrency => f.write_str("provisioned-concurrency"),
+            InitializationType::SnapStart => f.write_str("snap-start"),
+            InitializationType::Other(other) => f.write_str(other),
+        }
+    }
+}
+
+/// An error in the configuration of the environment, naming the variable at fault.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// A required variable isn't set.
+    Missing {
+        /// The name of the variable.
+        name: &'static str,
+    },
+    /// A variable is set to a value that can't be used.
+    Invalid {
+        /// The name of the variable.
+        name: &'static str,
+        /// The value of the variable.
+        value: String,
+        /// Why the value can't be used.
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing { name } => write!(f, "Missing environment variable {}", name),
+            ConfigError::Invalid { name, value, reason } => {
+                write!(
+                    f,
+                    "Invalid value for environment variable {}: {:?}, {}",
+                    name, value, reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Builds a [`Config`] explicitly, for tests and for runtimes outside of Lambda.
+///
+/// The defaults are the settings of a function called `mock-function`, with 128 MB of
+/// memory and a timeout of three seconds, as Lambda sets them in `us-east-1`.
+///
+/// [`Config`]: struct.Config.html
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder {
+            config: Config {
+                endpoint: "localhost:9001".to_owned(),
+                function_name: "mock-function".to_owned(),
+                memory: 128,
+                version: "$LATEST".to_owned(),
+                log_stream: DEFAULT_LOG_STREAM.to_owned(),
+                log_group: DEFAULT_LOG_GROUP.to_owned(),
+                region: Some("us-east-1".to_owned()),
+                handler: Some("bootstrap".to_owned()),
+                task_root: Some(PathBuf::from("/var/task")),
+                runtime_dir: Some(PathBuf::from("/var/runtime")),
+                initialization_type: InitializationType::OnDemand,
+                tz: Some(":UTC".to_owned()),
+                timeout: Some(Duration::from_secs(3)),
+            },
+        }
+    }
+}
+
+impl ConfigBuilder {
+    /// Sets the host and port of the Runtime API.
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.endpoint = endpoint.into();
+        self
+    }
+
+    /// Sets the name of the function.
+    pub fn function_name(mut self, function_name: impl Into<String>) -> Self {
+        self.config.function_name = function_name.into();
+        self
+    }
+
+    /// Sets the amount of memory available to the function in MB.
+    pub fn memory(mut self, memory: i32) -> Self {
+        self.config.memory = memory;
+        self
+    }
+
+    /// Sets the version of the function.
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.config.version = version.into();
+        self
+    }
+
+    /// Sets the CloudWatch Logs stream of the function.
+    pub fn log_stream(mut self, log_stream: impl Into<String>) -> Self {
+        self.config.log_stream = log_stream.into();
+        self
+    }
+
+    /// Sets the CloudWatch Logs group of the function.
+    pub fn log_group(mut self, log_group: impl Into<String>) -> Self {
+        self.config.log_group = log_group.into();
+        self
+    }
+
+    /// Sets the AWS Region where the function runs.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.config.region = Some(region.into());
+        self
+    }
+
+    /// Sets the handler location configured on the function.
+    pub fn handler(mut self, handler: impl Into<String>) -> Self {
+        self.config.handler = Some(handler.into());
+        self
+    }
+
+    /// Sets the path to the function code.
+    pub fn task_root(mut self, task_root: impl Into<PathBuf>) -> Self {
+        self.config.task_root = Some(task_root.into());
+        self
+    }
+
+    /// Sets the path to the runtime libraries.
+    pub fn runtime_dir(mut self, runtime_dir: impl Into<PathBuf>) -> Self {
+        self.config.runtime_dir = Some(runtime_dir.into());
+        self
+    }
+
+    /// Sets how the execution environment was initialized.
+    pub fn initialization_type(mut self, initialization_type: InitializationType) -> Self {
+        self.config.initialization_type = initialization_type;
+        self
+    }
+
+    /// Sets the time zone of the environment.
+    pub fn tz(mut self, tz: impl Into<String>) -> Self {
+        self.config.tz = Some(tz.into());
+        self
+    }
+
+    /// Sets the timeout of the function.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Builds the `Config`.
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn required() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("AWS_LAMBDA_RUNTIME_API", "127.0.0.1:9001"),
+            ("AWS_LAMBDA_FUNCTION_NAME", "my-function"),
+            ("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "1024"),
+            ("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST"),
+        ]
+    }
+
+    #[test]
+    fn reads_every_variable() {
+        let mut vars = required();
+        vars.extend(vec![
+            ("AWS_LAMBDA_LOG_STREAM_NAME", "2021/01/01/[$LATEST]abcdef"),
+            ("AWS_LAMBDA_LOG_GROUP_NAME", "/aws/lambda/my-function"),
+            ("AWS_REGION", "eu-west-1"),
+            ("_HANDLER", "bootstrap"),
+            ("LAMBDA_TASK_ROOT", "/var/task"),
+            ("LAMBDA_RUNTIME_DIR", "/var/runtime"),
+            ("AWS_LAMBDA_INITIALIZATION_TYPE", "snap-start"),
+            ("TZ", ":UTC"),
+            ("AWS_LAMBDA_FUNCTION_TIMEOUT", "30"),
+        ]);
+        let config = Config::from_map(vars).unwrap();
+
+        let expected = Config::builder()
+            .endpoint("127.0.0.1:9001")
+            .function_name("my-function")
+            .memory(1024)
+            .log_stream("2021/01/01/[$LATEST]abcdef")
+            .log_group("/aws/lambda/my-function")
+            .region("eu-west-1")
+            .initialization_type(InitializationType::SnapStart)
+            .timeout(Duration::from_secs(30))
+            .build();
+        assert_eq!(expected, config);
+    }
+
+    #[test]
+    fn defaults_optional_variables() {
+        let config = Config::from_map(required()).unwrap();
+        assert_eq!(DEFAULT_LOG_STREAM, config.log_stream);
+        assert_eq!(DEFAULT_LOG_GROUP, config.log_group);
+        assert_eq!(None, config.region);
+        assert_eq!(InitializationType::OnDemand, config.initialization_type);
+        assert_eq!(None, config.timeout);
+    }
+
+    #[test]
+    fn names_missing_variables() {
+        let vars = required()
+            .into_iter()
+            .filter(|(name, _)| *name != "AWS_LAMBDA_FUNCTION_NAME");
+        let err = Config::from_map(vars).unwrap_err();
+        assert_eq!(
+            ConfigError::Missing {
+                name: "AWS_LAMBDA_FUNCTION_NAME"
+            },
+            err
+        );
+        assert_eq!("Missing environment variable AWS_LAMBDA_FUNCTION_NAME", err.to_string());
+    }
+
+    #[test]
+    fn names_invalid_variables() {
+        let mut vars = required();
+        vars.push(("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "lots"));
+        match Config::from_map(vars).unwrap_err() {
+            ConfigError::Invalid { name, value, .. } => {
+                assert_eq!("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", name);
+                assert_eq!("lots", value);
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+
+        let mut vars = required();
+        vars.push(("AWS_LAMBDA_FUNCTION_TIMEOUT", "-1"));
+        let err = Config::from_map(vars).unwrap_err();
+        assert!(err.to_string().contains("AWS_LAMBDA_FUNCTION_TIMEOUT"), "{}", err);
+    }
+
+    #[test]
+    fn parses_initialization_types() {
+        for (value, expected) in &[
+            ("on-demand", InitializationType::OnDemand),
+            ("provisioned-concurrency", InitializationType::ProvisionedConcurrency),
+            ("snap-start", InitializationType::SnapStart),
+            ("lambda-managed", InitializationType::Other("lambda-managed".to_owned())),
+        ] {
+            let parsed = InitializationType::from(*value);
+            assert_eq!(*expected, parsed);
+            assert_eq!(*value, parsed.to_string());
+        }
+    }
+}
@@ -20,6 +20,9 @@
 //! accept an argument of type `A` which implements [`serde::Deserialize`], a [`lambda::Context`] and
 //! return a `Result<B, E>`, where `B` implements [`serde::Serializable`]. `E` is
 //! any type that implements `Into<Box<dyn std::error::Error + Send + Sync + 'static>>`.
+//! Events and responses are JSON by default, and the [`codec`] module has other formats.
+//! Handlers that take or return a [`Payload`] get the raw body of the event, or send
+//! pre-serialized bytes, without going through serde.
 //!
 //! ```no_run
 //! use lamedh_runtime::{lambda, Context, Error};
@@ -32,72 +35,200 @@
 //! }
 //! ```
 //!
+//! The context argument can be left out, and a function that never fails can return
+//! a plain `B` instead of a `Result`:
+//!
+//! ```no_run
+//! use lamedh_runtime::lambda;
+//! use serde_json::Value;
+//!
+//! #[lambda]
+//! #[tokio::main]
+//! async fn main(event: Value) -> Value {
+//!     event
+//! }
+//! ```
+//!
+//! The `#[lambda]` attribute also accepts a synchronous main function, for handlers
+//! that never await anything. The function then runs on a blocking thread through
+//! [`run_sync`], and no `#[tokio::main]` attribute is needed. `#[lambda(sync)]` makes
+//! this mode explicit, and rejects async functions.
+//!
+//! ```no_run
+//! use lamedh_runtime::{lambda, Context, Error};
+//! use serde_json::Value;
+//!
+//! #[lambda]
+//! fn main(event: Value, _: Context) -> Result<Value, Error> {
+//!     Ok(event)
+//! }
+//! ```
+//!
+//! When an async main function isn't decorated with `#[tokio::main]`, the `#[lambda]`
+//! attribute runs it through [`main`] instead, on a Tokio runtime sized for the vCPUs of the
+//! function rather than for the CPUs of the host. The [`tuning`] module has the details.
+//!
+//! ```no_run
+//! use lamedh_runtime::{lambda, Context, Error};
+//! use serde_json::Value;
+//!
+//! #[lambda]
+//! async fn main(event: Value, _: Context) -> Result<Value, Error> {
+//!     Ok(event)
+//! }
+//! ```
+//!
+//! One-time initialization can be declared with `#[lambda(init = ...)]`. The init function
+//! runs once before the first invocation, and the state it returns is passed to every
+//! invocation as a third argument. Init errors are reported to Lambda through [`run_with_init`].
+//!
+//! ```no_run
+//! use lamedh_runtime::{lambda, Context, Error};
+//! use serde_json::{json, Value};
+//!
+//! struct State {
+//!     greeting: String,
+//! }
+//!
+//! async fn setup() -> Result<State, Error> {
+//!     Ok(State { greeting: "Hello".to_string() })
+//! }
+//!
+//! #[lambda(init = setup)]
+//! #[tokio::main]
+//! async fn main(event: Value, _: Context, state: &State) -> Result<Value, Error> {
+//!     Ok(json!({ "message": format!("{}, {}!", state.greeting, event["name"]) }))
+//! }
+//! ```
+//!
+//! # Local invocations
+//!
+//! A function binary can run a single invocation locally, without Lambda or an emulator.
+//! Start it with `--event <PATH>`, or set the `LAMEDH_EVENT` environment variable to the
+//! path of a JSON event, where `-` reads the event from stdin. The handler runs once with a
+//! synthetic [`Context`], and the response, or the diagnostic of the error, is printed as
+//! JSON on stdout. Handler errors make the process exit with a failure code.
+//!
+//! The context can be adjusted with `--request-id`, `--function-name`, `--invoked-function-arn`,
+//! `--xray-trace-id`, and `--timeout <SECONDS>`.
+//!
+//! ```bash
+//! $ cargo run --example basic -- --event event.json --function-name my-function
+//! $ echo '{"command": "hi"}' | LAMEDH_EVENT=- cargo run --example basic
+//! ```
+//!
+//! # Recording invocations
+//!
+//! Set the `LAMEDH_RECORD` environment variable to a path, or `-` for stdout, to record
+//! a sample of the invocations as JSON lines, with their event, context headers, outcome
+//! and timing. The [`record`] module has the redaction hooks, and replays records against
+//! a handler to compare the outcomes.
+//!
+//! # Fixture tests
+//!
+//! The `#[lambda_test]` attribute generates one test per event fixture for a handler
+//! function. Each test invokes the handler with the fixture, like Lambda would, and compares
+//! the output with the sibling `*.expected.json` snapshot. Handler errors are compared as the
+//! `errorType` and `errorMessage` that the runtime reports. Run the tests with `LAMEDH_BLESS=1`
+//! to write the snapshots.
+//!
+//! ```ignore
+//! use lamedh_runtime::{lambda_test, Context, Error};
+//! use serde_json::Value;
+//!
+//! #[lambda_test("tests/events/*.json")]
+//! async fn handler(event: Value, _: Context) -> Result<Value, Error> {
+//!     Ok(event)
+//! }
+//! ```
+//!
+//! The pattern is relative to the crate root, and it's expanded at compile time. Changes to
+//! existing fixtures trigger a rebuild, but Cargo doesn't watch the directory for new files:
+//! after adding a fixture, `touch` the test file or run `cargo clean -p <crate>` so the tests
+//! are generated again. Snapshots are shared by every handler tested with a fixture, so keep
+//! separate fixture directories for separate handlers.
+//!
 //! [`Handler`]: trait.Handler.html
+//! [`record`]: record/index.html
+//! [`codec`]: codec/index.html
+//! [`Payload`]: struct.Payload.html
+//! [`Context`]: struct.Context.html
+//! [`run_sync`]: fn.run_sync.html
+//! [`main`]: fn.main.html
+//! [`tuning`]: tuning/index.html
+//! [`run_with_init`]: fn.run_with_init.html
 //! [`lambda::Context`]: struct.Context.html
 //! [`lambda`]: attr.lambda.html
 //! [`#[tokio::main]`]: https://docs.rs/tokio/0.2.21/tokio/attr.main.html
 //! [Tokio]: https://docs.rs/tokio/
-pub use crate::types::Context;
+pub use crate::{
+    config::{Config, ConfigBuilder, ConfigError, InitializationType},
+    payload::{FromPayload, IntoPayload, Payload},
+    types::{Context, ContextBuilder},
+};
 use client::Client;
+use clock::{Clock, TokioClock};
+use codec::Codec;
 use futures_core::stream::Stream;
 use futures_util::stream::StreamExt;
-pub use lamedh_attributes::lambda;
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "derive")]
+pub use lamedh_attributes::{lambda, lambda_test};
 use std::{
-    convert::{TryFrom, TryInto},
-    env, fmt,
+    convert::TryInto,
+    fmt,
     future::Future,
+    io::{self, Write},
+    panic,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
 };
-use tracing::trace;
+use tracing::{error, info_span, trace};
+use tracing_futures::Instrument;
 
 mod client;
+pub mod clock;
+pub mod codec;
+mod config;
+mod lifecycle;
+mod local;
+#[cfg(feature = "logging")]
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+mod payload;
+pub mod record;
 mod requests;
-#[cfg(test)]
+#[cfg(any(test, feature = "simulated"))]
 mod simulated;
+/// Utilities to test Lambda functions against a mock Runtime API.
+#[cfg(feature = "simulated")]
+pub mod testing;
+pub mod tuning;
 /// Types available to a Lambda function.
 mod types;
+#[cfg(feature = "xray")]
+pub mod xray;
 
-use requests::{EventCompletionRequest, EventErrorRequest, IntoRequest, NextEventRequest};
+use lifecycle::Lifecycle;
+use local::LocalInvocation;
+use requests::{EventCompletionRequest, EventErrorRequest, InitErrorRequest, IntoRequest, NextEventRequest};
 use types::Diagnostic;
 
-static DEFAULT_LOG_GROUP: &str = "/aws/lambda/Functions";
-static DEFAULT_LOG_STREAM: &str = "$LATEST";
+/// The name of the span that the runtime runs every invocation in.
+const INVOCATION_SPAN: &str = "invocation";
+
+/// Returns whether `metadata` describes the span that the runtime runs an invocation in.
+#[cfg(any(feature = "logging", feature = "xray"))]
+fn is_invocation_span(metadata: &tracing::Metadata<'_>) -> bool {
+    metadata.is_span() && metadata.name() == INVOCATION_SPAN && metadata.target() == env!("CARGO_CRATE_NAME")
+}
 
 /// Error type that lambdas may result in
 pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
-/// Configuration derived from environment variables.
-#[derive(Debug, Default, Clone, PartialEq)]
-pub struct Config {
-    /// The host and port of the [runtime API](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html).
-    pub endpoint: String,
-    /// The name of the function.
-    pub function_name: String,
-    /// The amount of memory available to the function in MB.
-    pub memory: i32,
-    /// The version of the function being executed.
-    pub version: String,
-    /// The name of the Amazon CloudWatch Logs stream for the function.
-    pub log_stream: String,
-    /// The name of the Amazon CloudWatch Logs group for the function.
-    pub log_group: String,
-}
-
-impl Config {
-    /// Attempts to read configuration from environment variables.
-    pub fn from_env() -> Result<Self, Error> {
-        let conf = Config {
-            endpoint: env::var("AWS_LAMBDA_RUNTIME_API")?,
-            function_name: env::var("AWS_LAMBDA_FUNCTION_NAME")?,
-            memory: env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")?.parse::<i32>()?,
-            version: env::var("AWS_LAMBDA_FUNCTION_VERSION")?,
-            log_stream: env::var("AWS_LAMBDA_LOG_STREAM_NAME").unwrap_or_else(|_e| DEFAULT_LOG_STREAM.to_owned()),
-            log_group: env::var("AWS_LAMBDA_LOG_GROUP_NAME").unwrap_or_else(|_e| DEFAULT_LOG_GROUP.to_owned()),
-        };
-        Ok(conf)
-    }
-}
-
 /// A trait describing an asynchronous function `A` to `B`.
 pub trait Handler<A, B> {
     /// Errors returned by this handler.
@@ -106,6 +237,15 @@ pub trait Handler<A, B> {
     type Fut: Future<Output = Result<B, Self::Error>>;
     /// Handle the incoming event.
     fn call(&mut self, event: A, context: Context) -> Self::Fut;
+    /// Describes what triggered the invocation of `event`, for its OpenTelemetry span.
+    /// The span continues the trace that the trigger carries, or the X-Ray trace of the
+    /// invocation. This requires the `opentelemetry` feature, see [`otel`].
+    ///
+    /// [`otel`]: otel/index.html
+    #[cfg(feature = "opentelemetry")]
+    fn trigger(&self, _event: &A) -> otel::Trigger {
+        otel::Trigger::default()
+    }
 }
 
 /// Returns a new [`HandlerFn`] with the given closure.
@@ -126,7 +266,7 @@ pub struct HandlerFn<F> {
 impl<F, A, B, Error, Fut> Handler<A, B> for HandlerFn<F>
 where
     F: Fn(A, Context) -> Fut,
-    Fut: Future<Output = Result<B, Error>> + Send,
+    Fut: Future<Output = Result<B, Error>>,
     Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>> + fmt::Display,
 {
     type Error = Error;
@@ -136,9 +276,75 @@ where
     }
 }
 
+/// Returns a new [`SyncHandlerFn`] with the given function.
+///
+/// [`SyncHandlerFn`]: struct.SyncHandlerFn.html
+pub fn sync_handler_fn<F>(f: F) -> SyncHandlerFn<F> {
+    SyncHandlerFn { f: Arc::new(f) }
+}
+
+/// A [`Handler`] implemented by a synchronous function.
+///
+/// Every invocation runs on Tokio's blocking thread pool, so CPU-bound work
+/// doesn't stall the runtime's communication with the Runtime APIs.
+///
+/// [`Handler`]: trait.Handler.html
+#[derive(Debug)]
+pub struct SyncHandlerFn<F> {
+    f: Arc<F>,
+}
+
+impl<F> Clone for SyncHandlerFn<F> {
+    fn clone(&self) -> Self {
+        SyncHandlerFn { f: self.f.clone() }
+    }
+}
+
+impl<F, A, B, Error> Handler<A, B> for SyncHandlerFn<F>
+where
+    F: Fn(A, Context) -> Result<B, Error> + Send + Sync + 'static,
+    A: Send + 'static,
+    B: Send + 'static,
+    Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>> + fmt::Display + Send + 'static,
+{
+    type Error = Error;
+    type Fut = SyncHandlerFuture<B, Error>;
+    fn call(&mut self, req: A, ctx: Context) -> Self::Fut {
+        let f = self.f.clone();
+        SyncHandlerFuture {
+            handle: tokio::task::spawn_blocking(move || f(req, ctx)),
+        }
+    }
+}
+
+/// The future returned by a [`SyncHandlerFn`]. It resolves once the
+/// function returns on the blocking thread pool.
+///
+/// [`SyncHandlerFn`]: struct.SyncHandlerFn.html
+#[derive(Debug)]
+pub struct SyncHandlerFuture<B, E> {
+    handle: tokio::task::JoinHandle<Result<B, E>>,
+}
+
+impl<B, E> Future for SyncHandlerFuture<B, E> {
+    type Output = Result<B, E>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.handle).poll(cx) {
+            Poll::Ready(Ok(res)) => Poll::Ready(res),
+            // surface handler panics the same way an async handler would
+            Poll::Ready(Err(e)) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+            Poll::Ready(Err(e)) => panic!("Synchronous handler was cancelled: {}", e),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 /// Starts the Lambda Rust runtime and begins polling for events on the [Lambda
 /// Runtime APIs](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html).
 ///
+/// When the process is started with `--event <PATH>`, or the `LAMEDH_EVENT` environment
+/// variable, the handler runs once in [local mode](index.html#local-invocations) instead.
+///
 /// # Example
 /// ```no_run
 /// use lamedh_runtime::{handler_fn, Context, Error};
@@ -159,38 +365,300 @@ pub async fn run<A, B, F>(handler: F) -> Result<(), Error>
 where
     F: Handler<A, B>,
     <F as Handler<A, B>>::Error: fmt::Display,
-    A: for<'de> Deserialize<'de>,
-    B: Serialize,
+    A: FromPayload,
+    B: IntoPayload,
 {
-    trace!("Loading config from env");
     let mut handler = handler;
-    let config = Config::from_env()?;
-    let uri = config.endpoint.try_into().expect("Unable to convert to URL");
-    let client = Client::with(uri, hyper::Client::new());
+    if let Some(invocation) = LocalInvocation::from_env()? {
+        return invocation.invoke(&mut handler, None).await;
+    }
+
+    trace!("Loading config from env");
+    let config = Arc::new(Config::from_env()?);
+    let uri = config.endpoint.as_str().try_into().expect("Unable to convert to URL");
+    let client = client::connect(uri)?;
     let incoming = incoming(&client);
-    run_inner(&client, incoming, &mut handler).await?;
+    let recorder = record::installed()?;
+    let codec = codec::installed();
+    run_inner(
+        &client,
+        incoming,
+        &mut handler,
+        &config,
+        &*codec,
+        None,
+        recorder.as_ref(),
+        &mut io::stdout(),
+    )
+    .await?;
 
     Ok(())
 }
 
+/// Runs `future` to completion on a Tokio runtime sized for the function.
+///
+/// The runtime is current-thread when the function has at most one vCPU, and has a
+/// worker per vCPU above that. `LAMEDH_TOKIO_THREADS` overrides this, see [`tuning`].
+/// This function builds its own Tokio runtime, so it must not be called from an
+/// asynchronous context.
+///
+/// # Example
+/// ```no_run
+/// use lamedh_runtime::{handler_fn, Context, Error};
+/// use serde_json::Value;
+///
+/// fn main() -> Result<(), Error> {
+///     lamedh_runtime::main(lamedh_runtime::run(handler_fn(func)))
+/// }
+///
+/// async fn func(event: Value, _: Context) -> Result<Value, Error> {
+///     Ok(event)
+/// }
+/// ```
+///
+/// [`tuning`]: tuning/index.html
+pub fn main<F, T, E>(future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: From<Error>,
+{
+    let threads = tuning::Threads::from_env().map_err(|e| E::from(e.into()))?;
+    threads.block_on(future)
+}
+
+/// Starts the Lambda Rust runtime on a current-thread Tokio runtime inside a
+/// [`LocalSet`], and begins polling for events on the [Lambda Runtime
+/// APIs](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html).
+///
+/// A Lambda sandbox only processes one invocation at a time, so handlers run
+/// this way don't need to be `Send`. State can be shared with `Rc<RefCell<_>>`
+/// instead of `Arc<Mutex<_>>`, and handlers can wrap `!Send` client libraries
+/// or spawn tasks with [`tokio::task::spawn_local`].
+///
+/// This function builds its own Tokio runtime, so it must not be called from
+/// an asynchronous context.
+///
+/// # Example
+/// ```no_run
+/// use lamedh_runtime::{handler_fn, Context, Error};
+/// use serde_json::Value;
+/// use std::{cell::RefCell, rc::Rc};
+///
+/// fn main() -> Result<(), Error> {
+///     let invocations = Rc::new(RefCell::new(0));
+///     let func = handler_fn(move |event: Value, _: Context| {
+///         let invocations = invocations.clone();
+///         async move {
+///             *invocations.borrow_mut() += 1;
+///             Ok::<_, Error>(event)
+///         }
+///     });
+///     lamedh_runtime::run_local(func)
+/// }
+/// ```
+///
+/// [`LocalSet`]: https://docs.rs/tokio/1/tokio/task/struct.LocalSet.html
+/// [`tokio::task::spawn_local`]: https://docs.rs/tokio/1/tokio/task/fn.spawn_local.html
+pub fn run_local<A, B, F>(handler: F) -> Result<(), Error>
+where
+    F: Handler<A, B>,
+    <F as Handler<A, B>>::Error: fmt::Display,
+    A: FromPayload,
+    B: IntoPayload,
+{
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&rt, run(handler))
+}
+
+/// Starts the Lambda Rust runtime with a synchronous function as the handler.
+///
+/// This is meant for functions that never await anything, like pure CPU
+/// transforms. The runtime keeps talking to the Runtime APIs asynchronously
+/// on a current-thread Tokio runtime, while the function runs on a blocking
+/// thread. This function builds its own Tokio runtime, so it must not be
+/// called from an asynchronous context.
+///
+/// # Example
+/// ```no_run
+/// use lamedh_runtime::{Context, Error};
+/// use serde_json::Value;
+///
+/// fn main() -> Result<(), Error> {
+///     lamedh_runtime::run_sync(func)
+/// }
+///
+/// fn func(event: Value, _: Context) -> Result<Value, Error> {
+///     Ok(event)
+/// }
+/// ```
+pub fn run_sync<A, B, E, F>(f: F) -> Result<(), Error>
+where
+    F: Fn(A, Context) -> Result<B, E> + Send + Sync + 'static,
+    A: FromPayload + Send + 'static,
+    B: IntoPayload + Send + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync + 'static>> + fmt::Display + Send + 'static,
+{
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(run(sync_handler_fn(f)))
+}
+
+/// Starts the Lambda Rust runtime after running a one-time initialization
+/// phase, and begins polling for events on the [Lambda Runtime
+/// APIs](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html).
+///
+/// The `init` future runs once, before the first event is requested. The state
+/// it produces is passed by reference to every invocation of `f`, and it lives
+/// for the rest of the process. If `init` fails, the error is reported to the
+/// `/runtime/init/error` endpoint and returned, so the process can exit.
+///
+/// The time from the start of the process to the first invocation, which includes
+/// the time spent in `init`, is available to the handler as [`Context::init_duration`].
+///
+/// # Example
+/// ```no_run
+/// use lamedh_runtime::{Context, Error};
+/// use serde_json::{json, Value};
+///
+/// struct State {
+///     greeting: String,
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Error> {
+///     lamedh_runtime::run_with_init(init, func).await
+/// }
+///
+/// async fn init() -> Result<State, Error> {
+///     Ok(State {
+///         greeting: "Hello".to_string(),
+///     })
+/// }
+///
+/// async fn func(event: Value, _: Context, state: &State) -> Result<Value, Error> {
+///     Ok(json!({ "message": format!("{}, {}!", state.greeting, event["name"]) }))
+/// }
+/// ```
+///
+/// [`Context::init_duration`]: struct.Context.html#structfield.init_duration
+pub async fn run_with_init<A, B, S, I, IFut, IE, F, Fut, E>(init: I, f: F) -> Result<(), Error>
+where
+    I: FnOnce() -> IFut,
+    IFut: Future<Output = Result<S, IE>>,
+    IE: Into<Box<dyn std::error::Error + Send + Sync + 'static>> + fmt::Display,
+    S: 'static,
+    F: Fn(A, Context, &'static S) -> Fut,
+    Fut: Future<Output = Result<B, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync + 'static>> + fmt::Display,
+    A: FromPayload,
+    B: IntoPayload,
+{
+    let local = LocalInvocation::from_env()?;
+    let client = match local {
+        Some(_) => None,
+        None => {
+            trace!("Loading config from env");
+            let config = Arc::new(Config::from_env()?);
+            let uri = config.endpoint.as_str().try_into().expect("Unable to convert to URL");
+            Some((client::connect(uri)?, config))
+        }
+    };
+
+    trace!("Running init phase");
+    let start = Instant::now();
+    let state = match init().await {
+        Ok(state) => state,
+        Err(e) => {
+            let diagnostic = Diagnostic {
+                error_message: e.to_string(),
+                error_type: std::any::type_name::<IE>().to_owned(),
+            };
+            return match client {
+                Some((client, _)) => {
+                    client.call(InitErrorRequest { diagnostic }.into_req()?).await?;
+                    Err(e.into())
+                }
+                None => Err(local::print_diagnostic(diagnostic, &mut std::io::stdout())?),
+            };
+        }
+    };
+    let init_duration = start.elapsed();
+
+    // The state is shared by every invocation for the lifetime of the process.
+    let state: &'static S = Box::leak(Box::new(state));
+    let mut handler = StatefulHandlerFn { f, state };
+    if let Some(invocation) = local {
+        return invocation.invoke(&mut handler, Some(init_duration)).await;
+    }
+    let (client, config) = client.expect("The client is created unless the invocation is local");
+    let incoming = incoming(&client);
+    let recorder = record::installed()?;
+    let codec = codec::installed();
+    run_inner(
+        &client,
+        incoming,
+        &mut handler,
+        &config,
+        &*codec,
+        Some(init_duration),
+        recorder.as_ref(),
+        &mut io::stdout(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// A [`Handler`] implemented by a closure that borrows the state created by
+/// the init phase of [`run_with_init`].
+struct StatefulHandlerFn<F, S: 'static> {
+    f: F,
+    state: &'static S,
+}
+
+impl<F, S, A, B, Error, Fut> Handler<A, B> for StatefulHandlerFn<F, S>
+where
+    F: Fn(A, Context, &'static S) -> Fut,
+    Fut: Future<Output = Result<B, Error>>,
+    Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>> + fmt::Display,
+{
+    type Error = Error;
+    type Fut = Fut;
+    fn call(&mut self, req: A, ctx: Context) -> Self::Fut {
+        (self.f)(req, ctx, self.state)
+    }
+}
+
 /// Runs the lambda function almost entirely in-memory. This is meant for testing.
 pub async fn run_simulated<A, B, F>(handler: F, url: &str) -> Result<(), Error>
 where
     F: Handler<A, B>,
     <F as Handler<A, B>>::Error: fmt::Display,
-    A: for<'de> Deserialize<'de>,
-    B: Serialize,
+    A: FromPayload,
+    B: IntoPayload,
 {
     let mut handler = handler;
+    let config = Arc::new(Config::from_env()?);
     let uri = url.try_into().expect("Unable to convert to URL");
-    let client = Client::with(uri, hyper::Client::new());
+    let client = client::connect(uri)?;
     let incoming = incoming(&client).take(1);
-    run_inner(&client, incoming, &mut handler).await?;
+    let codec = codec::installed();
+    run_inner(
+        &client,
+        incoming,
+        &mut handler,
+        &config,
+        &*codec,
+        None,
+        None,
+        &mut io::stdout(),
+    )
+    .await?;
 
     Ok(())
 }
 
-fn incoming(client: &Client) -> impl Stream<Item = Result<http::Response<hyper::Body>, Error>> + '_ {
+fn incoming<C: Client>(client: &C) -> impl Stream<Item = Result<http::Response<hyper::Body>, Error>> + '_ {
     async_stream::stream! {
         loop {
             let req = NextEventRequest.into_req().expect("Unable to construct request");
@@ -200,48 +668,204 @@ fn incoming(client: &Client) -> impl Stream<Item = Result<http::Response<hyper::
     }
 }
 
-async fn run_inner<A, B, F>(
-    client: &Client,
+#[allow(clippy::too_many_arguments)]
+async fn run_inner<A, B, F, C>(
+    client: &C,
     incoming: impl Stream<Item = Result<http::Response<hyper::Body>, Error>>,
     handler: &mut F,
+    config: &Arc<Config>,
+    codec: &dyn Codec,
+    init_duration: Option<Duration>,
+    recorder: Option<&Arc<record::Recorder>>,
+    metrics_out: &mut (dyn Write + Send),
 ) -> Result<(), Error>
 where
+    C: Client,
     F: Handler<A, B>,
     <F as Handler<A, B>>::Error: fmt::Display,
-    A: for<'de> Deserialize<'de>,
-    B: Serialize,
+    A: FromPayload,
+    B: IntoPayload,
 {
     tokio::pin!(incoming);
 
+    let mut lifecycle = Lifecycle::start(init_duration);
     while let Some(event) = incoming.next().await {
         let event = event?;
+        // Without a successful response there is no invocation to run, nor
+        // request id to report an error for. The runtime exits, and Lambda
+        // replaces the execution environment.
+        if !event.status().is_success() {
+            return Err(format!("Runtime API returned {} for the next invocation", event.status()).into());
+        }
         let (parts, body) = event.into_parts();
+        let recording = recorder
+            .filter(|recorder| recorder.sample())
+            .map(|recorder| (recorder, parts.headers.clone()));
 
-        let mut ctx: Context = Context::try_from(parts.headers)?;
-        ctx.env_config = Config::from_env()?;
-        let body = hyper::body::to_bytes(body).await?;
-        let body = serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(&body))?;
+        let mut ctx = Context::from_headers(parts.headers, config.clone())?;
+        lifecycle.begin(&mut ctx);
+        let body = Payload::from(hyper::body::to_bytes(body).await?);
+        // The body is only kept for the recorder, so codecs that parse in place can
+        // take it without copying it.
+        let recording = recording.map(|(recorder, headers)| (recorder, headers, body.clone()));
+        let event = A::from_payload(body, codec)?;
 
         let request_id = &ctx.request_id.clone();
-        let f = handler.call(body, ctx);
+        let metrics = ctx.metrics().clone();
+        let span = info_span!(
+            INVOCATION_SPAN,
+            requestId = %ctx.request_id,
+            xrayTraceId = %ctx.xray_trace_id,
+            functionName = %config.function_name,
+            functionVersion = %config.version,
+            coldStart = ctx.is_cold_start(),
+        );
+        #[cfg(feature = "opentelemetry")]
+        let root_span = otel::Invocation::start(handler.trigger(&event), &ctx);
+        let start = Instant::now();
+        let invocation = handler.call(event, ctx).instrument(span);
+        #[cfg(feature = "opentelemetry")]
+        let invocation = opentelemetry::trace::FutureExt::with_context(invocation, root_span.context());
+        let result = match invocation.await {
+            Ok(res) => Ok(res.into_payload(codec)?),
+            Err(e) => Err(diagnostic(e)),
+        };
+        // The metrics are written before the result is sent, since the execution
+        // environment can be frozen as soon as Lambda has the result.
+        if let Err(e) = metrics.flush(metrics_out, TokioClock.now()) {
+            error!(request_id = %request_id, error = %e, "Unable to write the metrics of the invocation");
+        }
+        #[cfg(feature = "opentelemetry")]
+        root_span.end(request_id, &result).await;
+        let recording = recording.map(|(recorder, headers, body)| {
+            let outcome = record::Outcome::new(&result);
+            (
+                recorder.clone(),
+                record::Record::new(&headers, &body, outcome, start.elapsed()),
+            )
+        });
 
-        let req = match f.await {
+        let req = match result {
             Ok(res) => EventCompletionRequest { request_id, body: res }.into_req()?,
-            Err(e) => EventErrorRequest {
-                request_id,
-                diagnostic: Diagnostic {
-                    error_message: e.to_string(),
-                    error_type: type_name_of_val(e).to_owned(),
-                },
-            }
-            .into_req()?,
+            Err(diagnostic) => EventErrorRequest { request_id, diagnostic }.into_req()?,
         };
-        client.call(req).await?;
+        // The Runtime API rejects results it can't accept, such as responses
+        // over the payload limit, and fails the invocation itself. There's
+        // nothing left to report, so the runtime moves on to the next event.
+        let rsp = client.call(req).await?;
+        if !rsp.status().is_success() {
+            error!(request_id = %request_id, status = %rsp.status(), "Runtime API rejected the invocation result");
+        }
+        // Records are written once Lambda has the result, on a blocking thread, so the
+        // disk doesn't add to the duration of the invocation.
+        if let Some((recorder, record)) = recording {
+            if let Err(e) = tokio::task::spawn_blocking(move || recorder.record(record)).await {
+                error!(request_id = %request_id, "Unable to write the record: {}", e);
+            }
+        }
+        lifecycle.end();
     }
 
     Ok(())
 }
 
+/// Describes a handler error the way it's reported to the Runtime API.
+pub(crate) fn diagnostic<E: fmt::Display>(e: E) -> Diagnostic {
+    Diagnostic {
+        error_message: e.to_string(),
+        error_type: type_name_of_val(e).to_owned(),
+    }
+}
+
 fn type_name_of_val<T>(_: T) -> &'static str {
     std::any::type_name::<T>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::{cell::RefCell, rc::Rc};
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn run_is_send_with_send_handlers() {
+        async fn func(event: Value, _: Context) -> Result<Value, Error> {
+            Ok(event)
+        }
+
+        // `tokio::spawn` needs the runtime's future to be `Send`.
+        assert_send(&run(handler_fn(func)));
+    }
+
+    #[test]
+    fn handler_fn_accepts_non_send_futures() -> Result<(), Error> {
+        let invocations = Rc::new(RefCell::new(0));
+        let counter = invocations.clone();
+        let mut handler = handler_fn(move |event: Value, _: Context| {
+            let counter = counter.clone();
+            async move {
+                *counter.borrow_mut() += 1;
+                Ok::<_, Error>(event)
+            }
+        });
+
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let local = tokio::task::LocalSet::new();
+        let event = json!({"message": "hello"});
+        let res = local.block_on(&rt, handler.call(event.clone(), Context::default()))?;
+
+        assert_eq!(event, res);
+        assert_eq!(1, *invocations.borrow());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stateful_handler_fn_borrows_state() -> Result<(), Error> {
+        struct State {
+            greeting: &'static str,
+        }
+
+        async fn func(event: Value, _: Context, state: &State) -> Result<Value, Error> {
+            Ok(json!(format!(
+                "{}, {}!",
+                state.greeting,
+                event.as_str().unwrap_or_default()
+            )))
+        }
+
+        let state: &'static State = Box::leak(Box::new(State { greeting: "Hello" }));
+        let mut handler = StatefulHandlerFn { f: func, state };
+        let res = handler.call(json!("world"), Context::default()).await?;
+
+        assert_eq!(json!("Hello, world!"), res);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_handler_fn_runs_on_blocking_pool() -> Result<(), Error> {
+        fn func(event: Value, ctx: Context) -> Result<Value, Error> {
+            Ok(json!({ "event": event, "req_id": ctx.request_id }))
+        }
+
+        let mut handler = sync_handler_fn(func);
+        let ctx = Context {
+            request_id: "id".to_string(),
+            ..Context::default()
+        };
+        let res = handler.call(json!("hello"), ctx).await?;
+
+        assert_eq!(json!({ "event": "hello", "req_id": "id" }), res);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "sync handler panic")]
+    async fn sync_handler_fn_propagates_panics() {
+        let mut handler = sync_handler_fn(|_: Value, _: Context| -> Result<Value, Error> {
+            panic!("sync handler panic");
+        });
+        let _ = handler.call(Value::Null, Context::default()).await;
+    }
+}
@@ -0,0 +1,164 @@
+use crate::Context;
+use std::{fs, time::Duration};
+use tokio::time::Instant;
+
+/// The length of a clock tick in `/proc`, in milliseconds, which is 1/100th of a second
+/// on every architecture that Lambda runs on.
+///
+/// This is deliberately hardcoded rather than read with `sysconf(_SC_CLK_TCK)`: `USER_HZ`
+/// is fixed at 100 on x86_64 and aarch64, and asking for it would take a dependency on
+/// libc for a value that can't change.
+const CLOCK_TICK_MS: u64 = 10;
+
+/// Tracks the invocations of the process, to describe each one in its context.
+#[derive(Debug)]
+pub(crate) struct Lifecycle {
+    init_duration: Option<Duration>,
+    invocations: u64,
+    previous_end: Option<Instant>,
+}
+
+impl Lifecycle {
+    /// Starts tracking invocations, right before the first event is requested.
+    ///
+    /// The init duration is the time since the process started. Where that isn't
+    /// available, it falls back to `init_phase`, the time spent in `run_with_init`'s init.
+    pub(crate) fn start(init_phase: Option<Duration>) -> Self {
+        Lifecycle {
+            init_duration: process_uptime().or(init_phase),
+            invocations: 0,
+            previous_end: None,
+        }
+    }
+
+    /// Counts a new invocation, and describes it in its context.
+    pub(crate) fn begin(&mut self, ctx: &mut Context) {
+        self.invocations += 1;
+        ctx.invocation_number = self.invocations;
+        ctx.init_duration = self.init_duration;
+        ctx.since_previous_invocation = self.previous_end.map(|end| end.elapsed());
+    }
+
+    /// Marks the end of the current invocation, once its result is sent.
+    pub(crate) fn end(&mut self) {
+        self.previous_end = Some(Instant::now());
+    }
+}
+
+/// Returns how long the process has been running, from its start time in `/proc`.
+fn process_uptime() -> Option<Duration> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    parse_process_uptime(&stat, &uptime)
+}
+
+/// Computes the uptime of a process from its `/proc/<pid>/stat` and the system's `/proc/uptime`.
+fn parse_process_uptime(stat: &str, uptime: &str) -> Option<Duration> {
+    // The start time is the 22nd field, in clock ticks since boot. The 2nd field is
+    // the name of the executable in parentheses, which can contain spaces.
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let start_ticks: u64 = fields.nth(19)?.parse().ok()?;
+    // The uptime of the system is in seconds, with the precision of a clock tick.
+    let system_uptime: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    let uptime_ticks = (system_uptime * 1000.0 / CLOCK_TICK_MS as f64).round() as u64;
+    Some(Duration::from_millis(
+        CLOCK_TICK_MS * uptime_ticks.checked_sub(start_ticks)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAT: &str =
+        "27507 (my bootstrap) R 27500 27507 27500 0 -1 4194304 83 0 0 0 0 0 0 0 20 0 1 0 800239 2703360 327";
+
+    #[test]
+    fn process_uptime_is_read_from_proc() {
+        assert_eq!(
+            Some(Duration::from_millis(1_250)),
+            parse_process_uptime(STAT, "8003.64 4080.31\n")
+        );
+        assert_eq!(None, parse_process_uptime(STAT, "8000.00 4080.31\n"));
+        assert_eq!(None, parse_process_uptime("27507 (bootstrap) R", "8003.64 4080.31\n"));
+    }
+
+    #[test]
+    fn process_uptime_outlasts_32_bit_ticks() {
+        let stat = STAT.replace(" 800239 ", " 5000000000 ");
+        assert_eq!(
+            Some(Duration::from_millis(1_250)),
+            parse_process_uptime(&stat, "50000001.25 4080.31\n")
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_uptime_is_available_on_linux() {
+        assert!(process_uptime().is_some());
+    }
+
+    #[tokio::test]
+    async fn invocations_are_counted_and_spaced() {
+        tokio::time::pause();
+        let mut lifecycle = Lifecycle::start(Some(Duration::from_secs(1)));
+        let init_duration = lifecycle.init_duration;
+
+        let mut first = Context::default();
+        lifecycle.begin(&mut first);
+        lifecycle.end();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let mut second = Context::default();
+        lifecycle.begin(&mut second);
+
+        assert!(first.is_cold_start());
+        assert_eq!(None, first.since_previous_invocation);
+        assert!(!second.is_cold_start());
+        assert_eq!(2, second.invocation_number);
+        assert_eq!(Some(Duration::from_secs(30)), second.since_previous_invocation);
+        assert_eq!(init_duration, second.init_duration);
+    }
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod runtime_tests {
+    use crate::{
+        handler_fn,
+        testing::{MockEvent, MockRuntime},
+        Context, Error,
+    };
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn contexts_describe_the_invocations_of_the_process() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        for _ in 0..3 {
+            runtime.push_event(MockEvent::new(&json!({}))?);
+        }
+
+        runtime
+            .run(handler_fn(|_: Value, ctx: Context| async move {
+                Ok::<_, Error>(json!({
+                    "cold": ctx.is_cold_start(),
+                    "number": ctx.invocation_number,
+                    "previous": ctx.since_previous_invocation.is_some(),
+                }))
+            }))
+            .await?;
+
+        let responses: Vec<Value> = runtime
+            .responses()
+            .iter()
+            .map(|response| response.json())
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            vec![
+                json!({ "cold": true, "number": 1, "previous": false }),
+                json!({ "cold": false, "number": 2, "previous": true }),
+                json!({ "cold": false, "number": 3, "previous": true }),
+            ],
+            responses
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{
+    clock::{Clock, TokioClock},
+    codec::{self, Codec},
+    diagnostic,
+    lifecycle::Lifecycle,
+    types::Diagnostic,
+    Config, Context, ContextBuilder, Error, FromPayload, Handler, IntoPayload, Payload,
+};
+use std::{
+    env, fmt, fs,
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+/// The environment variable that starts a one-shot local invocation, like `--event`.
+pub(crate) const EVENT_ENV_VAR: &str = "LAMEDH_EVENT";
+
+/// A single invocation of the handler outside of Lambda, with an event read from a
+/// file or stdin, and a synthetic context.
+#[derive(Debug)]
+pub(crate) struct LocalInvocation {
+    event: Vec<u8>,
+    ctx: Context,
+}
+
+impl LocalInvocation {
+    /// Returns the local invocation requested by the process arguments or the
+    /// environment, if any.
+    pub(crate) fn from_env() -> Result<Option<Self>, Error> {
+        let (path, builder) = match parse_args(env::args().skip(1), env::var(EVENT_ENV_VAR).ok())? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+
+        let event = if path == "-" {
+            let mut event = Vec::new();
+            io::stdin().read_to_end(&mut event)?;
+            event
+        } else {
+            fs::read(&path).map_err(|e| format!("Unable to read event from {}: {}", path, e))?
+        };
+
+        Ok(Some(LocalInvocation {
+            event,
+            ctx: builder.build(),
+        }))
+    }
+
+    /// Invokes `handler` once, and prints the response, or the diagnostic of the
+    /// error, as JSON on stdout. Handler errors are also returned, so the process
+    /// exits with a failure code.
+    pub(crate) async fn invoke<A, B, F>(self, handler: &mut F, init_duration: Option<Duration>) -> Result<(), Error>
+    where
+        F: Handler<A, B>,
+        <F as Handler<A, B>>::Error: fmt::Display,
+        A: FromPayload,
+        B: IntoPayload,
+    {
+        let codec = codec::installed();
+        self.invoke_to(handler, init_duration, &*codec, &mut io::stdout()).await
+    }
+
+    async fn invoke_to<A, B, F, W>(
+        self,
+        handler: &mut F,
+        init_duration: Option<Duration>,
+        codec: &dyn Codec,
+        out: &mut W,
+    ) -> Result<(), Error>
+    where
+        F: Handler<A, B>,
+        <F as Handler<A, B>>::Error: fmt::Display,
+        A: FromPayload,
+        B: IntoPayload,
+        W: Write,
+    {
+        let event = A::from_payload(Payload::from(self.event), codec)?;
+        let mut ctx = self.ctx;
+        Lifecycle::start(init_duration).begin(&mut ctx);
+        let metrics = ctx.metrics().clone();
+
+        let result = handler.call(event, ctx).await;
+        // The output is the response alone, so the metrics go to stderr.
+        metrics.flush(&mut io::stderr(), TokioClock.now())?;
+        match result {
+            Ok(res) => {
+                out.write_all(&res.into_payload(codec)?)?;
+                writeln!(out)?;
+                Ok(())
+            }
+            Err(e) => Err(print_diagnostic(diagnostic(e), out)?),
+        }
+    }
+}
+
+/// Prints a diagnostic as JSON, and returns it as an error.
+pub(crate) fn print_diagnostic<W: Write>(diagnostic: Diagnostic, out: &mut W) -> Result<Error, Error> {
+    serde_json::to_writer(&mut *out, &diagnostic)?;
+    writeln!(out)?;
+    Ok(diagnostic.error_message.into())
+}
+
+/// Parses the path of the event and the context overrides of a local invocation.
+///
+/// Local mode starts with `--event <PATH>` or the `LAMEDH_EVENT` environment variable,
+/// where `-` reads the event from stdin. The context can be adjusted with
+/// `--request-id`, `--function-name`, `--invoked-function-arn`, `--xray-trace-id`,
+/// and `--timeout <SECONDS>`. Other arguments are left to the function, and so are
+/// all of them outside of local mode.
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+    env_event: Option<String>,
+) -> Result<Option<(String, ContextBuilder)>, Error> {
+    let args: Vec<String> = args.into_iter().collect();
+    let mut path = env_event;
+    if let Some(at) = args.iter().position(|arg| arg == "--event") {
+        path = Some(args.get(at + 1).cloned().ok_or("Missing value for --event")?);
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let mut builder = Context::builder();
+    // Use the function's configuration, if it's set like in Lambda.
+    if let Ok(config) = Config::from_env() {
+        builder = builder.env_config(config);
+    }
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--event" => {
+                value()?;
+            }
+            "--request-id" => builder = builder.request_id(value()?),
+            "--function-name" => builder = builder.function_name(value()?),
+            "--invoked-function-arn" => builder = builder.invoked_function_arn(value()?),
+            "--xray-trace-id" => builder = builder.xray_trace_id(value()?),
+            "--timeout" => {
+                let value = value()?;
+                let timeout = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for --timeout: {}", value))?;
+                builder = builder.timeout(Duration::from_secs(timeout));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Some((path, builder)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::JsonCodec, handler_fn};
+    use serde_json::{json, Value};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    async fn greet(event: Value, ctx: Context) -> Result<Value, Error> {
+        match event["name"].as_str() {
+            Some(name) => Ok(json!({ "message": format!("Hello, {}!", name), "req_id": ctx.request_id })),
+            None => Err("missing name".into()),
+        }
+    }
+
+    #[test]
+    fn detects_local_mode() -> Result<(), Error> {
+        assert!(parse_args(args(&["--verbose"]), None)?.is_none());
+
+        let (path, _) = parse_args(args(&["--event", "event.json"]), None)?.expect("Local mode");
+        assert_eq!("event.json", path);
+
+        let (path, _) = parse_args(args(&[]), Some("-".to_string()))?.expect("Local mode");
+        assert_eq!("-", path);
+
+        assert!(parse_args(args(&["--event"]), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_flags_to_the_function_outside_of_local_mode() -> Result<(), Error> {
+        assert!(parse_args(args(&["--timeout", "abc"]), None)?.is_none());
+        assert!(parse_args(args(&["--timeout", "5m", "--request-id"]), None)?.is_none());
+        assert!(parse_args(args(&["--event", "event.json", "--timeout", "abc"]), None).is_err());
+        assert!(parse_args(args(&["--timeout", "abc"]), Some("-".to_string())).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn applies_context_overrides() -> Result<(), Error> {
+        let (_, builder) = parse_args(
+            args(&[
+                "--event",
+                "event.json",
+                "--request-id",
+                "local",
+                "--function-name",
+                "greeter",
+                "--timeout",
+                "10",
+            ]),
+            None,
+        )?
+        .expect("Local mode");
+        let ctx = builder.build();
+        assert_eq!("local", ctx.request_id);
+        assert_eq!("greeter", ctx.env_config.function_name);
+        assert!(ctx.invoked_function_arn.ends_with(":function:greeter"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prints_responses() -> Result<(), Error> {
+        let invocation = LocalInvocation {
+            event: br#"{"name":"Ferris"}"#.to_vec(),
+            ctx: Context::builder().request_id("local").build(),
+        };
+        let mut out = Vec::new();
+        invocation
+            .invoke_to(&mut handler_fn(greet), None, &JsonCodec, &mut out)
+            .await?;
+
+        let printed: Value = serde_json::from_slice(&out)?;
+        assert_eq!(json!({ "message": "Hello, Ferris!", "req_id": "local" }), printed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prints_diagnostics_and_fails() -> Result<(), Error> {
+        let invocation = LocalInvocation {
+            event: b"{}".to_vec(),
+            ctx: Context::builder().build(),
+        };
+        let mut out = Vec::new();
+        let res = invocation
+            .invoke_to(&mut handler_fn(greet), None, &JsonCodec, &mut out)
+            .await;
+        assert!(res.is_err());
+
+        let printed: Value = serde_json::from_slice(&out)?;
+        assert_eq!("missing name", printed["errorMessage"]);
+        assert!(printed["errorType"].is_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,572 @@
+//! Structured JSON logs, in a shape that CloudWatch Logs Insights can query.
+//!
+//! [`JsonLayer`] is a [`tracing`] layer that writes every event as one line of JSON on
+//! stdout, with the fields of the event and of the spans it's in. The runtime runs each
+//! invocation in a span, so every line that a handler logs carries the `requestId`,
+//! `xrayTraceId`, `functionName`, `functionVersion` and `coldStart` of its invocation:
+//!
+//! ```json
+//! {"coldStart":true,"functionName":"orders","functionVersion":"$LATEST","level":"INFO","message":"Order created","order_id":42,"requestId":"52fdfc07-2182-454f-963f-5f0f9a621d72","target":"orders","timestamp":"2020-09-13T12:26:40.123Z","xrayTraceId":"Root=1-5f5e1a40-0123456789abcdef01234567"}
+//! ```
+//!
+//! [`init`] installs the layer as the global subscriber, filtered by a [`JsonFilter`]
+//! configured from the environment:
+//!
+//! ```no_run
+//! use lamedh_runtime::{handler_fn, logging, Context, Error};
+//! use serde_json::Value;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     logging::init()?;
+//!     lamedh_runtime::run(handler_fn(func)).await
+//! }
+//!
+//! async fn func(event: Value, _: Context) -> Result<Value, Error> {
+//!     tracing::info!(order_id = 42, "Order created");
+//!     Ok(event)
+//! }
+//! ```
+//!
+//! The level comes from `AWS_LAMBDA_LOG_LEVEL`, which Lambda sets from the logging
+//! configuration of the function, and is `INFO` by default. `LAMEDH_LOG_DEBUG_SAMPLE_RATE`
+//! sets a fraction of invocations, between `0` and `1`, that log at `DEBUG` whatever the
+//! level is, to troubleshoot without flooding the logs.
+//!
+//! The filter only applies to the JSON layer, so the layer can be stacked with other
+//! layers, like the `XRayLayer` of the `xray` feature, that see the spans it filters out:
+//!
+//! ```no_run
+//! # #[cfg(feature = "xray")]
+//! # fn main() -> Result<(), lamedh_runtime::Error> {
+//! use lamedh_runtime::{logging::{JsonFilter, JsonLayer}, xray::XRayLayer};
+//! use tracing_subscriber::prelude::*;
+//!
+//! tracing_subscriber::registry()
+//!     .with(JsonLayer::new().with_filter(JsonFilter::from_env()?))
+//!     .with(XRayLayer::from_env()?)
+//!     .try_init()?;
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "xray"))]
+//! # fn main() {}
+//! ```
+//!
+//! This module requires the `logging` feature.
+//!
+//! [`JsonFilter`]: struct.JsonFilter.html
+//! [`JsonLayer`]: struct.JsonLayer.html
+//! [`init`]: fn.init.html
+//! [`tracing`]: https://docs.rs/tracing/0.1
+use crate::{clock::civil_from_days, is_invocation_span, Error};
+use serde_json::{Map, Value};
+use std::{
+    env, fmt,
+    io::{self, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context as LayerContext, Filter, Layer},
+    prelude::*,
+    registry::LookupSpan,
+};
+
+/// The environment variable with the log level, which Lambda sets from the logging
+/// configuration of the function.
+pub const LEVEL_ENV_VAR: &str = "AWS_LAMBDA_LOG_LEVEL";
+
+/// The environment variable with the fraction of invocations that log at `DEBUG`.
+pub const DEBUG_SAMPLE_RATE_ENV_VAR: &str = "LAMEDH_LOG_DEBUG_SAMPLE_RATE";
+
+/// Installs a [`JsonLayer`], filtered by a [`JsonFilter`] configured from the environment,
+/// as the global subscriber.
+///
+/// [`JsonFilter`]: struct.JsonFilter.html
+/// [`JsonLayer`]: struct.JsonLayer.html
+pub fn init() -> Result<(), Error> {
+    tracing_subscriber::registry()
+        .with(JsonLayer::new().with_filter(JsonFilter::from_env()?))
+        .try_init()?;
+    Ok(())
+}
+
+/// A [`tracing`] layer that writes events as lines of JSON.
+///
+/// The layer writes every event it sees. Use it with a [`JsonFilter`] to choose the level.
+///
+/// [`JsonFilter`]: struct.JsonFilter.html
+/// [`tracing`]: https://docs.rs/tracing/0.1
+pub struct JsonLayer {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for JsonLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonLayer").finish()
+    }
+}
+
+impl Default for JsonLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonLayer {
+    /// Creates a layer that writes events to stdout.
+    pub fn new() -> Self {
+        JsonLayer {
+            writer: Mutex::new(Box::new(io::stdout())),
+        }
+    }
+
+    /// Writes the lines to `writer`, instead of stdout.
+    pub fn writer(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.writer = Mutex::new(Box::new(writer));
+        self
+    }
+
+    fn write(&self, object: Map<String, Value>) {
+        let mut line = match serde_json::to_vec(&object) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+        let mut writer = self.writer.lock().expect("Lock was poisoned when writing a log line");
+        // There's nowhere left to report a failure to write a log line.
+        let _ = writer.write_all(&line).and_then(|_| writer.flush());
+    }
+}
+
+/// A per-layer filter with the level of the logs, and the invocations sampled at `DEBUG`.
+///
+/// The filter only applies to the layer it's attached to with `Layer::with_filter`, so
+/// other layers of the subscriber still see the spans and events it filters out.
+#[derive(Debug)]
+pub struct JsonFilter {
+    level: LevelFilter,
+    debug_sample_rate: f64,
+    seen: AtomicU64,
+}
+
+impl Default for JsonFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonFilter {
+    /// Creates a filter that enables events at `INFO` and above.
+    pub fn new() -> Self {
+        JsonFilter {
+            level: LevelFilter::INFO,
+            debug_sample_rate: 0.0,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates the filter configured by the `AWS_LAMBDA_LOG_LEVEL` and
+    /// `LAMEDH_LOG_DEBUG_SAMPLE_RATE` environment variables.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut filter = Self::new();
+        if let Ok(level) = env::var(LEVEL_ENV_VAR) {
+            filter = filter.level(parse_level(&level).ok_or_else(|| {
+                format!(
+                    "Invalid value for {}: {}, expected one of TRACE, DEBUG, INFO, WARN, ERROR or FATAL",
+                    LEVEL_ENV_VAR, level
+                )
+            })?);
+        }
+        if let Ok(rate) = env::var(DEBUG_SAMPLE_RATE_ENV_VAR) {
+            let rate = rate
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: {}", DEBUG_SAMPLE_RATE_ENV_VAR, rate))?;
+            filter = filter.debug_sample_rate(rate);
+        }
+        Ok(filter)
+    }
+
+    /// Sets the most verbose level that is enabled.
+    pub fn level(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.level = level.into();
+        self
+    }
+
+    /// Sets the fraction of invocations that log at `DEBUG`, between `0` and `1`.
+    /// Sampling is deterministic: a rate of `0.25` raises the level of one invocation out of four.
+    pub fn debug_sample_rate(mut self, rate: f64) -> Self {
+        self.debug_sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Returns the most verbose level that some invocations write.
+    fn max_level(&self) -> LevelFilter {
+        if self.debug_sample_rate > 0.0 {
+            self.level.max(LevelFilter::DEBUG)
+        } else {
+            self.level
+        }
+    }
+
+    /// Returns whether the next invocation logs at `DEBUG`.
+    fn sample(&self) -> bool {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * self.debug_sample_rate).floor() > (seen * self.debug_sample_rate).floor()
+    }
+}
+
+/// Parses a level of `AWS_LAMBDA_LOG_LEVEL`, where `FATAL` is the same as `ERROR`.
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    if level.eq_ignore_ascii_case("fatal") {
+        return Some(LevelFilter::ERROR);
+    }
+    LevelFilter::from_str(level).ok()
+}
+
+/// The fields of a span, in the extensions of the span.
+struct Fields(Map<String, Value>);
+
+/// Whether an invocation span logs at `DEBUG`, in the extensions of the span.
+struct Sampled(bool);
+
+impl<S> Filter<S> for JsonFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> tracing::subscriber::Interest {
+        use tracing::subscriber::Interest;
+        if is_invocation_span(metadata) || *metadata.level() <= self.level {
+            Interest::always()
+        } else if *metadata.level() <= self.max_level() {
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: &LayerContext<'_, S>) -> bool {
+        if is_invocation_span(metadata) || *metadata.level() <= self.level {
+            return true;
+        }
+        if *metadata.level() > Level::DEBUG || self.debug_sample_rate <= 0.0 {
+            return false;
+        }
+        ctx.lookup_current()
+            .map(|span| {
+                span.scope()
+                    .any(|span| matches!(span.extensions().get::<Sampled>(), Some(Sampled(true))))
+            })
+            .unwrap_or_default()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level())
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: LayerContext<'_, S>) {
+        if !is_invocation_span(attrs.metadata()) {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut()
+                .insert(Sampled(self.debug_sample_rate > 0.0 && self.sample()));
+        }
+    }
+}
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Map::new();
+            attrs.record(&mut JsonVisitor(&mut fields));
+            span.extensions_mut().insert(Fields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(Fields(fields)) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(&mut JsonVisitor(fields));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let metadata = event.metadata();
+        let mut object = Map::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(Fields(fields)) = span.extensions().get::<Fields>() {
+                    object.extend(fields.iter().map(|(name, value)| (name.clone(), value.clone())));
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut object));
+        object.insert("timestamp".to_owned(), Value::from(format_timestamp(SystemTime::now())));
+        object.insert("level".to_owned(), Value::from(metadata.level().as_str()));
+        object.insert("target".to_owned(), Value::from(metadata.target()));
+        self.write(object);
+    }
+}
+
+/// Records fields as JSON values.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), Value::from(format!("{:?}", value)));
+    }
+}
+
+/// Formats a time as RFC 3339 in UTC, with milliseconds, like Lambda's own JSON logs.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::INVOCATION_SPAN;
+    use std::{sync::Arc, time::Duration};
+    use tracing::{debug, info, info_span, warn};
+
+    /// A writer that tests can read the lines back from.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Lines {
+        fn json(&self) -> Vec<Value> {
+            let buf = self.0.lock().unwrap();
+            buf.split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn invocation(request_id: &str) -> tracing::Span {
+        info_span!(target: "lamedh_runtime", INVOCATION_SPAN, requestId = request_id, coldStart = false)
+    }
+
+    #[test]
+    fn events_are_written_with_the_fields_of_their_spans() {
+        let lines = Lines::default();
+        let layer = JsonLayer::new().writer(lines.clone()).with_filter(JsonFilter::new());
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _invocation = invocation("52fdfc07").entered();
+            let _step = info_span!("step", name = "payment").entered();
+            info!(order_id = 42, amount = 9.5, "Order created");
+            debug!("Not written");
+        });
+
+        let lines = lines.json();
+        assert_eq!(1, lines.len());
+        let line = &lines[0];
+        assert_eq!("Order created", line["message"]);
+        assert_eq!("INFO", line["level"]);
+        assert_eq!("52fdfc07", line["requestId"]);
+        assert_eq!(false, line["coldStart"]);
+        assert_eq!("payment", line["name"]);
+        assert_eq!(42, line["order_id"]);
+        assert_eq!(9.5, line["amount"]);
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn sampled_invocations_log_at_debug() {
+        let lines = Lines::default();
+        let filter = JsonFilter::new().level(LevelFilter::WARN).debug_sample_rate(0.5);
+        let layer = JsonLayer::new().writer(lines.clone()).with_filter(filter);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for request_id in &["first", "second", "third", "fourth"] {
+                let _invocation = invocation(request_id).entered();
+                debug!("Debugging");
+                warn!("Warning");
+            }
+            debug!("Outside of an invocation");
+        });
+
+        let lines: Vec<(String, String)> = lines
+            .json()
+            .iter()
+            .map(|line| {
+                (
+                    line["requestId"].as_str().unwrap().to_owned(),
+                    line["message"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect();
+        let expected: Vec<(String, String)> = vec![
+            ("first", "Warning"),
+            ("second", "Debugging"),
+            ("second", "Warning"),
+            ("third", "Warning"),
+            ("fourth", "Debugging"),
+            ("fourth", "Warning"),
+        ]
+        .into_iter()
+        .map(|(id, message)| (id.to_owned(), message.to_owned()))
+        .collect();
+        assert_eq!(expected, lines);
+    }
+
+    #[cfg(feature = "simulated")]
+    #[tokio::test]
+    async fn handlers_log_with_the_fields_of_their_invocation() -> Result<(), Error> {
+        use crate::{
+            handler_fn,
+            testing::{MockEvent, MockRuntime},
+            Context,
+        };
+
+        let lines = Lines::default();
+        let layer = JsonLayer::new().writer(lines.clone()).with_filter(JsonFilter::new());
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let mut runtime = MockRuntime::new();
+        runtime.push_event(MockEvent::new(&"first")?.request_id("first-request"));
+        runtime.push_event(
+            MockEvent::new(&"second")?
+                .request_id("second-request")
+                .xray_trace_id("Root=1-2-3"),
+        );
+        runtime
+            .run(handler_fn(|event: String, _: Context| async move {
+                info!(event = %event, "Handling");
+                Ok::<_, Error>(event)
+            }))
+            .await?;
+
+        let lines: Vec<Value> = lines
+            .json()
+            .into_iter()
+            .filter(|line| line["message"] == "Handling")
+            .collect();
+        assert_eq!(2, lines.len());
+        assert_eq!("first-request", lines[0]["requestId"]);
+        assert_eq!(true, lines[0]["coldStart"]);
+        assert_eq!("mock-function", lines[0]["functionName"]);
+        assert_eq!("second-request", lines[1]["requestId"]);
+        assert_eq!("Root=1-2-3", lines[1]["xrayTraceId"]);
+        assert_eq!(false, lines[1]["coldStart"]);
+        assert_eq!("second", lines[1]["event"]);
+        Ok(())
+    }
+
+    #[cfg(feature = "xray")]
+    #[test]
+    fn the_filter_leaves_spans_to_the_other_layers() {
+        use crate::xray::tests::{Daemon, TRACE_HEADER};
+
+        let daemon = Daemon::bind();
+        let lines = Lines::default();
+        let layer = JsonLayer::new()
+            .writer(lines.clone())
+            .with_filter(JsonFilter::new().level(LevelFilter::WARN));
+        let subscriber = tracing_subscriber::registry().with(layer).with(daemon.layer());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _invocation = info_span!(
+                target: "lamedh_runtime",
+                INVOCATION_SPAN,
+                requestId = "52fdfc07",
+                xrayTraceId = TRACE_HEADER
+            )
+            .entered();
+            let _orders = info_span!("orders", xray = true).entered();
+            info!("Not written");
+            warn!("Out of stock");
+        });
+
+        let lines = lines.json();
+        assert_eq!(1, lines.len());
+        assert_eq!("Out of stock", lines[0]["message"]);
+        assert_eq!("52fdfc07", lines[0]["requestId"]);
+
+        let subsegments = daemon.subsegments();
+        assert_eq!(1, subsegments.len());
+        assert_eq!("orders", subsegments[0]["name"]);
+    }
+
+    #[test]
+    fn levels_follow_lambda_names() {
+        assert_eq!(Some(LevelFilter::ERROR), parse_level("FATAL"));
+        assert_eq!(Some(LevelFilter::WARN), parse_level("WARN"));
+        assert_eq!(Some(LevelFilter::DEBUG), parse_level("debug"));
+        assert_eq!(None, parse_level("LOUD"));
+    }
+
+    #[test]
+    fn timestamps_are_rfc_3339() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_600_000_000_123);
+        assert_eq!("2020-09-13T12:26:40.123Z", format_timestamp(time));
+        assert_eq!("1970-01-01T00:00:00.000Z", format_timestamp(UNIX_EPOCH));
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!("2000-02-29T00:00:00.000Z", format_timestamp(leap_day));
+    }
+}
@@ -0,0 +1,463 @@
+//! Custom metrics in the CloudWatch [Embedded Metric Format].
+//!
+//! Every invocation has a [`Metrics`] collector, available with [`Context::metrics`]. The
+//! runtime writes what it collected to stdout once the handler returns, as lines of EMF
+//! JSON, and CloudWatch Logs turns them into metrics asynchronously, without any API call
+//! from the function:
+//!
+//! ```
+//! use lamedh_runtime::{metrics::Unit, Context, Error};
+//! use serde_json::Value;
+//!
+//! async fn func(event: Value, ctx: Context) -> Result<Value, Error> {
+//!     let metrics = ctx.metrics();
+//!     metrics.namespace("Orders");
+//!     metrics.dimension("Operation", "Create");
+//!     metrics.put("Items", 3.0, Unit::Count);
+//!     metrics.put("PaymentLatency", 41.5, Unit::Milliseconds);
+//!     Ok(event)
+//! }
+//! ```
+//!
+//! The metrics of an invocation have the `FunctionName` and `FunctionVersion` dimensions
+//! of the function by default, on top of the ones that the handler adds. A metric that is
+//! put several times is sent with every value. CloudWatch accepts at most 100 metrics per
+//! line, and 100 values per metric, so larger batches are split over several lines.
+//!
+//! [Embedded Metric Format]: https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+//! [`Metrics`]: struct.Metrics.html
+//! [`Context::metrics`]: ../struct.Context.html#method.metrics
+use crate::Config;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Map, Value};
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The namespace of metrics, unless the handler sets one.
+pub const DEFAULT_NAMESPACE: &str = "aws-embedded-metrics";
+
+/// The most metrics that CloudWatch accepts in a line, and values for a metric.
+const MAX_PER_LINE: usize = 100;
+
+/// The unit of a metric.
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unit {
+    Seconds,
+    Microseconds,
+    Milliseconds,
+    Bytes,
+    Kilobytes,
+    Megabytes,
+    Gigabytes,
+    Terabytes,
+    Bits,
+    Kilobits,
+    Megabits,
+    Gigabits,
+    Terabits,
+    Percent,
+    Count,
+    BytesPerSecond,
+    KilobytesPerSecond,
+    MegabytesPerSecond,
+    GigabytesPerSecond,
+    TerabytesPerSecond,
+    BitsPerSecond,
+    KilobitsPerSecond,
+    MegabitsPerSecond,
+    GigabitsPerSecond,
+    TerabitsPerSecond,
+    CountPerSecond,
+    #[default]
+    None,
+}
+
+impl Unit {
+    /// Returns the name of the unit in CloudWatch.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Unit::Seconds => "Seconds",
+            Unit::Microseconds => "Microseconds",
+            Unit::Milliseconds => "Milliseconds",
+            Unit::Bytes => "Bytes",
+            Unit::Kilobytes => "Kilobytes",
+            Unit::Megabytes => "Megabytes",
+            Unit::Gigabytes => "Gigabytes",
+            Unit::Terabytes => "Terabytes",
+            Unit::Bits => "Bits",
+            Unit::Kilobits => "Kilobits",
+            Unit::Megabits => "Megabits",
+            Unit::Gigabits => "Gigabits",
+            Unit::Terabits => "Terabits",
+            Unit::Percent => "Percent",
+            Unit::Count => "Count",
+            Unit::BytesPerSecond => "Bytes/Second",
+            Unit::KilobytesPerSecond => "Kilobytes/Second",
+            Unit::MegabytesPerSecond => "Megabytes/Second",
+            Unit::GigabytesPerSecond => "Gigabytes/Second",
+            Unit::TerabytesPerSecond => "Terabytes/Second",
+            Unit::BitsPerSecond => "Bits/Second",
+            Unit::KilobitsPerSecond => "Kilobits/Second",
+            Unit::MegabitsPerSecond => "Megabits/Second",
+            Unit::GigabitsPerSecond => "Gigabits/Second",
+            Unit::TerabitsPerSecond => "Terabits/Second",
+            Unit::CountPerSecond => "Count/Second",
+            Unit::None => "None",
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Unit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// The metrics that an invocation collected.
+///
+/// Clones share the same metrics, so a clone can be moved into a task that the handler
+/// spawns. Metrics don't take part in the comparison of contexts.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<Collector>>,
+}
+
+#[derive(Debug, Default)]
+struct Collector {
+    namespace: Option<String>,
+    dimensions: Vec<(String, String)>,
+    properties: Map<String, Value>,
+    metrics: Vec<Metric>,
+}
+
+#[derive(Debug)]
+struct Metric {
+    name: String,
+    unit: Unit,
+    values: Vec<f64>,
+}
+
+impl Metrics {
+    /// Creates an empty collector, without dimensions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the collector of an invocation, with the default dimensions of the function.
+    pub(crate) fn for_function(config: &Config) -> Self {
+        let metrics = Self::new();
+        if !config.function_name.is_empty() {
+            metrics.dimension("FunctionName", &config.function_name);
+        }
+        if !config.version.is_empty() {
+            metrics.dimension("FunctionVersion", &config.version);
+        }
+        metrics
+    }
+
+    /// Sets the namespace of the metrics, `aws-embedded-metrics` by default.
+    pub fn namespace(&self, namespace: impl Into<String>) -> &Self {
+        self.lock().namespace = Some(namespace.into());
+        self
+    }
+
+    /// Adds a dimension to the metrics, or replaces the value of a dimension with the same name.
+    pub fn dimension(&self, name: impl Into<String>, value: impl Into<String>) -> &Self {
+        let (name, value) = (name.into(), value.into());
+        let mut collector = self.lock();
+        match collector.dimensions.iter_mut().find(|(n, _)| *n == name) {
+            Some(dimension) => dimension.1 = value,
+            None => collector.dimensions.push((name, value)),
+        }
+        self
+    }
+
+    /// Removes every dimension, including the default ones.
+    pub fn clear_dimensions(&self) -> &Self {
+        self.lock().dimensions.clear();
+        self
+    }
+
+    /// Adds a property to the log lines of the metrics. Properties aren't metrics,
+    /// but they can be searched with CloudWatch Logs Insights.
+    pub fn property(&self, name: impl Into<String>, value: impl Into<Value>) -> &Self {
+        self.lock().properties.insert(name.into(), value.into());
+        self
+    }
+
+    /// Adds a value to a metric. The unit of a metric is the one of its first value.
+    ///
+    /// Values that aren't finite are dropped, since they can't be represented in JSON.
+    pub fn put(&self, name: impl Into<String>, value: f64, unit: Unit) -> &Self {
+        if !value.is_finite() {
+            return self;
+        }
+        let name = name.into();
+        let mut collector = self.lock();
+        match collector.metrics.iter_mut().find(|metric| metric.name == name) {
+            Some(metric) => metric.values.push(value),
+            None => collector.metrics.push(Metric {
+                name,
+                unit,
+                values: vec![value],
+            }),
+        }
+        self
+    }
+
+    /// Returns whether no metric was put.
+    pub fn is_empty(&self) -> bool {
+        self.lock().metrics.is_empty()
+    }
+
+    /// Returns the EMF documents of the metrics collected so far, one per log line.
+    pub fn documents(&self, timestamp: SystemTime) -> Vec<Value> {
+        let collector = self.lock();
+        let timestamp = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let namespace = collector.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+        let dimensions: Vec<&str> = collector.dimensions.iter().map(|(name, _)| name.as_str()).collect();
+        let dimension_sets = if dimensions.is_empty() {
+            json!([])
+        } else {
+            json!([dimensions])
+        };
+
+        // A line can only have one entry per metric, so every value past the limit of a
+        // metric goes to another round of lines.
+        let rounds = collector
+            .metrics
+            .iter()
+            .map(|metric| metric.values.len().div_ceil(MAX_PER_LINE))
+            .max()
+            .unwrap_or_default();
+        let mut documents = Vec::new();
+        for round in 0..rounds {
+            let chunks: Vec<(&Metric, &[f64])> = collector
+                .metrics
+                .iter()
+                .filter_map(|metric| Some((metric, metric.values.chunks(MAX_PER_LINE).nth(round)?)))
+                .collect();
+            for line in chunks.chunks(MAX_PER_LINE) {
+                let mut document = collector.properties.clone();
+                for (name, value) in &collector.dimensions {
+                    document.insert(name.clone(), Value::from(value.as_str()));
+                }
+                for (metric, values) in line {
+                    let value = match values {
+                        [value] => json!(value),
+                        values => json!(values),
+                    };
+                    document.insert(metric.name.clone(), value);
+                }
+                let definitions: Vec<Value> = line
+                    .iter()
+                    .map(|(metric, _)| json!({ "Name": metric.name, "Unit": metric.unit }))
+                    .collect();
+                document.insert(
+                    "_aws".to_owned(),
+                    json!({
+                        "Timestamp": timestamp,
+                        "CloudWatchMetrics": [{
+                            "Namespace": namespace,
+                            "Dimensions": dimension_sets,
+                            "Metrics": definitions,
+                        }],
+                    }),
+                );
+                documents.push(Value::Object(document));
+            }
+        }
+        documents
+    }
+
+    /// Writes the metrics collected so far to `out`, and clears them. Nothing is written
+    /// when no metric was put.
+    pub(crate) fn flush<W: Write + ?Sized>(&self, out: &mut W, timestamp: SystemTime) -> io::Result<()> {
+        let documents = self.documents(timestamp);
+        self.lock().metrics.clear();
+        for document in documents {
+            let mut line = serde_json::to_vec(&document)?;
+            line.push(b'\n');
+            out.write_all(&line)?;
+        }
+        out.flush()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Collector> {
+        self.inner.lock().expect("Lock was poisoned when collecting metrics")
+    }
+}
+
+impl PartialEq for Metrics {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn timestamp() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_600_000_000_123)
+    }
+
+    #[test]
+    fn documents_follow_the_embedded_metric_format() {
+        let config = Config::builder().function_name("orders").build();
+        let metrics = Metrics::for_function(&config);
+        metrics
+            .namespace("Shop")
+            .dimension("Operation", "Create")
+            .property("RequestId", "52fdfc07")
+            .put("Items", 3.0, Unit::Count)
+            .put("Latency", 41.5, Unit::Milliseconds)
+            .put("Latency", 12.0, Unit::Seconds)
+            .put("Ratio", f64::NAN, Unit::Percent);
+
+        assert_eq!(
+            vec![json!({
+                "_aws": {
+                    "Timestamp": 1_600_000_000_123u64,
+                    "CloudWatchMetrics": [{
+                        "Namespace": "Shop",
+                        "Dimensions": [["FunctionName", "FunctionVersion", "Operation"]],
+                        "Metrics": [
+                            { "Name": "Items", "Unit": "Count" },
+                            { "Name": "Latency", "Unit": "Milliseconds" },
+                        ],
+                    }],
+                },
+                "FunctionName": "orders",
+                "FunctionVersion": config.version,
+                "Operation": "Create",
+                "RequestId": "52fdfc07",
+                "Items": 3.0,
+                "Latency": [41.5, 12.0],
+            })],
+            metrics.documents(timestamp())
+        );
+    }
+
+    #[test]
+    fn metrics_without_dimensions_have_no_dimension_sets() {
+        let metrics = Metrics::for_function(&Config::builder().build());
+        metrics.clear_dimensions().put("Hits", 1.0, Unit::Count);
+
+        let documents = metrics.documents(timestamp());
+        assert_eq!(json!([]), documents[0]["_aws"]["CloudWatchMetrics"][0]["Dimensions"]);
+        assert_eq!(
+            DEFAULT_NAMESPACE,
+            documents[0]["_aws"]["CloudWatchMetrics"][0]["Namespace"]
+        );
+    }
+
+    #[test]
+    fn lines_are_split_at_100_metrics() {
+        let metrics = Metrics::new();
+        for i in 0..250 {
+            metrics.put(format!("Metric{}", i), i as f64, Unit::Count);
+        }
+
+        let documents = metrics.documents(timestamp());
+        let sizes: Vec<usize> = documents
+            .iter()
+            .map(|document| {
+                document["_aws"]["CloudWatchMetrics"][0]["Metrics"]
+                    .as_array()
+                    .unwrap()
+                    .len()
+            })
+            .collect();
+        assert_eq!(vec![100, 100, 50], sizes);
+        assert_eq!(json!(249.0), documents[2]["Metric249"]);
+    }
+
+    #[test]
+    fn lines_are_split_at_100_values() {
+        let metrics = Metrics::new();
+        for i in 0..150 {
+            metrics.put("Latency", i as f64, Unit::Milliseconds);
+        }
+        metrics.put("Items", 1.0, Unit::Count);
+
+        let documents = metrics.documents(timestamp());
+        assert_eq!(2, documents.len());
+        assert_eq!(100, documents[0]["Latency"].as_array().unwrap().len());
+        assert_eq!(json!(1.0), documents[0]["Items"]);
+        assert_eq!(50, documents[1]["Latency"].as_array().unwrap().len());
+        assert!(documents[1].get("Items").is_none());
+    }
+
+    #[test]
+    fn flushing_writes_lines_and_clears_the_metrics() -> Result<(), crate::Error> {
+        let metrics = Metrics::new();
+        let mut out = Vec::new();
+        metrics.flush(&mut out, timestamp())?;
+        assert!(out.is_empty());
+
+        metrics.put("Hits", 1.0, Unit::Count).put("Misses", 2.0, Unit::Count);
+        metrics.flush(&mut out, timestamp())?;
+        let lines: Vec<Value> = out
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(1, lines.len());
+        assert_eq!(json!(2.0), lines[0]["Misses"]);
+        assert!(metrics.is_empty());
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod runtime_tests {
+    use super::Unit;
+    use crate::{
+        handler_fn,
+        testing::{MockEvent, MockRuntime},
+        Context, Error,
+    };
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn the_runtime_flushes_metrics_after_each_invocation() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime.push_event(MockEvent::new(&json!(2))?);
+        runtime.push_event(MockEvent::new(&json!(0))?);
+        runtime.push_event(MockEvent::new(&json!(-1))?);
+
+        runtime
+            .run(handler_fn(|items: i64, ctx: Context| async move {
+                if items > 0 {
+                    ctx.metrics().put("Items", items as f64, Unit::Count);
+                }
+                if items < 0 {
+                    ctx.metrics().put("Failures", 1.0, Unit::Count);
+                    return Err(Error::from("negative items"));
+                }
+                Ok::<_, Error>(Value::Null)
+            }))
+            .await?;
+
+        let metrics = runtime.metrics();
+        assert_eq!(2, metrics.len());
+        assert_eq!(json!(2.0), metrics[0]["Items"]);
+        assert_eq!(json!("mock-function"), metrics[0]["FunctionName"]);
+        assert_eq!(json!(1.0), metrics[1]["Failures"]);
+        assert!(metrics[1].get("Items").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+//! OpenTelemetry traces of the invocations, with the semantic conventions for FaaS.
+//!
+//! The runtime starts a root span for every invocation, named after the function, and runs
+//! the handler in its context, so the spans that the handler starts are its children. The
+//! root span has the attributes:
+//!
+//! - `faas.invocation_id`, the request id of the invocation.
+//! - `faas.coldstart`, whether the invocation is the first of the process.
+//! - `cloud.resource_id`, the ARN that the function was invoked with.
+//! - `faas.trigger`, what triggered the invocation. Handlers describe it with
+//!   [`Handler::trigger`], and it's `other` by default.
+//!
+//! The parent of the span is the trace that the trigger carries, such as the W3C
+//! `traceparent` header of the requests of `lamedh_http`. Otherwise it's the X-Ray trace
+//! of the invocation. Handler errors set the status of the span.
+//!
+//! Spans are started with the global tracer provider. The execution environment can be
+//! frozen as soon as Lambda has the result of an invocation, so a provider that batches
+//! its spans should be [`install`]ed, to be flushed before each result is sent:
+//!
+//! ```no_run
+//! use lamedh_runtime::{handler_fn, otel, Context, Error};
+//! use opentelemetry_sdk::trace::SdkTracerProvider;
+//! use serde_json::Value;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     # let exporter = opentelemetry_sdk::trace::InMemorySpanExporter::default();
+//!     otel::install(SdkTracerProvider::builder().with_batch_exporter(exporter).build());
+//!     lamedh_runtime::run(handler_fn(func)).await
+//! }
+//!
+//! async fn func(event: Value, _: Context) -> Result<Value, Error> {
+//!     Ok(event)
+//! }
+//! ```
+//!
+//! This module requires the `opentelemetry` feature.
+//!
+//! [`Handler::trigger`]: ../trait.Handler.html#method.trigger
+//! [`install`]: fn.install.html
+use crate::{types::Diagnostic, Context};
+use http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, TextMapPropagator},
+    trace::{SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId, TraceState, Tracer},
+    Context as TraceContext, KeyValue,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use std::sync::Mutex;
+use tracing::error;
+
+/// The provider flushed after each invocation, when one is installed.
+static INSTALLED: Mutex<Option<SdkTracerProvider>> = Mutex::new(None);
+
+/// Installs `provider` as the global tracer provider, and flushes it before the result
+/// of each invocation is sent. It applies to runtimes started afterwards.
+pub fn install(provider: SdkTracerProvider) {
+    global::set_tracer_provider(provider.clone());
+    *INSTALLED
+        .lock()
+        .expect("Lock was poisoned when installing a tracer provider") = Some(provider);
+}
+
+/// The kinds of triggers of the FaaS semantic conventions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TriggerKind {
+    /// A change in a data source, such as a DynamoDB stream or an S3 bucket.
+    Datasource,
+    /// An HTTP request, such as from API Gateway or an Application Load Balancer.
+    Http,
+    /// A message from a queue or a topic, such as SQS or SNS.
+    PubSub,
+    /// A schedule, such as an EventBridge rule.
+    Timer,
+    /// Anything else, or an unknown trigger.
+    #[default]
+    Other,
+}
+
+impl TriggerKind {
+    /// Returns the value of the `faas.trigger` attribute for this trigger.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TriggerKind::Datasource => "datasource",
+            TriggerKind::Http => "http",
+            TriggerKind::PubSub => "pubsub",
+            TriggerKind::Timer => "timer",
+            TriggerKind::Other => "other",
+        }
+    }
+
+    fn span_kind(self) -> SpanKind {
+        match self {
+            TriggerKind::PubSub => SpanKind::Consumer,
+            _ => SpanKind::Server,
+        }
+    }
+}
+
+/// What triggered an invocation, and the trace it carries.
+#[derive(Debug, Clone, Default)]
+pub struct Trigger {
+    kind: TriggerKind,
+    parent: Option<TraceContext>,
+}
+
+impl Trigger {
+    /// Creates a trigger of `kind`, that doesn't carry a trace.
+    pub fn new(kind: TriggerKind) -> Self {
+        Trigger { kind, parent: None }
+    }
+
+    /// Sets the trace that the invocation continues, instead of its X-Ray trace.
+    pub fn with_parent(mut self, parent: TraceContext) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    /// Returns the kind of the trigger.
+    pub fn kind(&self) -> TriggerKind {
+        self.kind
+    }
+
+    /// Returns the trace that the invocation continues, if the trigger carries one.
+    pub fn parent(&self) -> Option<&TraceContext> {
+        self.parent.as_ref()
+    }
+}
+
+/// Reads the trace of a request from its W3C `traceparent` and `tracestate` headers.
+///
+/// This returns `None` when the request doesn't carry a valid trace.
+pub fn parent_from_headers(headers: &HeaderMap) -> Option<TraceContext> {
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    if parent.span().span_context().is_valid() {
+        Some(parent)
+    } else {
+        None
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Reads the span context of an X-Ray trace header, such as
+/// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`.
+fn parent_from_xray(header: &str) -> Option<SpanContext> {
+    let (mut trace_id, mut span_id, mut flags) = (None, None, TraceFlags::default());
+    for field in header.split(';') {
+        match field.trim().split_once('=') {
+            Some(("Root", root)) => {
+                // The root is a version, then the epoch of the trace and a unique id,
+                // which are the two halves of the W3C trace id.
+                let mut parts = root.split('-');
+                if parts.next() != Some("1") {
+                    return None;
+                }
+                let (epoch, unique) = (parts.next()?, parts.next()?);
+                if epoch.len() != 8 || unique.len() != 24 || parts.next().is_some() {
+                    return None;
+                }
+                trace_id = TraceId::from_hex(&format!("{}{}", epoch, unique)).ok();
+            }
+            Some(("Parent", parent)) if parent.len() == 16 => span_id = SpanId::from_hex(parent).ok(),
+            Some(("Sampled", "1")) => flags = TraceFlags::SAMPLED,
+            _ => {}
+        }
+    }
+    let span = SpanContext::new(trace_id?, span_id?, flags, true, TraceState::default());
+    Some(span).filter(SpanContext::is_valid)
+}
+
+/// The root span of an invocation.
+pub(crate) struct Invocation {
+    cx: TraceContext,
+}
+
+impl Invocation {
+    /// Starts the span of the invocation described by `ctx`.
+    pub(crate) fn start(trigger: Trigger, ctx: &Context) -> Self {
+        let parent = trigger
+            .parent
+            .or_else(|| {
+                parent_from_xray(&ctx.xray_trace_id).map(|span| TraceContext::new().with_remote_span_context(span))
+            })
+            .unwrap_or_default();
+        let tracer = global::tracer(env!("CARGO_CRATE_NAME"));
+        let span = tracer
+            .span_builder(ctx.env_config.function_name.clone())
+            .with_kind(trigger.kind.span_kind())
+            .with_attributes(vec![
+                KeyValue::new("faas.invocation_id", ctx.request_id.clone()),
+                KeyValue::new("faas.coldstart", ctx.is_cold_start()),
+                KeyValue::new("cloud.resource_id", ctx.invoked_function_arn.clone()),
+                KeyValue::new("faas.trigger", trigger.kind.as_str()),
+            ])
+            .start_with_context(&tracer, &parent);
+        Invocation {
+            cx: parent.with_span(span),
+        }
+    }
+
+    /// Returns the context that the handler runs in.
+    pub(crate) fn context(&self) -> TraceContext {
+        self.cx.clone()
+    }
+
+    /// Ends the span with the result of the invocation, and flushes the installed provider.
+    pub(crate) async fn end<T>(self, request_id: &str, result: &Result<T, Diagnostic>) {
+        let span = self.cx.span();
+        if let Err(diagnostic) = result {
+            span.set_status(Status::error(diagnostic.error_message.clone()));
+        }
+        span.end();
+
+        let provider = INSTALLED
+            .lock()
+            .expect("Lock was poisoned when loading the tracer provider")
+            .clone();
+        if let Some(provider) = provider {
+            // Flushing waits on the exporter, so it runs off the runtime's threads.
+            match tokio::task::spawn_blocking(move || provider.force_flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!(request_id = %request_id, error = %e, "Unable to flush the spans of the invocation")
+                }
+                Err(e) => error!(request_id = %request_id, error = %e, "Unable to flush the spans of the invocation"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn xray_headers_are_span_contexts() {
+        let span = parent_from_xray("Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1")
+            .expect("the header has a valid span context");
+        assert_eq!("5759e988bd862e3fe1be46a994272793", span.trace_id().to_string());
+        assert_eq!("53995c3f42cd8ad8", span.span_id().to_string());
+        assert!(span.is_sampled());
+        assert!(span.is_remote());
+
+        let span = parent_from_xray("Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=0")
+            .expect("the header has a valid span context");
+        assert!(!span.is_sampled());
+    }
+
+    #[test]
+    fn incomplete_xray_headers_are_ignored() {
+        for header in &[
+            "",
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1",
+            "Parent=53995c3f42cd8ad8;Sampled=1",
+            "Root=2-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8",
+            "Root=1-5759e988bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8",
+            "Root=1-5759e988-bd862e3fe1be46a99427279z;Parent=53995c3f42cd8ad8",
+            "Root=1-00000000-000000000000000000000000;Parent=53995c3f42cd8ad8",
+        ] {
+            assert_eq!(None, parent_from_xray(header), "{}", header);
+        }
+    }
+
+    #[test]
+    fn traceparent_headers_are_parents() {
+        let mut headers = HeaderMap::new();
+        assert!(parent_from_headers(&headers).is_none());
+
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+        );
+        let parent = parent_from_headers(&headers).expect("the headers have a valid trace");
+        let span = parent.span().span_context().clone();
+        assert_eq!("0af7651916cd43dd8448eb211c80319c", span.trace_id().to_string());
+        assert_eq!("b7ad6b7169203331", span.span_id().to_string());
+        assert!(span.is_sampled());
+
+        headers.insert("traceparent", HeaderValue::from_static("00-invalid"));
+        assert!(parent_from_headers(&headers).is_none());
+    }
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod runtime_tests {
+    use super::*;
+    use crate::{
+        handler_fn,
+        testing::{MockEvent, MockRuntime},
+        Config, Error, Handler,
+    };
+    use opentelemetry::{trace::SpanKind, Value as AttributeValue};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SpanData};
+    use serde_json::{json, Value};
+    use std::future::Future;
+
+    const XRAY_TRACE_ID: &str = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+    fn attribute(span: &SpanData, key: &str) -> Option<AttributeValue> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.clone())
+    }
+
+    /// A handler for events triggered by HTTP requests that carry their own trace.
+    struct HttpHandler<F>(F);
+
+    impl<F, Fut> Handler<Value, Value> for HttpHandler<F>
+    where
+        F: Fn(Value, Context) -> Fut,
+        Fut: Future<Output = Result<Value, Error>>,
+    {
+        type Error = Error;
+        type Fut = Fut;
+        fn call(&mut self, event: Value, ctx: Context) -> Self::Fut {
+            (self.0)(event, ctx)
+        }
+
+        fn trigger(&self, event: &Value) -> Trigger {
+            let mut headers = HeaderMap::new();
+            if let Some(traceparent) = event["traceparent"].as_str() {
+                headers.insert(
+                    "traceparent",
+                    traceparent.parse().expect("the traceparent is a header value"),
+                );
+            }
+            let trigger = Trigger::new(TriggerKind::Http);
+            match parent_from_headers(&headers) {
+                Some(parent) => trigger.with_parent(parent),
+                None => trigger,
+            }
+        }
+    }
+
+    // The provider is global, so every scenario runs in the same test.
+    #[tokio::test]
+    async fn invocations_are_root_spans_flushed_before_their_result() -> Result<(), Error> {
+        let exporter = InMemorySpanExporter::default();
+        install(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter.clone())
+                .build(),
+        );
+
+        let config = Config::builder().function_name("orders").build();
+        let mut runtime = MockRuntime::with_config(config.clone());
+        runtime
+            .push_event(
+                MockEvent::new(&json!({}))?
+                    .request_id("first")
+                    .invoked_function_arn("arn:aws:lambda:us-east-1:123456789012:function:orders")
+                    .xray_trace_id(XRAY_TRACE_ID),
+            )
+            .push_event(MockEvent::new(&json!({ "fail": true }))?.request_id("second"));
+        let flushed = exporter.clone();
+        runtime
+            .run(handler_fn(move |event: Value, _: Context| {
+                let flushed = flushed.clone();
+                async move {
+                    let parent = TraceContext::current().span().span_context().span_id();
+                    let tracer = global::tracer("orders");
+                    tracer.in_span("query", |_| ());
+                    if event["fail"].as_bool().unwrap_or_default() {
+                        // The spans of the first invocation were flushed before its result.
+                        let spans = flushed.get_finished_spans()?;
+                        return Err(format!("{} spans were flushed, under {}", spans.len(), parent).into());
+                    }
+                    Ok::<_, Error>(json!(parent.to_string()))
+                }
+            }))
+            .await?;
+
+        let spans = exporter.get_finished_spans()?;
+        assert_eq!(4, spans.len());
+        let (query, root) = (&spans[0], &spans[1]);
+        assert_eq!("query", query.name);
+        assert_eq!(root.span_context.span_id(), query.parent_span_id);
+        assert_eq!("orders", root.name);
+        assert_eq!(SpanKind::Server, root.span_kind);
+        assert_eq!(
+            "5759e988bd862e3fe1be46a994272793",
+            root.span_context.trace_id().to_string()
+        );
+        assert_eq!("53995c3f42cd8ad8", root.parent_span_id.to_string());
+        assert_eq!(Some("first".into()), attribute(root, "faas.invocation_id"));
+        assert_eq!(Some(true.into()), attribute(root, "faas.coldstart"));
+        assert_eq!(
+            Some("arn:aws:lambda:us-east-1:123456789012:function:orders".into()),
+            attribute(root, "cloud.resource_id")
+        );
+        assert_eq!(Some("other".into()), attribute(root, "faas.trigger"));
+        assert_eq!(Status::Unset, root.status);
+        assert_eq!(
+            json!(root.span_context.span_id().to_string()),
+            runtime.responses()[0].json::<Value>()?
+        );
+
+        let root = &spans[3];
+        assert_eq!(Some(false.into()), attribute(root, "faas.coldstart"));
+        assert_eq!(SpanId::INVALID, root.parent_span_id);
+        assert_eq!(
+            Status::error(format!("2 spans were flushed, under {}", root.span_context.span_id())),
+            root.status
+        );
+
+        exporter.reset();
+        let mut runtime = MockRuntime::with_config(config);
+        runtime.push_event(MockEvent::new(
+            &json!({ "traceparent": "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01" }),
+        )?);
+        runtime
+            .run(HttpHandler(|_: Value, _: Context| async { Ok(json!({})) }))
+            .await?;
+
+        let spans = exporter.get_finished_spans()?;
+        assert_eq!(1, spans.len());
+        assert_eq!(
+            "0af7651916cd43dd8448eb211c80319c",
+            spans[0].span_context.trace_id().to_string()
+        );
+        assert_eq!("b7ad6b7169203331", spans[0].parent_span_id.to_string());
+        assert_eq!(Some("http".into()), attribute(&spans[0], "faas.trigger"));
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{
+    codec::{self, Codec},
+    Error,
+};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::ops::Deref;
+
+/// The raw body of an event or a response.
+///
+/// A handler that takes a `Payload` receives the body of the event as it was sent to the
+/// function, without going through serde. Events can then be deserialized with
+/// [`parse`](#method.parse) into types that borrow their strings from the body, instead
+/// of copying them, which matters for large batches:
+///
+/// ```
+/// use lamedh_runtime::{handler_fn, Context, Error, Payload};
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize)]
+/// struct Batch<'a> {
+///     #[serde(borrow, rename = "Records")]
+///     records: Vec<Message<'a>>,
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct Message<'a> {
+///     #[serde(rename = "messageId")]
+///     message_id: &'a str,
+/// }
+///
+/// async fn func(event: Payload, _: Context) -> Result<Vec<String>, Error> {
+///     let batch: Batch<'_> = event.parse()?;
+///     Ok(batch.records.iter().map(|message| message.message_id.to_owned()).collect())
+/// }
+/// # let _ = handler_fn(func);
+/// ```
+///
+/// A handler that returns a `Payload` sends it as the response as is, so pre-serialized
+/// responses skip serde too:
+///
+/// ```
+/// use lamedh_runtime::{handler_fn, Context, Error, Payload};
+///
+/// async fn func(event: Payload, _: Context) -> Result<Payload, Error> {
+///     Ok(event)
+/// }
+/// # let _ = handler_fn(func);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Payload {
+    bytes: Bytes,
+}
+
+impl Payload {
+    /// Creates a payload with the given bytes.
+    pub fn new<B: Into<Bytes>>(bytes: B) -> Self {
+        Payload { bytes: bytes.into() }
+    }
+
+    /// Deserializes the payload as JSON. The result can borrow from the payload.
+    ///
+    /// Errors report the path of the field that failed.
+    pub fn parse<'a, T: Deserialize<'a>>(&'a self) -> Result<T, Error> {
+        Ok(serde_path_to_error::deserialize(
+            &mut serde_json::Deserializer::from_slice(&self.bytes),
+        )?)
+    }
+
+    /// Returns the bytes of the payload.
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    /// Returns the bytes of the payload. This doesn't copy them.
+    pub fn into_bytes(self) -> Bytes {
+        self.bytes
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<Bytes> for Payload {
+    fn from(bytes: Bytes) -> Self {
+        Payload { bytes }
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(bytes: Vec<u8>) -> Self {
+        Payload::new(bytes)
+    }
+}
+
+impl From<String> for Payload {
+    fn from(s: String) -> Self {
+        Payload::new(s)
+    }
+}
+
+impl From<&'static [u8]> for Payload {
+    fn from(bytes: &'static [u8]) -> Self {
+        Payload::new(bytes)
+    }
+}
+
+impl From<&'static str> for Payload {
+    fn from(s: &'static str) -> Self {
+        Payload::new(s)
+    }
+}
+
+impl From<Payload> for Bytes {
+    fn from(payload: Payload) -> Self {
+        payload.bytes
+    }
+}
+
+/// An event that the runtime can pass to a handler.
+///
+/// This is implemented for every type that implements [`serde::Deserialize`], which is
+/// decoded with the [codec] of the runtime, and for [`Payload`], which receives the raw body.
+///
+/// [codec]: codec/index.html
+/// [`Payload`]: struct.Payload.html
+pub trait FromPayload: Sized {
+    /// Creates the event from the body of an invocation.
+    fn from_payload(payload: Payload, codec: &dyn Codec) -> Result<Self, Error>;
+}
+
+impl<T: DeserializeOwned> FromPayload for T {
+    fn from_payload(payload: Payload, codec: &dyn Codec) -> Result<Self, Error> {
+        codec::decode(codec, payload)
+    }
+}
+
+impl FromPayload for Payload {
+    fn from_payload(payload: Payload, _: &dyn Codec) -> Result<Self, Error> {
+        Ok(payload)
+    }
+}
+
+/// A response that the runtime can send back to Lambda.
+///
+/// This is implemented for every type that implements [`serde::Serialize`], which is
+/// encoded with the [codec] of the runtime, and for [`Payload`], which is sent as is.
+///
+/// [codec]: codec/index.html
+/// [`Payload`]: struct.Payload.html
+pub trait IntoPayload {
+    /// Converts the response into the body sent to Lambda.
+    fn into_payload(self, codec: &dyn Codec) -> Result<Payload, Error>;
+}
+
+impl<T: Serialize> IntoPayload for T {
+    fn into_payload(self, codec: &dyn Codec) -> Result<Payload, Error> {
+        codec::encode(codec, &self)
+    }
+}
+
+impl IntoPayload for Payload {
+    fn into_payload(self, _: &dyn Codec) -> Result<Payload, Error> {
+        Ok(self)
+    }
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod tests {
+    use super::*;
+    use crate::{handler_fn, testing::MockEvent, testing::MockRuntime, Context};
+    use serde_json::{json, Value};
+
+    #[derive(Deserialize)]
+    struct Borrowed<'a> {
+        name: &'a str,
+        #[serde(borrow)]
+        tags: Vec<&'a str>,
+    }
+
+    #[test]
+    fn parse_borrows_from_the_payload() -> Result<(), Error> {
+        let payload = Payload::from(r#"{"name": "ferris", "tags": ["crab", "rust"]}"#);
+        let event: Borrowed<'_> = payload.parse()?;
+
+        assert_eq!("ferris", event.name);
+        assert_eq!(vec!["crab", "rust"], event.tags);
+        assert!(payload.as_bytes().as_ptr_range().contains(&event.name.as_ptr()));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors_report_the_path() {
+        let payload = Payload::from(r#"{"name": "ferris", "tags": [1]}"#);
+        let err = payload.parse::<Borrowed<'_>>().err().unwrap();
+        assert!(err.to_string().starts_with("tags[0]"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn payload_handlers_skip_serde() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime.push_event(MockEvent::from_slice(&b"not json"[..]));
+        runtime.push_event(MockEvent::from_slice(&b"[1, 2]"[..]));
+
+        runtime
+            .run(handler_fn(|event: Payload, _: Context| async move {
+                let mut body = event.into_bytes().to_vec();
+                body.reverse();
+                Ok::<_, Error>(Payload::from(body))
+            }))
+            .await?;
+
+        let responses = runtime.responses();
+        assert_eq!(b"nosj ton", &responses[0].body[..]);
+        assert_eq!(b"]2 ,1[", &responses[1].body[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn borrowed_events_run_through_the_runtime() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime.push_event(MockEvent::new(&json!({ "name": "ferris", "tags": ["crab"] }))?);
+
+        runtime
+            .run(handler_fn(|event: Payload, _: Context| async move {
+                let event: Borrowed<'_> = event.parse()?;
+                Ok::<_, Error>(json!({ "greeting": format!("Hello, {}!", event.name), "tags": event.tags }))
+            }))
+            .await?;
+
+        let response: Value = runtime.responses()[0].json()?;
+        assert_eq!(json!({ "greeting": "Hello, ferris!", "tags": ["crab"] }), response);
+        Ok(())
+    }
+}
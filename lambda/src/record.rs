@@ -0,0 +1,667 @@
+//! Recording of invocations, to replay production traffic against a handler.
+//!
+//! A [`Recorder`] samples the invocations that the runtime processes, and writes each one
+//! as a line of JSON: the raw event, the headers that the Runtime API sent along with it,
+//! the response or the error of the handler, and the time the handler took. Recording is
+//! opt-in. Set the `LAMEDH_RECORD` environment variable to the path of a file, or `-` for
+//! stdout, or [`install`] a recorder before starting the runtime. `LAMEDH_RECORD_SAMPLE_RATE`
+//! sets the fraction of invocations that are recorded, between `0` and `1`.
+//!
+//! Records are written after the redaction hook of the recorder runs, so secrets and
+//! personal data can be removed before they leave the function:
+//!
+//! ```no_run
+//! use lamedh_runtime::{handler_fn, record::{self, Recorder}, Context, Error};
+//! use serde_json::Value;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     let recorder = Recorder::to_file("/tmp/invocations.jsonl")?
+//!         .sample_rate(0.1)
+//!         .redact(|record| {
+//!             if let Some(password) = record.event.pointer_mut("/password") {
+//!                 *password = Value::from("<redacted>");
+//!             }
+//!         });
+//!     record::install(recorder);
+//!     lamedh_runtime::run(handler_fn(func)).await
+//! }
+//!
+//! async fn func(event: Value, _: Context) -> Result<Value, Error> {
+//!     Ok(event)
+//! }
+//! ```
+//!
+//! With the `simulated` feature, [`replay`] feeds records back to a handler through the
+//! same code path as the runtime, over an in-memory Runtime API, and compares the outcomes
+//! with the recorded ones.
+//!
+//! [`Recorder`]: struct.Recorder.html
+//! [`install`]: fn.install.html
+//! [`replay`]: fn.replay.html
+use crate::{types::Diagnostic, Error, Payload};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    fs::OpenOptions,
+    io::{self, BufRead, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::error;
+
+/// The environment variable that enables recording, with the path of the records,
+/// or `-` for stdout.
+pub const RECORD_ENV_VAR: &str = "LAMEDH_RECORD";
+
+/// The environment variable with the fraction of invocations to record.
+pub const SAMPLE_RATE_ENV_VAR: &str = "LAMEDH_RECORD_SAMPLE_RATE";
+
+/// The recorder used by the runtime, when one is installed.
+static INSTALLED: Mutex<Option<Arc<Recorder>>> = Mutex::new(None);
+
+/// A recorded invocation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    /// The request id of the invocation.
+    pub request_id: String,
+    /// When the invocation started, in Unix time milliseconds.
+    pub timestamp: u64,
+    /// How long the handler took, in milliseconds.
+    pub duration_ms: f64,
+    /// The headers that the Runtime API sent with the event, like the deadline and the
+    /// X-Ray trace id.
+    pub headers: BTreeMap<String, String>,
+    /// The event. Events that aren't valid JSON are recorded as a base64 string, and
+    /// [`event_encoding`] is set.
+    ///
+    /// [`event_encoding`]: #structfield.event_encoding
+    pub event: Value,
+    /// How the event is encoded, when it isn't JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_encoding: Option<Encoding>,
+    /// The response or the error of the handler.
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl Record {
+    pub(crate) fn new(headers: &HeaderMap, event: &[u8], outcome: Outcome, duration: Duration) -> Self {
+        let headers: BTreeMap<String, String> = headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned())))
+            .collect();
+        let timestamp = SystemTime::now()
+            .checked_sub(duration)
+            .and_then(|start| start.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_millis() as u64);
+        let (event, event_encoding) = match serde_json::from_slice(event) {
+            Ok(event) => (event, None),
+            Err(_) => (Value::String(base64::encode(event)), Some(Encoding::Base64)),
+        };
+        Record {
+            request_id: headers
+                .get("lambda-runtime-aws-request-id")
+                .cloned()
+                .unwrap_or_default(),
+            timestamp,
+            duration_ms: duration.as_secs_f64() * 1000.0,
+            headers,
+            event,
+            event_encoding,
+            outcome,
+        }
+    }
+
+    /// The body of the event, as the Runtime API sent it.
+    pub fn event_body(&self) -> Result<Vec<u8>, Error> {
+        match (self.event_encoding, &self.event) {
+            (None, event) => Ok(serde_json::to_vec(event)?),
+            (Some(Encoding::Base64), Value::String(event)) => Ok(base64::decode(event)?),
+            (Some(Encoding::Base64), _) => Err("Base64 encoded events must be strings".into()),
+        }
+    }
+
+    /// Reads the records written by a [`Recorder`], one per line. Empty lines are skipped.
+    ///
+    /// [`Recorder`]: struct.Recorder.html
+    pub fn read_all(reader: impl BufRead) -> Result<Vec<Record>, Error> {
+        let mut records = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record =
+                serde_json::from_str(&line).map_err(|e| format!("Invalid record on line {}: {}", number + 1, e))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Reads the records in the file at `path`, like [`read_all`] does.
+    ///
+    /// [`read_all`]: #method.read_all
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Vec<Record>, Error> {
+        let file = std::fs::File::open(path)?;
+        Self::read_all(io::BufReader::new(file))
+    }
+}
+
+/// The encoding of an event that isn't JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Encoding {
+    /// The raw body, encoded as standard base64.
+    Base64,
+}
+
+/// The outcome of an invocation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Outcome {
+    /// The handler returned a response.
+    Response(Value),
+    /// The handler returned an error, reported with this type and message.
+    #[serde(rename_all = "camelCase")]
+    Error {
+        /// The type of the error.
+        error_type: String,
+        /// The error message.
+        error_message: String,
+    },
+}
+
+impl Outcome {
+    pub(crate) fn new(result: &Result<Payload, Diagnostic>) -> Self {
+        match result {
+            Ok(response) => Outcome::Response(json_or_string(response)),
+            Err(diagnostic) => Outcome::Error {
+                error_type: diagnostic.error_type.clone(),
+                error_message: diagnostic.error_message.clone(),
+            },
+        }
+    }
+}
+
+/// Parses a response as JSON, or keeps it as a string when it isn't JSON, like the
+/// response of a handler that returns a raw [`Payload`].
+///
+/// [`Payload`]: ../struct.Payload.html
+fn json_or_string(body: &[u8]) -> Value {
+    serde_json::from_slice(body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+type Redactor = Box<dyn Fn(&mut Record) + Send + Sync>;
+
+/// Writes a sample of the invocations that the runtime processes, as JSON lines.
+pub struct Recorder {
+    sink: Mutex<Box<dyn Write + Send>>,
+    sample_rate: f64,
+    redact: Option<Redactor>,
+    seen: AtomicU64,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("sample_rate", &self.sample_rate)
+            .field("redact", &self.redact.is_some())
+            .finish()
+    }
+}
+
+impl Recorder {
+    /// Creates a recorder that writes every invocation to `sink`.
+    pub fn new(sink: impl Write + Send + 'static) -> Self {
+        Recorder {
+            sink: Mutex::new(Box::new(sink)),
+            sample_rate: 1.0,
+            redact: None,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates a recorder that writes to stdout, so records end up in the function's logs.
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+
+    /// Creates a recorder that appends to the file at `path`, creating it if needed.
+    pub fn to_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Unable to open records at {}: {}", path.display(), e))?;
+        Ok(Self::new(file))
+    }
+
+    /// Creates the recorder configured by the `LAMEDH_RECORD` and `LAMEDH_RECORD_SAMPLE_RATE`
+    /// environment variables, if recording is enabled.
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        let path = match env::var(RECORD_ENV_VAR) {
+            Ok(path) if !path.is_empty() => path,
+            _ => return Ok(None),
+        };
+        let recorder = if path == "-" {
+            Self::stdout()
+        } else {
+            Self::to_file(&path)?
+        };
+        match env::var(SAMPLE_RATE_ENV_VAR) {
+            Ok(rate) => {
+                let rate = rate
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", SAMPLE_RATE_ENV_VAR, rate))?;
+                Ok(Some(recorder.sample_rate(rate)))
+            }
+            Err(_) => Ok(Some(recorder)),
+        }
+    }
+
+    /// Sets the fraction of invocations that are recorded, between `0` and `1`.
+    /// Sampling is deterministic: a rate of `0.25` records one invocation out of four.
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets a hook that runs on every record before it's written, to redact sensitive data.
+    pub fn redact(mut self, redact: impl Fn(&mut Record) + Send + Sync + 'static) -> Self {
+        self.redact = Some(Box::new(redact));
+        self
+    }
+
+    /// Returns whether the next invocation should be recorded.
+    pub(crate) fn sample(&self) -> bool {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * self.sample_rate).floor() > (seen * self.sample_rate).floor()
+    }
+
+    /// Redacts and writes a record. Failures are logged, so recording never fails an invocation.
+    pub(crate) fn record(&self, mut record: Record) {
+        if let Some(redact) = &self.redact {
+            redact(&mut record);
+        }
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(request_id = %record.request_id, "Unable to serialize the record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut sink = self.sink.lock().expect("Lock was poisoned when writing a record");
+        if let Err(e) = sink.write_all(&line).and_then(|_| sink.flush()) {
+            error!(request_id = %record.request_id, "Unable to write the record: {}", e);
+        }
+    }
+}
+
+/// Installs the recorder that the runtime uses, instead of the one configured by the
+/// environment. It applies to runtimes started afterwards.
+pub fn install(recorder: Recorder) {
+    *INSTALLED.lock().expect("Lock was poisoned when installing a recorder") = Some(Arc::new(recorder));
+}
+
+/// Returns the installed recorder, or the one configured by the environment.
+pub(crate) fn installed() -> Result<Option<Arc<Recorder>>, Error> {
+    let mut installed = INSTALLED.lock().expect("Lock was poisoned when loading a recorder");
+    if installed.is_none() {
+        *installed = Recorder::from_env()?.map(Arc::new);
+    }
+    Ok(installed.clone())
+}
+
+/// The outcome of a recorded invocation, next to the outcome of its replay.
+#[cfg(feature = "simulated")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    /// The request id of the invocation.
+    pub request_id: String,
+    /// The recorded outcome.
+    pub recorded: Outcome,
+    /// The outcome of the replay.
+    pub replayed: Outcome,
+}
+
+#[cfg(feature = "simulated")]
+impl Replay {
+    /// Returns whether the replay had the recorded outcome.
+    pub fn is_match(&self) -> bool {
+        self.recorded == self.replayed
+    }
+
+    /// Returns the differences between the recorded and the replayed outcomes.
+    pub fn diff(&self) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        let recorded = serde_json::to_value(&self.recorded).unwrap_or_default();
+        let replayed = serde_json::to_value(&self.replayed).unwrap_or_default();
+        diff_values(String::new(), Some(&recorded), Some(&replayed), &mut differences);
+        differences
+    }
+}
+
+/// A value that differs between a recorded and a replayed outcome.
+#[cfg(feature = "simulated")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// The JSON pointer of the value, like `/response/items/0`.
+    pub path: String,
+    /// The recorded value, if there was one.
+    pub recorded: Option<Value>,
+    /// The replayed value, if there was one.
+    pub replayed: Option<Value>,
+}
+
+#[cfg(feature = "simulated")]
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<Value>| value.as_ref().map_or("<missing>".to_owned(), Value::to_string);
+        write!(f, "{}: {} != {}", self.path, show(&self.recorded), show(&self.replayed))
+    }
+}
+
+#[cfg(feature = "simulated")]
+fn diff_values(path: String, recorded: Option<&Value>, replayed: Option<&Value>, out: &mut Vec<Difference>) {
+    match (recorded, replayed) {
+        (Some(Value::Object(recorded)), Some(Value::Object(replayed))) => {
+            let mut keys: Vec<&String> = recorded.keys().chain(replayed.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                diff_values(path, recorded.get(key), replayed.get(key), out);
+            }
+        }
+        (Some(Value::Array(recorded)), Some(Value::Array(replayed))) => {
+            for i in 0..recorded.len().max(replayed.len()) {
+                diff_values(format!("{}/{}", path, i), recorded.get(i), replayed.get(i), out);
+            }
+        }
+        (recorded, replayed) if recorded != replayed => out.push(Difference {
+            path,
+            recorded: recorded.cloned(),
+            replayed: replayed.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+/// Replays `records` against `handler`, and returns the recorded and replayed outcome of
+/// each one, in order.
+///
+/// Events are served by a [`MockRuntime`], so they go through the same code path as they
+/// do in Lambda, with the request id, function ARN and X-Ray trace id they were recorded
+/// with, and the time they had left before their deadline. Records are replayed as they
+/// were written, so redacted values are replayed redacted.
+///
+/// # Example
+/// ```
+/// use lamedh_runtime::{handler_fn, record::{self, Record}, Context, Error};
+/// use serde_json::Value;
+///
+/// async fn func(event: Value, _: Context) -> Result<Value, Error> {
+///     Ok(event)
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Error> {
+///     let records = Record::read_all(&br#"{"requestId":"1","timestamp":0,"durationMs":1.5,"headers":{},"event":{"a":1},"response":{"a":1}}"#[..])?;
+///     for replay in record::replay(handler_fn(func), &records).await? {
+///         for difference in replay.diff() {
+///             println!("{}: {}", replay.request_id, difference);
+///         }
+///         assert!(replay.is_match());
+///     }
+///     Ok(())
+/// }
+/// ```
+///
+/// [`MockRuntime`]: ../testing/struct.MockRuntime.html
+#[cfg(feature = "simulated")]
+pub async fn replay<A, B, F>(handler: F, records: &[Record]) -> Result<Vec<Replay>, Error>
+where
+    F: crate::Handler<A, B>,
+    <F as crate::Handler<A, B>>::Error: fmt::Display,
+    A: crate::FromPayload,
+    B: crate::IntoPayload,
+{
+    use crate::testing::{MockEvent, MockRuntime};
+    use std::collections::{HashMap, HashSet};
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let mut runtime = MockRuntime::new();
+    let mut request_ids = Vec::with_capacity(records.len());
+    let mut seen = HashSet::new();
+    for (i, record) in records.iter().enumerate() {
+        // Request ids identify the outcome of each replay, so records captured
+        // more than once get a unique one.
+        let request_id = if seen.insert(record.request_id.as_str()) {
+            record.request_id.clone()
+        } else {
+            format!("{}-{}", record.request_id, i)
+        };
+        let mut event = MockEvent::from_slice(record.event_body()?).request_id(request_id.as_str());
+        if let Some(arn) = record.headers.get("lambda-runtime-invoked-function-arn") {
+            event = event.invoked_function_arn(arn.as_str());
+        }
+        if let Some(trace_id) = record.headers.get("lambda-runtime-trace-id") {
+            event = event.xray_trace_id(trace_id.as_str());
+        }
+        if let Some(deadline) = record
+            .headers
+            .get("lambda-runtime-deadline-ms")
+            .and_then(|deadline| deadline.parse::<u64>().ok())
+        {
+            event = event.deadline(now + deadline.saturating_sub(record.timestamp));
+        }
+        runtime.push_event(event);
+        request_ids.push(request_id);
+    }
+    runtime.run(handler).await?;
+
+    let mut outcomes: HashMap<String, Outcome> = HashMap::new();
+    for response in runtime.responses() {
+        outcomes.insert(
+            response.request_id.clone(),
+            Outcome::Response(json_or_string(&response.body)),
+        );
+    }
+    for error in runtime.errors() {
+        outcomes.insert(
+            error.request_id,
+            Outcome::Error {
+                error_type: error.error_type,
+                error_message: error.error_message,
+            },
+        );
+    }
+
+    records
+        .iter()
+        .zip(request_ids)
+        .map(|(record, request_id)| {
+            let replayed = outcomes
+                .remove(&request_id)
+                .ok_or_else(|| format!("Invocation {} wasn't replayed", request_id))?;
+            Ok(Replay {
+                request_id: record.request_id.clone(),
+                recorded: record.outcome.clone(),
+                replayed,
+            })
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod tests {
+    use super::*;
+    use crate::{
+        handler_fn,
+        testing::{MockEvent, MockRuntime},
+        Context,
+    };
+    use serde_json::json;
+
+    /// A sink that tests can read back.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn records(&self) -> Vec<Record> {
+            Record::read_all(&self.0.lock().unwrap()[..]).unwrap()
+        }
+    }
+
+    async fn greet(event: Value, _: Context) -> Result<Value, Error> {
+        match event["name"].as_str() {
+            Some(name) => Ok(json!({ "message": format!("Hello, {}!", name) })),
+            None => Err("missing name".into()),
+        }
+    }
+
+    async fn record(events: Vec<Value>, recorder: Recorder) -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime.recorder(recorder);
+        for event in events {
+            runtime.push_event(MockEvent::new(&event)?.xray_trace_id("Root=1-5759e988-bd862e3fe1be46a994272793"));
+        }
+        runtime.run(handler_fn(greet)).await
+    }
+
+    #[test]
+    fn samples_invocations() {
+        let recorder = Recorder::new(io::sink()).sample_rate(0.25);
+        let sampled: Vec<bool> = (0..8).map(|_| recorder.sample()).collect();
+        assert_eq!(vec![false, false, false, true, false, false, false, true], sampled);
+
+        let recorder = Recorder::new(io::sink()).sample_rate(0.0);
+        assert!((0..8).all(|_| !recorder.sample()));
+    }
+
+    #[tokio::test]
+    async fn records_responses_and_errors() -> Result<(), Error> {
+        let buffer = Buffer::default();
+        record(
+            vec![json!({ "name": "Ferris" }), json!({})],
+            Recorder::new(buffer.clone()),
+        )
+        .await?;
+
+        let records = buffer.records();
+        assert_eq!(2, records.len());
+        assert_eq!("mock-request-1", records[0].request_id);
+        assert_eq!(json!({ "name": "Ferris" }), records[0].event);
+        assert_eq!(
+            Outcome::Response(json!({ "message": "Hello, Ferris!" })),
+            records[0].outcome
+        );
+        assert_eq!(
+            "Root=1-5759e988-bd862e3fe1be46a994272793",
+            records[0].headers["lambda-runtime-trace-id"]
+        );
+        assert!(records[0].headers.contains_key("lambda-runtime-deadline-ms"));
+        match &records[1].outcome {
+            Outcome::Error { error_message, .. } => assert_eq!("missing name", error_message),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redacts_records() -> Result<(), Error> {
+        let buffer = Buffer::default();
+        let recorder = Recorder::new(buffer.clone()).redact(|record| {
+            if let Some(name) = record.event.pointer_mut("/name") {
+                *name = Value::from("<redacted>");
+            }
+        });
+        record(vec![json!({ "name": "Ferris" })], recorder).await?;
+
+        assert_eq!(json!({ "name": "<redacted>" }), buffer.records()[0].event);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replays_records() -> Result<(), Error> {
+        let buffer = Buffer::default();
+        record(
+            vec![json!({ "name": "Ferris" }), json!({})],
+            Recorder::new(buffer.clone()),
+        )
+        .await?;
+        let records = buffer.records();
+
+        let replays = replay(handler_fn(greet), &records).await?;
+        assert!(replays.iter().all(Replay::is_match));
+
+        let changed = handler_fn(|event: Value, _: Context| async move {
+            Ok::<_, Error>(json!({ "message": format!("Hi, {}!", event["name"].as_str().unwrap_or("stranger")) }))
+        });
+        let replays = replay(changed, &records).await?;
+        assert_eq!(
+            vec![Difference {
+                path: "/response/message".to_owned(),
+                recorded: Some(json!("Hello, Ferris!")),
+                replayed: Some(json!("Hi, Ferris!")),
+            }],
+            replays[0].diff()
+        );
+        let differences: Vec<String> = replays[1].diff().iter().map(|d| d.path.clone()).collect();
+        assert_eq!(vec!["/error", "/response"], differences);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replays_raw_events() -> Result<(), Error> {
+        let echo = |event: Payload, _: Context| async move { Ok::<_, Error>(event) };
+        let buffer = Buffer::default();
+        let mut runtime = MockRuntime::new();
+        runtime.recorder(Recorder::new(buffer.clone()));
+        runtime.push_event(MockEvent::from_slice(&b"name=Ferris\xff"[..]));
+        runtime.run(handler_fn(echo)).await?;
+
+        let records = buffer.records();
+        assert_eq!(Some(Encoding::Base64), records[0].event_encoding);
+        assert_eq!(b"name=Ferris\xff".to_vec(), records[0].event_body()?);
+
+        let replays = replay(handler_fn(echo), &records).await?;
+        assert!(replays[0].is_match());
+        Ok(())
+    }
+
+    #[test]
+    fn diffs_nested_values() {
+        let mut differences = Vec::new();
+        diff_values(
+            String::new(),
+            Some(&json!({ "a/b": [1, 2], "c": { "d": true } })),
+            Some(&json!({ "a/b": [1], "c": { "d": true }, "e": null })),
+            &mut differences,
+        );
+        let paths: Vec<String> = differences.iter().map(ToString::to_string).collect();
+        assert_eq!(vec!["/a~1b/1: 2 != <missing>", "/e: <missing> != null"], paths);
+    }
+}
@@ -1,15 +1,15 @@
-use crate::{types::Diagnostic, Error};
-use http::{Method, Request, Response, Uri};
+use crate::{types::Diagnostic, Error, Payload};
+use http::{Method, Request, Uri};
 use hyper::Body;
-use serde::Serialize;
 use std::str::FromStr;
 
 pub(crate) trait IntoRequest {
     fn into_req(self) -> Result<Request<Body>, Error>;
 }
 
+#[cfg(any(test, feature = "simulated"))]
 pub(crate) trait IntoResponse {
-    fn into_rsp(self) -> Result<Response<Body>, Error>;
+    fn into_rsp(self) -> Result<http::Response<Body>, Error>;
 }
 
 // /runtime/invocation/next
@@ -26,6 +26,7 @@ impl IntoRequest for NextEventRequest {
     }
 }
 
+#[cfg(any(test, feature = "simulated"))]
 #[derive(Debug, PartialEq)]
 pub struct NextEventResponse<'a> {
     // lambda-runtime-aws-request-id
@@ -40,9 +41,10 @@ pub struct NextEventResponse<'a> {
     pub body: Vec<u8>,
 }
 
+#[cfg(any(test, feature = "simulated"))]
 impl<'a> IntoResponse for NextEventResponse<'a> {
-    fn into_rsp(self) -> Result<Response<Body>, Error> {
-        let rsp = Response::builder()
+    fn into_rsp(self) -> Result<http::Response<Body>, Error> {
+        let rsp = http::Response::builder()
             .header("lambda-runtime-aws-request-id", self.request_id)
             .header("lambda-runtime-deadline-ms", self.deadline)
             .header("lambda-runtime-invoked-function-arn", self.arn)
@@ -60,20 +62,16 @@ fn test_next_event_request() {
 }
 
 // /runtime/invocation/{AwsRequestId}/response
-pub(crate) struct EventCompletionRequest<'a, T> {
+pub(crate) struct EventCompletionRequest<'a> {
     pub(crate) request_id: &'a str,
-    pub(crate) body: T,
+    pub(crate) body: Payload,
 }
 
-impl<'a, T> IntoRequest for EventCompletionRequest<'a, T>
-where
-    T: for<'serialize> Serialize,
-{
+impl<'a> IntoRequest for EventCompletionRequest<'a> {
     fn into_req(self) -> Result<Request<Body>, Error> {
         let uri = format!("/2018-06-01/runtime/invocation/{}/response", self.request_id);
         let uri = Uri::from_str(&uri)?;
-        let body = serde_json::to_vec(&self.body)?;
-        let body = Body::from(body);
+        let body = Body::from(self.body.into_bytes());
 
         let req = Request::builder().method(Method::POST).uri(uri).body(body)?;
         Ok(req)
@@ -84,7 +82,7 @@ where
 fn test_event_completion_request() {
     let req = EventCompletionRequest {
         request_id: "id",
-        body: "hello, world!",
+        body: Payload::from(r#""hello, world!""#),
     };
     let req = req.into_req().unwrap();
     let expected = Uri::from_static("/2018-06-01/runtime/invocation/id/response");
@@ -130,25 +128,34 @@ fn test_event_error_request() {
 }
 
 // /runtime/init/error
-struct InitErrorRequest;
+pub(crate) struct InitErrorRequest {
+    pub(crate) diagnostic: Diagnostic,
+}
 
 impl IntoRequest for InitErrorRequest {
     fn into_req(self) -> Result<Request<Body>, Error> {
         let uri = "/2018-06-01/runtime/init/error".to_string();
         let uri = Uri::from_str(&uri)?;
+        let body = serde_json::to_vec(&self.diagnostic)?;
+        let body = Body::from(body);
 
         let req = Request::builder()
             .method(Method::POST)
             .uri(uri)
             .header("lambda-runtime-function-error-type", "unhandled")
-            .body(Body::empty())?;
+            .body(body)?;
         Ok(req)
     }
 }
 
 #[test]
 fn test_init_error_request() {
-    let req = InitErrorRequest;
+    let req = InitErrorRequest {
+        diagnostic: Diagnostic {
+            error_type: "InitError".to_string(),
+            error_message: "Unable to initialize".to_string(),
+        },
+    };
     let req = req.into_req().unwrap();
     let expected = Uri::from_static("/2018-06-01/runtime/init/error");
     assert_eq!(req.method(), Method::POST);
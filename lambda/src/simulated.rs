@@ -1,16 +1,21 @@
-use http::Uri;
-use hyper::client::connect::Connection;
 use std::{
     cmp::min,
     collections::VecDeque,
+    fmt,
     future::Future,
-    io::Result as IoResult,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
 };
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tower_service::Service;
 
 /// Creates a pair of `AsyncRead`/`AsyncWrite` data streams, where the write end of each member of the pair
 /// is the read end of the other member of the pair.  This allows us to emulate the behavior of a TcpStream
@@ -22,26 +27,62 @@ pub(crate) fn chan() -> (SimStream, SimStream) {
     let two = Arc::new(Mutex::new(BufferState::new()));
 
     // Use buf1 for the read-side of left, use buf2 for the write-side of left
-    let left = SimStream {
-        read: ReadHalf { buffer: one.clone() },
-        write: WriteHalf { buffer: two.clone() },
-    };
+    let left = SimStream::new(ReadHalf::new(one.clone()), WriteHalf::new(two.clone()));
 
     // Now swap the buffers for right
-    let right = SimStream {
-        read: ReadHalf { buffer: two },
-        write: WriteHalf { buffer: one },
-    };
+    let right = SimStream::new(ReadHalf::new(two), WriteHalf::new(one));
 
     (left, right)
 }
 
+/// Failures to inject in one end of an in-memory connection.
+///
+/// Faults apply to the end of the connection they are set on: delays slow down
+/// that end's reads and writes, and `close_after` closes the connection once that
+/// end has written a number of bytes, which truncates whatever it was sending.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    read_delay: Option<Duration>,
+    write_delay: Option<Duration>,
+    close_after: Option<usize>,
+}
+
+impl Faults {
+    /// Creates a set of faults that doesn't inject any failure.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delays every read by `delay`.
+    pub fn read_delay(mut self, delay: Duration) -> Self {
+        self.read_delay = Some(delay);
+        self
+    }
+
+    /// Delays every write by `delay`.
+    pub fn write_delay(mut self, delay: Duration) -> Self {
+        self.write_delay = Some(delay);
+        self
+    }
+
+    /// Closes the connection after `bytes` bytes have been written. Writes that
+    /// cross the limit are truncated, and later writes fail.
+    pub fn close_after(mut self, bytes: usize) -> Self {
+        self.close_after = Some(bytes);
+        self
+    }
+}
+
+/// Connects hyper's client to an in-memory connection.
+#[cfg(feature = "hyper-client")]
+#[cfg_attr(feature = "minimal-client", allow(dead_code))]
 #[derive(Clone)]
 pub struct Connector {
     pub inner: SimStream,
 }
 
-impl Service<Uri> for Connector {
+#[cfg(feature = "hyper-client")]
+impl tower_service::Service<http::Uri> for Connector {
     type Response = SimStream;
     type Error = std::io::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
@@ -50,13 +91,14 @@ impl Service<Uri> for Connector {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: Uri) -> Self::Future {
+    fn call(&mut self, _: http::Uri) -> Self::Future {
         let inner = self.inner.clone();
         Box::pin(async move { Ok(inner) })
     }
 }
 
-impl Connection for SimStream {
+#[cfg(feature = "hyper-client")]
+impl hyper::client::connect::Connection for SimStream {
     fn connected(&self) -> hyper::client::connect::Connected {
         hyper::client::connect::Connected::new()
     }
@@ -65,17 +107,67 @@ impl Connection for SimStream {
 /// A struct that implements AsyncRead + AsyncWrite (similarly to TcpStream) using in-memory
 /// bytes only.  Unfortunately tokio does not provide an operation that is the opposite of
 /// `tokio::io::split`, as that would negate the need for this struct.
-// TODO: Implement the ability to explicitly close a connection
 #[derive(Debug, Clone)]
 pub struct SimStream {
     read: ReadHalf,
     write: WriteHalf,
+    faults: Faults,
+    // Shared between clones, since they all write to the same connection.
+    written: Arc<AtomicUsize>,
+}
+
+impl SimStream {
+    fn new(read: ReadHalf, write: WriteHalf) -> Self {
+        SimStream {
+            read,
+            write,
+            faults: Faults::default(),
+            written: Arc::default(),
+        }
+    }
+
+    /// Sets the failures to inject in this end of the connection.
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.read.delay = faults.read_delay.map(Delay::new);
+        self.write.delay = faults.write_delay.map(Delay::new);
+        self.faults = faults;
+    }
+
+    /// Closes both directions of the connection. Pending and future reads on
+    /// either end see the end of the stream once buffered data is consumed,
+    /// and writes fail.
+    pub fn close(&self) {
+        for buffer in &[&self.read.buffer, &self.write.buffer] {
+            buffer
+                .lock()
+                .expect("Lock was poisoned when acquiring buffer lock for SimStream")
+                .close();
+        }
+    }
 }
 
 /// Delegates to the underlying `write` member's methods
 impl AsyncWrite for SimStream {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
-        Pin::new(&mut self.write).poll_write(cx, buf)
+        let limit = match self.faults.close_after {
+            Some(limit) => limit,
+            None => return Pin::new(&mut self.write).poll_write(cx, buf),
+        };
+
+        let remaining = limit.saturating_sub(self.written.load(Ordering::SeqCst));
+        if remaining == 0 {
+            self.close();
+            return Poll::Ready(Err(IoError::new(ErrorKind::BrokenPipe, "Connection closed")));
+        }
+
+        let buf = &buf[..min(buf.len(), remaining)];
+        let res = Pin::new(&mut self.write).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            if self.written.fetch_add(written, Ordering::SeqCst) + written >= limit {
+                self.close();
+            }
+        }
+        res
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
@@ -101,6 +193,7 @@ impl AsyncRead for SimStream {
 pub struct BufferState {
     buffer: VecDeque<u8>,
     read_waker: Option<Waker>,
+    closed: bool,
 }
 
 impl BufferState {
@@ -109,6 +202,7 @@ impl BufferState {
         BufferState {
             buffer: VecDeque::new(),
             read_waker: None,
+            closed: false,
         }
     }
     /// Writes data to the front of the deque byte buffer
@@ -123,12 +217,21 @@ impl BufferState {
         }
     }
 
+    /// Marks the buffer as closed, and wakes up anybody waiting on data
+    /// so they can see the end of the stream.
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
     /// Read data from the end of the deque byte buffer
     fn read(&mut self, to_buf: &mut [u8]) -> usize {
         // Read no more bytes than we have available, and no more bytes than we were asked for
         let bytes_to_read = min(to_buf.len(), self.buffer.len());
-        for i in 0..bytes_to_read {
-            to_buf[i] = self.buffer.pop_back().unwrap();
+        for byte in &mut to_buf[..bytes_to_read] {
+            *byte = self.buffer.pop_back().unwrap();
         }
 
         bytes_to_read
@@ -142,16 +245,33 @@ impl BufferState {
 #[derive(Debug, Clone)]
 pub struct WriteHalf {
     buffer: Arc<Mutex<BufferState>>,
+    delay: Option<Delay>,
+}
+
+impl WriteHalf {
+    fn new(buffer: Arc<Mutex<BufferState>>) -> Self {
+        WriteHalf { buffer, delay: None }
+    }
 }
 
 impl AsyncWrite for WriteHalf {
-    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.poll_elapsed(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
         // Acquire the lock for the buffer
         let mut write_to = self
             .buffer
             .lock()
             .expect("Lock was poisoned when acquiring buffer lock for WriteHalf");
 
+        if write_to.closed {
+            return Poll::Ready(Err(IoError::new(ErrorKind::BrokenPipe, "Connection closed")));
+        }
+
         // write the bytes
         write_to.write(buf);
 
@@ -171,10 +291,23 @@ impl AsyncWrite for WriteHalf {
 #[derive(Debug, Clone)]
 pub struct ReadHalf {
     buffer: Arc<Mutex<BufferState>>,
+    delay: Option<Delay>,
+}
+
+impl ReadHalf {
+    fn new(buffer: Arc<Mutex<BufferState>>) -> Self {
+        ReadHalf { buffer, delay: None }
+    }
 }
 
 impl AsyncRead for ReadHalf {
-    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<IoResult<()>> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<IoResult<()>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.poll_elapsed(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
         // Acquire the lock for the buffer
         let mut read_from = self
             .buffer
@@ -183,6 +316,11 @@ impl AsyncRead for ReadHalf {
 
         let bytes_read = read_from.read(buf.initialize_unfilled());
 
+        // Once the connection is closed, an empty buffer is the end of the stream.
+        if bytes_read == 0 && read_from.closed {
+            return Poll::Ready(Ok(()));
+        }
+
         // bytes_read == 0 would indicate that there is nothing more to read, which
         // means that someone trying to read from a VecDeque that hasn't been written to yet
         // would get an Eof error (as I learned the hard way).  Instead we should return Poll:Pending
@@ -197,9 +335,48 @@ impl AsyncRead for ReadHalf {
     }
 }
 
+/// A delay applied before every read or write of a stream half.
+struct Delay {
+    duration: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Delay {
+    fn new(duration: Duration) -> Self {
+        Delay { duration, sleep: None }
+    }
+
+    /// Polls the delay, and rearms it for the next operation once it has elapsed.
+    fn poll_elapsed(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let duration = self.duration;
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(sleep(duration)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.sleep = None;
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Clones start with a fresh timer, since a pending sleep belongs to a single operation.
+impl Clone for Delay {
+    fn clone(&self) -> Self {
+        Delay::new(self.duration)
+    }
+}
+
+impl fmt::Debug for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delay").field("duration", &self.duration).finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::chan;
+    use super::{chan, Faults};
+    use std::time::Duration;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[tokio::test]
@@ -227,4 +404,56 @@ mod tests {
             .expect("Read should succeed");
         assert_eq!(&read_on_client, b"Pong");
     }
+
+    #[tokio::test]
+    async fn closed_connections_reach_end_of_stream() {
+        let (mut client, mut server) = chan();
+        client.write_all(b"Ping").await.expect("Write should succeed");
+        client.close();
+
+        // Buffered data is still readable, then the stream ends
+        let mut read_on_server = Vec::new();
+        server
+            .read_to_end(&mut read_on_server)
+            .await
+            .expect("Read should succeed");
+        assert_eq!(&read_on_server, b"Ping");
+
+        assert!(server.write_all(b"Pong").await.is_err());
+        assert!(client.write_all(b"Ping").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn close_after_truncates_writes() {
+        let (mut client, mut server) = chan();
+        client.set_faults(Faults::new().close_after(3));
+        assert!(client.write_all(b"Ping").await.is_err());
+
+        let mut read_on_server = Vec::new();
+        server
+            .read_to_end(&mut read_on_server)
+            .await
+            .expect("Read should succeed");
+        assert_eq!(&read_on_server, b"Pin");
+    }
+
+    #[tokio::test]
+    async fn delays_slow_down_reads_and_writes() {
+        tokio::time::pause();
+        let (mut client, mut server) = chan();
+        client.set_faults(Faults::new().write_delay(Duration::from_secs(1)));
+        server.set_faults(Faults::new().read_delay(Duration::from_secs(2)));
+
+        let start = tokio::time::Instant::now();
+        client.write_all(b"Ping").await.expect("Write should succeed");
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let mut read_on_server = [0_u8; 4];
+        server
+            .read_exact(&mut read_on_server)
+            .await
+            .expect("Read should succeed");
+        assert_eq!(&read_on_server, b"Ping");
+        assert!(start.elapsed() >= Duration::from_secs(3));
+    }
 }
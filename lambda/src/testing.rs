@@ -0,0 +1,751 @@
+pub use crate::simulated::Faults;
+use crate::{
+    client,
+    clock::{Clock, TokioClock},
+    codec::{self, Codec},
+    diagnostic, incoming,
+    record::Recorder,
+    requests::{EventCompletionRequest, IntoRequest, IntoResponse, NextEventResponse},
+    run_inner,
+    simulated::chan,
+    Config, Context, Error, FromPayload, Handler, IntoPayload, Payload,
+};
+use futures_util::stream::StreamExt;
+use http::{header::HeaderName, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::{server::conn::Http, service::service_fn, Body};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    env, fmt, fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// The function ARN that mock events are sent with, unless it's overridden.
+const DEFAULT_ARN: &str = "arn:aws:lambda:us-east-1:123456789012:function:mock-function";
+
+/// The time that mock events have before their deadline, unless it's overridden.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// An event served by a [`MockRuntime`], with the headers that the Runtime API sends along with it.
+///
+/// [`MockRuntime`]: struct.MockRuntime.html
+#[derive(Debug, Clone)]
+pub struct MockEvent {
+    body: Vec<u8>,
+    request_id: Option<String>,
+    deadline: Option<u64>,
+    arn: String,
+    trace_id: String,
+    headers: HeaderMap,
+    status: StatusCode,
+}
+
+impl MockEvent {
+    /// Creates an event whose body is the JSON representation of `event`.
+    pub fn new<T: Serialize>(event: &T) -> Result<Self, Error> {
+        Ok(Self::from_slice(serde_json::to_vec(event)?))
+    }
+
+    /// Creates an event with a raw body. The body is sent to the function as is.
+    pub fn from_slice(body: impl Into<Vec<u8>>) -> Self {
+        MockEvent {
+            body: body.into(),
+            request_id: None,
+            deadline: None,
+            arn: DEFAULT_ARN.to_owned(),
+            trace_id: String::new(),
+            headers: HeaderMap::new(),
+            status: StatusCode::OK,
+        }
+    }
+
+    /// Sets the request id of the event. Events get a sequential request id by default.
+    pub fn request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    /// Sets the deadline of the event, in Unix time milliseconds.
+    /// Events have a deadline three seconds after they are served by default.
+    pub fn deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the ARN of the function being invoked.
+    pub fn invoked_function_arn(mut self, arn: impl Into<String>) -> Self {
+        self.arn = arn.into();
+        self
+    }
+
+    /// Sets the X-Ray tracing header of the event.
+    pub fn xray_trace_id(mut self, trace_id: impl Into<String>) -> Self {
+        self.trace_id = trace_id.into();
+        self
+    }
+
+    /// Adds a header to the Runtime API response that serves the event.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Sets the status of the Runtime API response that serves the event.
+    /// The runtime fails when the status isn't successful, like it does
+    /// when Lambda's Runtime API fails.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+/// A response that a function sent to the mock Runtime API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockResponse {
+    /// The request id of the invocation.
+    pub request_id: String,
+    /// The raw body of the response.
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    /// Deserializes the body of the response.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// An error that a function reported to the mock Runtime API.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MockError {
+    /// The request id of the invocation. It's empty for init errors.
+    #[serde(skip)]
+    pub request_id: String,
+    /// The type of the error.
+    pub error_type: String,
+    /// The error message.
+    pub error_message: String,
+}
+
+impl fmt::Display for MockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error_type, self.error_message)
+    }
+}
+
+impl std::error::Error for MockError {}
+
+/// The state shared between a [`MockRuntime`] and the Runtime API server it runs.
+#[derive(Debug, Default)]
+struct ServerState {
+    events: VecDeque<MockEvent>,
+    served: usize,
+    responses: Vec<MockResponse>,
+    errors: Vec<MockError>,
+    init_errors: Vec<MockError>,
+    result_status: Option<StatusCode>,
+}
+
+/// A scriptable mock of the [Lambda Runtime APIs](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html).
+///
+/// A `MockRuntime` serves a queue of events to a handler, through the same code path that
+/// `lamedh_runtime::run` uses in production. The runtime talks to the mock over in-memory
+/// streams, so no network is required. Every response and error is recorded, so tests can
+/// assert on them after the run.
+///
+/// # Example
+/// ```
+/// use lamedh_runtime::{handler_fn, testing::{MockEvent, MockRuntime}, Context, Error};
+/// use serde_json::{json, Value};
+///
+/// async fn func(event: Value, _: Context) -> Result<Value, Error> {
+///     Ok(json!({ "message": format!("Hello, {}!", event["name"].as_str().unwrap_or("world")) }))
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Error> {
+///     let mut runtime = MockRuntime::new();
+///     runtime.push_event(MockEvent::new(&json!({ "name": "Ferris" }))?);
+///     runtime.run(handler_fn(func)).await?;
+///
+///     let responses = runtime.responses();
+///     assert_eq!(json!({ "message": "Hello, Ferris!" }), responses[0].json::<Value>()?);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MockRuntime {
+    config: Arc<Config>,
+    codec: Option<Arc<dyn Codec>>,
+    faults: Faults,
+    recorder: Option<Arc<Recorder>>,
+    metrics: Vec<Value>,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl Default for MockRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockRuntime {
+    /// Creates a mock with an empty event queue, and the configuration of a
+    /// function called `mock-function`.
+    pub fn new() -> Self {
+        Self::with_config(Config::builder().build())
+    }
+
+    /// Creates a mock with an empty event queue. `config` is passed to the
+    /// handler in every `Context`, instead of the environment configuration.
+    pub fn with_config(config: Config) -> Self {
+        MockRuntime {
+            config: Arc::new(config),
+            codec: None,
+            faults: Faults::default(),
+            recorder: None,
+            metrics: Vec::new(),
+            state: Arc::default(),
+        }
+    }
+
+    /// Adds an event to the end of the queue.
+    pub fn push_event(&mut self, event: MockEvent) -> &mut Self {
+        self.lock().events.push_back(event);
+        self
+    }
+
+    /// Injects `faults` in the runtime's end of the connection to the mock.
+    /// Delays slow down the runtime's requests and the responses it reads,
+    /// and `close_after` drops the connection in the middle of a request.
+    pub fn faults(&mut self, faults: Faults) -> &mut Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Decodes the events and encodes the responses with `codec`, instead of the
+    /// installed codec.
+    pub fn codec(&mut self, codec: impl Codec + 'static) -> &mut Self {
+        self.codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// Records the invocations that the mock serves with `recorder`, like the runtime
+    /// does in Lambda when a recorder is installed.
+    pub fn recorder(&mut self, recorder: Recorder) -> &mut Self {
+        self.recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    /// Sets the status that the mock replies with when the function sends a
+    /// response or an error, `202 Accepted` by default. Use `413 Payload Too Large`
+    /// to simulate responses over Lambda's payload limit. Responses and errors
+    /// are recorded whatever the status is.
+    pub fn result_status(&mut self, status: StatusCode) -> &mut Self {
+        self.lock().result_status = Some(status);
+        self
+    }
+
+    /// Runs `handler` until every event in the queue has been processed.
+    ///
+    /// Errors returned by the handler are reported to the mock, like they
+    /// are reported to Lambda, and they don't fail the run. Errors in the
+    /// runtime itself, like events that can't be deserialized, are returned.
+    pub async fn run<A, B, F>(&mut self, handler: F) -> Result<(), Error>
+    where
+        F: Handler<A, B>,
+        <F as Handler<A, B>>::Error: fmt::Display,
+        A: FromPayload,
+        B: IntoPayload,
+    {
+        let mut handler = handler;
+        let pending = self.lock().events.len();
+
+        let (mut client, server) = chan();
+        client.set_faults(self.faults.clone());
+        let state = self.state.clone();
+        let server = tokio::spawn(async move {
+            let service = service_fn(move |req| serve(state.clone(), req));
+            Http::new().serve_connection(server, service).await
+        });
+
+        let client = client::simulated(client);
+        let incoming = incoming(&client).take(pending);
+        let codec = self.codec.clone().unwrap_or_else(codec::installed);
+        let mut metrics = Vec::new();
+        let res = run_inner(
+            &client,
+            incoming,
+            &mut handler,
+            &self.config,
+            &*codec,
+            None,
+            self.recorder.as_ref(),
+            &mut metrics,
+        )
+        .await;
+
+        server.abort();
+        for line in metrics.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            self.metrics.push(serde_json::from_slice(line)?);
+        }
+        res
+    }
+
+    /// Returns the responses that have been sent to the mock, in order.
+    pub fn responses(&self) -> Vec<MockResponse> {
+        self.lock().responses.clone()
+    }
+
+    /// Returns the invocation errors that have been reported to the mock, in order.
+    pub fn errors(&self) -> Vec<MockError> {
+        self.lock().errors.clone()
+    }
+
+    /// Returns the lines of [EMF](../metrics/index.html) metrics that the runtime wrote
+    /// after the invocations, in order. In Lambda, they're written to stdout.
+    pub fn metrics(&self) -> Vec<Value> {
+        self.metrics.clone()
+    }
+
+    /// Returns the init errors that have been reported to the mock, in order.
+    pub fn init_errors(&self) -> Vec<MockError> {
+        self.lock().init_errors.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ServerState> {
+        self.state
+            .lock()
+            .expect("Lock was poisoned when acquiring mock runtime state")
+    }
+}
+
+/// Invokes `handler` once with `event`, and a context built with [`Context::builder`].
+///
+/// The event and the response go through the installed codec, JSON by default, exactly
+/// like they do in Lambda, so this catches serialization mismatches that calling the
+/// handler directly doesn't.
+/// Errors returned by the handler are converted to a [`MockError`], with the same
+/// type and message that the runtime would report, and can be downcast from the
+/// returned error.
+///
+/// # Example
+/// ```
+/// use lamedh_runtime::{handler_fn, testing, Context, Error};
+/// use serde_json::{json, Value};
+///
+/// async fn func(event: Value, _: Context) -> Result<Value, Error> {
+///     Ok(json!({ "message": format!("Hello, {}!", event["name"].as_str().unwrap_or("world")) }))
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Error> {
+///     let response: Value = testing::invoke(&mut handler_fn(func), json!({ "name": "Ferris" })).await?;
+///     assert_eq!(json!({ "message": "Hello, Ferris!" }), response);
+///     Ok(())
+/// }
+/// ```
+///
+/// [`Context::builder`]: ../struct.Context.html#method.builder
+/// [`MockError`]: struct.MockError.html
+pub async fn invoke<A, B, F, T, R>(handler: &mut F, event: T) -> Result<R, Error>
+where
+    F: Handler<A, B>,
+    <F as Handler<A, B>>::Error: fmt::Display,
+    A: FromPayload,
+    B: IntoPayload,
+    T: Serialize,
+    R: DeserializeOwned,
+{
+    invoke_with_context(handler, event, Context::builder().build()).await
+}
+
+/// Invokes `handler` once with `event` and `ctx`, like [`invoke`] does.
+///
+/// [`invoke`]: fn.invoke.html
+pub async fn invoke_with_context<A, B, F, T, R>(handler: &mut F, event: T, ctx: Context) -> Result<R, Error>
+where
+    F: Handler<A, B>,
+    <F as Handler<A, B>>::Error: fmt::Display,
+    A: FromPayload,
+    B: IntoPayload,
+    T: Serialize,
+    R: DeserializeOwned,
+{
+    invoke_with_codec(handler, event, ctx, &*codec::installed()).await
+}
+
+/// Invokes `handler` once, with an event and a response that go through `codec`.
+async fn invoke_with_codec<A, B, F, T, R>(
+    handler: &mut F,
+    event: T,
+    ctx: Context,
+    codec: &dyn Codec,
+) -> Result<R, Error>
+where
+    F: Handler<A, B>,
+    <F as Handler<A, B>>::Error: fmt::Display,
+    A: FromPayload,
+    B: IntoPayload,
+    T: Serialize,
+    R: DeserializeOwned,
+{
+    let event = A::from_payload(codec::encode(codec, &event)?, codec)?;
+    let request_id = ctx.request_id.clone();
+
+    match handler.call(event, ctx).await {
+        Ok(res) => {
+            // Serialize the response through the request the runtime sends to Lambda.
+            let req = EventCompletionRequest {
+                request_id: &request_id,
+                body: res.into_payload(codec)?,
+            }
+            .into_req()?;
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            codec::decode(codec, Payload::from(body))
+        }
+        Err(e) => {
+            let diagnostic = diagnostic(e);
+            Err(Box::new(MockError {
+                request_id,
+                error_type: diagnostic.error_type,
+                error_message: diagnostic.error_message,
+            }))
+        }
+    }
+}
+
+/// The environment variable that makes fixture tests write their snapshots instead of
+/// comparing them. Any value other than `0` enables it.
+pub const BLESS_ENV_VAR: &str = "LAMEDH_BLESS";
+
+/// Invokes `handler` with a fixture event through [`invoke`], and compares the output
+/// with the JSON snapshot at `snapshot`. Handler errors are compared as the diagnostic
+/// that the runtime reports, with their `errorType` and `errorMessage`.
+///
+/// When the `LAMEDH_BLESS` environment variable is set, the snapshot is written with the
+/// output instead. This is the function behind the tests generated by `#[lambda_test]`.
+///
+/// # Panics
+///
+/// Panics when the output doesn't match the snapshot, when the snapshot is missing, or
+/// when the handler can't be invoked with the fixture.
+///
+/// [`invoke`]: fn.invoke.html
+pub async fn assert_fixture<A, B, F>(handler: &mut F, event: &str, snapshot: impl AsRef<Path>)
+where
+    F: Handler<A, B>,
+    <F as Handler<A, B>>::Error: fmt::Display,
+    A: FromPayload,
+    B: IntoPayload,
+{
+    let snapshot = snapshot.as_ref();
+    let event: Value = serde_json::from_str(event)
+        .unwrap_or_else(|e| panic!("The fixture of {} isn't valid JSON: {}", snapshot.display(), e));
+    let output: Value = match invoke(handler, event).await {
+        Ok(output) => output,
+        Err(e) => match e.downcast::<MockError>() {
+            Ok(error) => json!({ "errorType": error.error_type, "errorMessage": error.error_message }),
+            Err(e) => panic!("Unable to invoke the handler for {}: {}", snapshot.display(), e),
+        },
+    };
+    let pretty = |value: &Value| serde_json::to_string_pretty(value).expect("JSON values always serialize");
+
+    if matches!(env::var(BLESS_ENV_VAR), Ok(bless) if bless != "0") {
+        fs::write(snapshot, pretty(&output) + "\n")
+            .unwrap_or_else(|e| panic!("Unable to write snapshot {}: {}", snapshot.display(), e));
+        return;
+    }
+
+    let expected = fs::read(snapshot).unwrap_or_else(|e| {
+        panic!(
+            "Unable to read snapshot {}: {}. Run the tests with {}=1 to create it.",
+            snapshot.display(),
+            e,
+            BLESS_ENV_VAR
+        )
+    });
+    let expected: Value = serde_json::from_slice(&expected)
+        .unwrap_or_else(|e| panic!("Snapshot {} isn't valid JSON: {}", snapshot.display(), e));
+    assert!(
+        expected == output,
+        "The output doesn't match snapshot {}. Run the tests with {}=1 to update it.\nexpected: {}\n  actual: {}",
+        snapshot.display(),
+        BLESS_ENV_VAR,
+        pretty(&expected),
+        pretty(&output)
+    );
+}
+
+async fn serve(state: Arc<Mutex<ServerState>>, req: Request<Body>) -> Result<Response<Body>, Error> {
+    let path = req.uri().path().to_owned();
+    let segments: Vec<&str> = path.split('/').collect();
+    match (req.method(), &segments[1..]) {
+        (&Method::GET, ["2018-06-01", "runtime", "invocation", "next"]) => next_event(&state),
+        (&Method::POST, ["2018-06-01", "runtime", "invocation", id, "response"]) => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let response = MockResponse {
+                request_id: (*id).to_owned(),
+                body: body.to_vec(),
+            };
+            let mut state = state.lock().expect("Lock was poisoned");
+            state.responses.push(response);
+            result(&state)
+        }
+        (&Method::POST, ["2018-06-01", "runtime", "invocation", id, "error"]) => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let mut error: MockError = serde_json::from_slice(&body)?;
+            error.request_id = (*id).to_owned();
+            let mut state = state.lock().expect("Lock was poisoned");
+            state.errors.push(error);
+            result(&state)
+        }
+        (&Method::POST, ["2018-06-01", "runtime", "init", "error"]) => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let error: MockError = serde_json::from_slice(&body)?;
+            let mut state = state.lock().expect("Lock was poisoned");
+            state.init_errors.push(error);
+            result(&state)
+        }
+        _ => Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())?),
+    }
+}
+
+fn next_event(state: &Mutex<ServerState>) -> Result<Response<Body>, Error> {
+    let mut state = state.lock().expect("Lock was poisoned");
+    let event = match state.events.pop_front() {
+        Some(event) => event,
+        None => return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())?),
+    };
+    state.served += 1;
+
+    let request_id = event
+        .request_id
+        .unwrap_or_else(|| format!("mock-request-{}", state.served));
+    let deadline = match event.deadline {
+        Some(deadline) => deadline,
+        None => u64::try_from(
+            (TokioClock.now() + DEFAULT_TIMEOUT)
+                .duration_since(UNIX_EPOCH)?
+                .as_millis(),
+        )?,
+    };
+
+    let rsp = NextEventResponse {
+        request_id: &request_id,
+        deadline,
+        arn: &event.arn,
+        trace_id: &event.trace_id,
+        body: event.body,
+    };
+    let mut rsp = rsp.into_rsp()?;
+    rsp.headers_mut().extend(event.headers);
+    *rsp.status_mut() = event.status;
+    Ok(rsp)
+}
+
+fn result(state: &ServerState) -> Result<Response<Body>, Error> {
+    let status = state.result_status.unwrap_or(StatusCode::ACCEPTED);
+    Ok(Response::builder().status(status).body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handler_fn, Context};
+    use serde_json::{json, Value};
+
+    async fn greet(event: Value, ctx: Context) -> Result<Value, Error> {
+        match event["name"].as_str() {
+            Some(name) => Ok(json!({ "message": format!("Hello, {}!", name), "req_id": ctx.request_id })),
+            None => Err("missing name".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn records_responses_and_errors() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime
+            .push_event(MockEvent::new(&json!({ "name": "Ferris" }))?.request_id("first"))
+            .push_event(MockEvent::new(&json!({}))?)
+            .push_event(MockEvent::new(&json!({ "name": "Corro" }))?);
+        runtime.run(handler_fn(greet)).await?;
+
+        let responses = runtime.responses();
+        assert_eq!(2, responses.len());
+        assert_eq!("first", responses[0].request_id);
+        assert_eq!(
+            json!({ "message": "Hello, Ferris!", "req_id": "first" }),
+            responses[0].json::<Value>()?
+        );
+        assert_eq!("mock-request-3", responses[1].request_id);
+
+        let errors = runtime.errors();
+        assert_eq!(1, errors.len());
+        assert_eq!("mock-request-2", errors[0].request_id);
+        assert_eq!("missing name", errors[0].error_message);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn passes_headers_and_config_to_context() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime.push_event(
+            MockEvent::new(&json!({}))?
+                .deadline(1_542_409_706_888)
+                .invoked_function_arn("arn:aws:lambda:us-east-2:123456789012:function:custom-runtime")
+                .xray_trace_id("Root=1-5bef4de7-ad49b0e87f6ef6c87fc2e700;Parent=9a9197af755a6419"),
+        );
+
+        let func = handler_fn(|_: Value, ctx: Context| async move {
+            Ok::<_, Error>(json!({
+                "deadline": ctx.deadline,
+                "arn": ctx.invoked_function_arn,
+                "trace_id": ctx.xray_trace_id,
+                "function_name": ctx.env_config.function_name,
+            }))
+        });
+        runtime.run(func).await?;
+
+        let expected = json!({
+            "deadline": 1_542_409_706_888_u64,
+            "arn": "arn:aws:lambda:us-east-2:123456789012:function:custom-runtime",
+            "trace_id": "Root=1-5bef4de7-ad49b0e87f6ef6c87fc2e700;Parent=9a9197af755a6419",
+            "function_name": "mock-function",
+        });
+        assert_eq!(expected, runtime.responses()[0].json::<Value>()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_on_events_that_cannot_be_deserialized() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime.push_event(MockEvent::from_slice("not json"));
+
+        let res = runtime.run(handler_fn(greet)).await;
+        assert!(res.is_err());
+        assert!(runtime.responses().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_when_next_event_is_not_successful() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime
+            .push_event(MockEvent::new(&json!({ "name": "Ferris" }))?.status(StatusCode::INTERNAL_SERVER_ERROR))
+            .push_event(MockEvent::new(&json!({ "name": "Corro" }))?);
+
+        let res = runtime.run(handler_fn(greet)).await;
+        assert!(res.is_err());
+        assert!(runtime.responses().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_running_when_results_are_rejected() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime
+            .result_status(StatusCode::PAYLOAD_TOO_LARGE)
+            .push_event(MockEvent::new(&json!({ "name": "Ferris" }))?)
+            .push_event(MockEvent::new(&json!({}))?);
+        runtime.run(handler_fn(greet)).await?;
+
+        assert_eq!(1, runtime.responses().len());
+        assert_eq!(1, runtime.errors().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_connection_drops() -> Result<(), Error> {
+        let mut runtime = MockRuntime::new();
+        runtime
+            .faults(Faults::new().close_after(16))
+            .push_event(MockEvent::new(&json!({ "name": "Ferris" }))?);
+
+        let res = runtime.run(handler_fn(greet)).await;
+        assert!(res.is_err());
+        assert!(runtime.responses().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn completes_over_a_slow_connection() -> Result<(), Error> {
+        tokio::time::pause();
+        let mut runtime = MockRuntime::new();
+        runtime
+            .faults(
+                Faults::new()
+                    .read_delay(Duration::from_millis(100))
+                    .write_delay(Duration::from_millis(100)),
+            )
+            .push_event(MockEvent::new(&json!({ "name": "Ferris" }))?);
+        runtime.run(handler_fn(greet)).await?;
+
+        assert_eq!(1, runtime.responses().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invokes_handlers_once() -> Result<(), Error> {
+        let ctx = Context::builder().request_id("direct").build();
+        let response: Value = invoke_with_context(&mut handler_fn(greet), json!({ "name": "Ferris" }), ctx).await?;
+        assert_eq!(json!({ "message": "Hello, Ferris!", "req_id": "direct" }), response);
+
+        let error = invoke::<_, Value, _, _, Value>(&mut handler_fn(greet), json!({}))
+            .await
+            .unwrap_err();
+        let error = error
+            .downcast_ref::<MockError>()
+            .expect("Handler errors are MockErrors");
+        assert_eq!("missing name", error.error_message);
+        assert!(error.error_type.contains("Error"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invoke_round_trips_through_json() -> Result<(), Error> {
+        #[derive(Deserialize)]
+        struct Event {
+            #[serde(rename = "Name")]
+            name: String,
+        }
+        #[derive(Serialize)]
+        struct Response {
+            #[serde(rename = "Greeting")]
+            greeting: String,
+        }
+
+        let mut handler = handler_fn(|event: Event, _: Context| async move {
+            Ok::<_, Error>(Response {
+                greeting: format!("Hello, {}!", event.name),
+            })
+        });
+        let response: Value = invoke(&mut handler, json!({ "Name": "Ferris" })).await?;
+        assert_eq!(json!({ "Greeting": "Hello, Ferris!" }), response);
+
+        // Field names that don't match the serialized names fail, like they do in Lambda
+        let res: Result<Value, _> = invoke(&mut handler, json!({ "name": "Ferris" })).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invoke_round_trips_through_the_codec() -> Result<(), Error> {
+        // The response isn't JSON, so it can only be decoded with the codec.
+        let mut handler = handler_fn(|event: String, _: Context| async move { Ok::<_, Error>(format!("{}!", event)) });
+        let response: String = invoke_with_codec(
+            &mut handler,
+            "hello",
+            Context::builder().build(),
+            &crate::codec::PassthroughCodec,
+        )
+        .await?;
+        assert_eq!("hello!", response);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Tokio runtimes sized for the resources that Lambda allocates to the function.
+//!
+//! Lambda allocates vCPUs in proportion to the memory of the function: one full vCPU at
+//! 1,769 MB, up to six at 10,240 MB. `#[tokio::main]` sizes its runtime on the CPUs of the
+//! host instead, so a small function spawns worker threads that it has no CPU for.
+//! [`Threads::from_env`] picks a current-thread runtime when the function has at most one
+//! vCPU, and a worker per vCPU above that:
+//!
+//! ```
+//! use lamedh_runtime::tuning::Threads;
+//!
+//! assert_eq!(Threads::CurrentThread, Threads::for_memory(1024));
+//! assert_eq!(Threads::MultiThread(2), Threads::for_memory(3008));
+//! assert_eq!(Threads::MultiThread(6), Threads::for_memory(10240));
+//! ```
+//!
+//! [`lamedh_runtime::main`] runs a future on such a runtime. The choice can be overridden
+//! with the `LAMEDH_TOKIO_THREADS` environment variable, which is either `current_thread`
+//! or a number of worker threads.
+//!
+//! [`Threads::from_env`]: enum.Threads.html#method.from_env
+//! [`lamedh_runtime::main`]: ../fn.main.html
+use crate::{ConfigError, Error};
+use std::{
+    env::{self, VarError},
+    future::Future,
+    num::NonZeroUsize,
+    thread,
+};
+
+/// The environment variable that overrides the threads of the runtime.
+pub const THREADS_ENV_VAR: &str = "LAMEDH_TOKIO_THREADS";
+
+/// The memory, in MB, at which Lambda allocates one full vCPU.
+pub const MEMORY_PER_VCPU: i32 = 1769;
+
+/// The most vCPUs that Lambda allocates to a function.
+const MAX_VCPUS: usize = 6;
+
+const MEMORY_ENV_VAR: &str = "AWS_LAMBDA_FUNCTION_MEMORY_SIZE";
+
+/// The threads that a Tokio runtime runs tasks on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threads {
+    /// Every task runs on the thread that blocks on the runtime.
+    CurrentThread,
+    /// Tasks run on this many worker threads.
+    MultiThread(usize),
+}
+
+impl Threads {
+    /// Returns the threads that match the vCPUs of a function with `memory` MB.
+    pub fn for_memory(memory: i32) -> Self {
+        if memory <= MEMORY_PER_VCPU {
+            return Threads::CurrentThread;
+        }
+        let vcpus = (memory as usize).div_ceil(MEMORY_PER_VCPU as usize);
+        Threads::MultiThread(vcpus.min(MAX_VCPUS))
+    }
+
+    /// Reads the threads from `LAMEDH_TOKIO_THREADS`, or from the memory of the function.
+    ///
+    /// Outside of Lambda, where the memory isn't set, the runtime gets a worker per CPU of
+    /// the host, like `#[tokio::main]` does.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|name| match env::var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(VarError::NotPresent) => Ok(None),
+            Err(VarError::NotUnicode(value)) => Err(ConfigError::Invalid {
+                name,
+                value: value.to_string_lossy().into_owned(),
+                reason: "it isn't valid unicode".to_owned(),
+            }),
+        })
+    }
+
+    fn from_lookup<F>(lookup: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&'static str) -> Result<Option<String>, ConfigError>,
+    {
+        if let Some(value) = lookup(THREADS_ENV_VAR)? {
+            return match value.trim() {
+                "current_thread" => Ok(Threads::CurrentThread),
+                workers => match workers.parse::<NonZeroUsize>() {
+                    Ok(workers) => Ok(Threads::MultiThread(workers.get())),
+                    Err(_) => Err(ConfigError::Invalid {
+                        name: THREADS_ENV_VAR,
+                        reason: "expected `current_thread` or a number of worker threads".to_owned(),
+                        value,
+                    }),
+                },
+            };
+        }
+        match lookup(MEMORY_ENV_VAR)? {
+            Some(value) => match value.parse() {
+                Ok(memory) => Ok(Self::for_memory(memory)),
+                Err(e) => Err(ConfigError::Invalid {
+                    name: MEMORY_ENV_VAR,
+                    reason: format!("{}", e),
+                    value,
+                }),
+            },
+            None => Ok(Threads::MultiThread(
+                thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1),
+            )),
+        }
+    }
+
+    /// Returns a builder for a Tokio runtime with these threads, and every driver enabled.
+    pub fn builder(self) -> tokio::runtime::Builder {
+        let mut builder = match self {
+            Threads::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+            Threads::MultiThread(workers) => {
+                let mut builder = tokio::runtime::Builder::new_multi_thread();
+                builder.worker_threads(workers);
+                builder
+            }
+        };
+        builder.enable_all();
+        builder
+    }
+
+    /// Runs `future` to completion on a Tokio runtime with these threads.
+    ///
+    /// This builds a runtime, so it must not be called from an asynchronous context.
+    pub fn block_on<F, T, E>(self, future: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        let rt = self.builder().build().map_err(|e| E::from(e.into()))?;
+        rt.block_on(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn from_map(vars: &[(&'static str, &str)]) -> Result<Threads, ConfigError> {
+        let vars: HashMap<_, _> = vars.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        Threads::from_lookup(|name| Ok(vars.get(name).cloned()))
+    }
+
+    #[test]
+    fn threads_follow_the_vcpus_of_the_memory() {
+        assert_eq!(Threads::CurrentThread, Threads::for_memory(128));
+        assert_eq!(Threads::CurrentThread, Threads::for_memory(MEMORY_PER_VCPU));
+        assert_eq!(Threads::MultiThread(2), Threads::for_memory(MEMORY_PER_VCPU + 1));
+        assert_eq!(Threads::MultiThread(4), Threads::for_memory(7076));
+        assert_eq!(Threads::MultiThread(5), Threads::for_memory(7077));
+        assert_eq!(Threads::MultiThread(6), Threads::for_memory(10240));
+    }
+
+    #[test]
+    fn threads_are_read_from_the_environment() -> Result<(), ConfigError> {
+        assert_eq!(Threads::CurrentThread, from_map(&[(MEMORY_ENV_VAR, "512")])?);
+        assert_eq!(Threads::MultiThread(3), from_map(&[(MEMORY_ENV_VAR, "5120")])?);
+        assert!(matches!(from_map(&[])?, Threads::MultiThread(workers) if workers >= 1));
+        Ok(())
+    }
+
+    #[test]
+    fn threads_can_be_overridden() -> Result<(), ConfigError> {
+        let threads = from_map(&[(MEMORY_ENV_VAR, "512"), (THREADS_ENV_VAR, "4")])?;
+        assert_eq!(Threads::MultiThread(4), threads);
+        let threads = from_map(&[(MEMORY_ENV_VAR, "10240"), (THREADS_ENV_VAR, "current_thread")])?;
+        assert_eq!(Threads::CurrentThread, threads);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_overrides_name_the_variable() {
+        for value in &["0", "many"] {
+            let err = from_map(&[(THREADS_ENV_VAR, value)]).unwrap_err();
+            assert!(err.to_string().contains(THREADS_ENV_VAR), "{}", err);
+        }
+    }
+
+    #[test]
+    fn block_on_runs_on_the_chosen_runtime() -> Result<(), Error> {
+        let flavor = Threads::CurrentThread
+            .block_on(async { Ok::<_, Error>(tokio::runtime::Handle::current().runtime_flavor()) })?;
+        assert_eq!(tokio::runtime::RuntimeFlavor::CurrentThread, flavor);
+
+        let flavor = Threads::MultiThread(2)
+            .block_on(async { Ok::<_, Error>(tokio::runtime::Handle::current().runtime_flavor()) })?;
+        assert_eq!(tokio::runtime::RuntimeFlavor::MultiThread, flavor);
+        Ok(())
+    }
+}
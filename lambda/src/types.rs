@@ -1,7 +1,21 @@
-use crate::{Config, Error};
+use crate::{
+    clock::{Clock, ContextClock},
+    metrics::Metrics,
+    Config, Error,
+};
 use http::HeaderMap;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, convert::TryFrom};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    convert::TryFrom,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -112,13 +126,37 @@ pub struct Context {
     pub identity: Option<CognitoIdentity>,
     /// Lambda function configuration from the local environment variables.
     /// Includes information such as the function name, memory allocation,
-    /// version, and log streams.
-    pub env_config: Config,
+    /// version, and log streams. It's loaded once, and shared by every invocation.
+    pub env_config: Arc<Config>,
+    /// The time from the start of the process to the first request for an invocation,
+    /// which includes the init phase of `lamedh_runtime::run_with_init`. It's measured
+    /// to the hundredth of a second. Where the start of the process isn't available,
+    /// this is the time spent in the init phase, or empty without one.
+    pub init_duration: Option<Duration>,
+    /// The number of this invocation in the process, starting at 1 for the cold start.
+    /// It's 0 in contexts that the runtime didn't create.
+    pub invocation_number: u64,
+    /// The time since the result of the previous invocation of the process was sent.
+    /// This field is empty for the cold start.
+    pub since_previous_invocation: Option<Duration>,
+    /// The metrics that the invocation collected.
+    pub(crate) metrics: Metrics,
+    /// The clock that the deadline helpers read the time from.
+    pub(crate) clock: ContextClock,
 }
 
 impl TryFrom<HeaderMap> for Context {
     type Error = Error;
     fn try_from(headers: HeaderMap) -> Result<Self, Self::Error> {
+        Context::from_headers(headers, Arc::default())
+    }
+}
+
+impl Context {
+    /// Creates the context of an invocation from the headers of the Runtime API,
+    /// with the configuration shared by every invocation.
+    pub(crate) fn from_headers(headers: HeaderMap, env_config: Arc<Config>) -> Result<Self, Error> {
+        let metrics = Metrics::for_function(&env_config);
         let ctx = Context {
             request_id: headers["lambda-runtime-aws-request-id"]
                 .to_str()
@@ -138,8 +176,329 @@ impl TryFrom<HeaderMap> for Context {
                 .and_then(|h| h.to_str().ok())
                 .map(str::to_owned)
                 .unwrap_or_default(),
-            ..Default::default()
+            client_context: None,
+            identity: None,
+            env_config,
+            init_duration: None,
+            invocation_number: 0,
+            since_previous_invocation: None,
+            metrics,
+            clock: ContextClock::default(),
         };
         Ok(ctx)
     }
+
+    /// Returns a builder for a `Context`, with realistic defaults to test handlers.
+    ///
+    /// # Example
+    /// ```
+    /// use lamedh_runtime::Context;
+    /// use std::time::Duration;
+    ///
+    /// let ctx = Context::builder()
+    ///     .function_name("my-function")
+    ///     .timeout(Duration::from_secs(10))
+    ///     .build();
+    /// assert_eq!("my-function", ctx.env_config.function_name);
+    /// assert!(ctx.invoked_function_arn.ends_with(":function:my-function"));
+    /// ```
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
+    /// Returns whether this is the first invocation of the process, which paid for its
+    /// cold start.
+    pub fn is_cold_start(&self) -> bool {
+        self.invocation_number == 1
+    }
+
+    /// Returns the collector of the [metrics](metrics/index.html) of the invocation. The
+    /// runtime writes them in the Embedded Metric Format once the handler returns.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the deadline of the invocation.
+    pub fn deadline_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.deadline)
+    }
+
+    /// Returns the time left before the deadline, or zero when it has passed.
+    ///
+    /// The current time comes from the clock of the context, which follows Tokio's clock
+    /// unless it's set with [`ContextBuilder::clock`], so the remaining time stops when
+    /// time is paused in tests.
+    ///
+    /// [`ContextBuilder::clock`]: struct.ContextBuilder.html#method.clock
+    pub fn remaining_time(&self) -> Duration {
+        let now = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Duration::from_millis(u64::try_from(u128::from(self.deadline).saturating_sub(now)).unwrap_or(u64::MAX))
+    }
+
+    /// Returns whether the invocation has `margin` or less left before its deadline.
+    pub fn is_near_deadline(&self, margin: Duration) -> bool {
+        self.remaining_time() <= margin
+    }
+
+    /// Runs `future` until it completes, or until `margin` before the deadline, so the
+    /// handler still has time to return a partial result.
+    ///
+    /// The timeout is measured with Tokio's timers, so it follows paused time in tests.
+    ///
+    /// # Example
+    /// ```
+    /// use lamedh_runtime::{Context, Error};
+    /// use std::time::Duration;
+    ///
+    /// async fn func(_: (), ctx: Context) -> Result<&'static str, Error> {
+    ///     let slow = tokio::time::sleep(Duration::from_secs(60));
+    ///     match ctx.soft_timeout(Duration::from_millis(500), slow).await {
+    ///         Ok(()) => Ok("done"),
+    ///         Err(_) => Ok("partial"),
+    ///     }
+    /// }
+    ///
+    /// #[tokio::main(flavor = "current_thread")]
+    /// async fn main() -> Result<(), Error> {
+    ///     tokio::time::pause();
+    ///     let ctx = Context::builder().timeout(Duration::from_secs(3)).build();
+    ///     assert_eq!("partial", func((), ctx).await?);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn soft_timeout<F: Future>(
+        &self,
+        margin: Duration,
+        future: F,
+    ) -> Result<F::Output, tokio::time::error::Elapsed> {
+        tokio::time::timeout(self.remaining_time().saturating_sub(margin), future).await
+    }
+}
+
+/// The default timeout of Lambda functions.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Builds a [`Context`] for tests, outside of a Lambda execution environment.
+///
+/// Every field has a default that looks like what Lambda sends:
+/// - the request id is a randomly generated UUID,
+/// - the deadline is the time the context is built at plus the timeout, three seconds by default,
+/// - the function ARN is built from the function name and region of the configuration,
+///   `mock-function` in `us-east-1` by default.
+///
+/// [`Context`]: struct.Context.html
+#[derive(Debug, Clone)]
+pub struct ContextBuilder {
+    request_id: Option<String>,
+    deadline: Option<u64>,
+    timeout: Duration,
+    invoked_function_arn: Option<String>,
+    xray_trace_id: String,
+    client_context: Option<ClientContext>,
+    identity: Option<CognitoIdentity>,
+    env_config: Config,
+    init_duration: Option<Duration>,
+    invocation_number: u64,
+    since_previous_invocation: Option<Duration>,
+    clock: ContextClock,
+}
+
+impl Default for ContextBuilder {
+    fn default() -> Self {
+        ContextBuilder {
+            request_id: None,
+            deadline: None,
+            timeout: DEFAULT_TIMEOUT,
+            invoked_function_arn: None,
+            xray_trace_id: String::new(),
+            client_context: None,
+            identity: None,
+            env_config: Config::builder().build(),
+            init_duration: None,
+            invocation_number: 1,
+            since_previous_invocation: None,
+            clock: ContextClock::default(),
+        }
+    }
+}
+
+impl ContextBuilder {
+    /// Sets the request id, instead of generating one.
+    pub fn request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    /// Sets the deadline in Unix time milliseconds. It takes precedence over the timeout.
+    pub fn deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the time that the invocation has before its deadline.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the name of the function. The function ARN is built from it,
+    /// unless it's set with `invoked_function_arn`.
+    pub fn function_name(mut self, function_name: impl Into<String>) -> Self {
+        self.env_config.function_name = function_name.into();
+        self
+    }
+
+    /// Sets the ARN of the function being invoked.
+    pub fn invoked_function_arn(mut self, arn: impl Into<String>) -> Self {
+        self.invoked_function_arn = Some(arn.into());
+        self
+    }
+
+    /// Sets the X-Ray trace id.
+    pub fn xray_trace_id(mut self, trace_id: impl Into<String>) -> Self {
+        self.xray_trace_id = trace_id.into();
+        self
+    }
+
+    /// Sets the client context sent by the AWS mobile SDK.
+    pub fn client_context(mut self, client_context: ClientContext) -> Self {
+        self.client_context = Some(client_context);
+        self
+    }
+
+    /// Sets the Cognito identity that invoked the function.
+    pub fn identity(mut self, identity: CognitoIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Sets the function configuration, including the function name.
+    pub fn env_config(mut self, config: Config) -> Self {
+        self.env_config = config;
+        self
+    }
+
+    /// Sets the time from the start of the process to the first invocation.
+    pub fn init_duration(mut self, init_duration: Duration) -> Self {
+        self.init_duration = Some(init_duration);
+        self
+    }
+
+    /// Sets the number of the invocation in the process. It's 1 by default, the cold start.
+    pub fn invocation_number(mut self, invocation_number: u64) -> Self {
+        self.invocation_number = invocation_number;
+        self
+    }
+
+    /// Sets the time since the previous invocation of the process.
+    pub fn since_previous_invocation(mut self, since_previous_invocation: Duration) -> Self {
+        self.since_previous_invocation = Some(since_previous_invocation);
+        self
+    }
+
+    /// Sets the clock that the deadline helpers of the context read the time from.
+    /// The default deadline is computed with it too.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = ContextClock::new(clock);
+        self
+    }
+
+    /// Builds the `Context`.
+    pub fn build(self) -> Context {
+        let clock = self.clock;
+        let timeout = self.timeout;
+        let deadline = self.deadline.unwrap_or_else(|| {
+            let deadline = clock.now() + timeout;
+            let millis = deadline.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            u64::try_from(millis).unwrap_or(u64::MAX)
+        });
+        let env_config = self.env_config;
+        let invoked_function_arn = self.invoked_function_arn.unwrap_or_else(|| {
+            format!(
+                "arn:aws:lambda:{}:123456789012:function:{}",
+                env_config.region.as_deref().unwrap_or("us-east-1"),
+                env_config.function_name
+            )
+        });
+
+        let metrics = Metrics::for_function(&env_config);
+        Context {
+            request_id: self.request_id.unwrap_or_else(generate_request_id),
+            deadline,
+            invoked_function_arn,
+            xray_trace_id: self.xray_trace_id,
+            client_context: self.client_context,
+            identity: self.identity,
+            env_config: Arc::new(env_config),
+            init_duration: self.init_duration,
+            invocation_number: self.invocation_number,
+            since_previous_invocation: self.since_previous_invocation,
+            metrics,
+            clock,
+        }
+    }
+}
+
+/// Generates a random request id, formatted like the version 4 UUIDs that Lambda uses.
+fn generate_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // `RandomState` is seeded randomly, so hashing a counter gives unique
+    // random-looking values without pulling in a random number generator.
+    let hash = |n: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(n);
+        hasher.finish()
+    };
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let high = hash(count);
+    let low = hash(count.wrapping_add(u64::MAX / 2));
+
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0x0fff,
+        (low >> 48) & 0x3fff | 0x8000,
+        low & 0xffff_ffff_ffff,
+    )
+}
+
+#[test]
+fn builds_context_with_defaults() {
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let ctx = Context::builder().build();
+
+    assert_eq!(36, ctx.request_id.len());
+    assert_eq!(Some('4'), ctx.request_id.chars().nth(14));
+    assert_ne!(ctx.request_id, Context::builder().build().request_id);
+    assert!(ctx.deadline >= before + 3000);
+    assert_eq!(
+        "arn:aws:lambda:us-east-1:123456789012:function:mock-function",
+        ctx.invoked_function_arn
+    );
+    assert_eq!("mock-function", ctx.env_config.function_name);
+}
+
+#[test]
+fn builds_context_with_overrides() {
+    let ctx = Context::builder()
+        .request_id("id")
+        .deadline(1_542_409_706_888)
+        .function_name("custom-runtime")
+        .xray_trace_id("Root=1-5bef4de7-ad49b0e87f6ef6c87fc2e700")
+        .build();
+
+    assert_eq!("id", ctx.request_id);
+    assert_eq!(1_542_409_706_888, ctx.deadline);
+    assert_eq!(
+        "arn:aws:lambda:us-east-1:123456789012:function:custom-runtime",
+        ctx.invoked_function_arn
+    );
+    assert_eq!("Root=1-5bef4de7-ad49b0e87f6ef6c87fc2e700", ctx.xray_trace_id);
 }
@@ -0,0 +1,565 @@
+//! X-Ray subsegments for the `tracing` spans of a handler, without OpenTelemetry.
+//!
+//! [`XRayLayer`] is a [`tracing`] layer that sends a subsegment to the X-Ray daemon for
+//! every span marked with an `xray` field, once the span closes. The subsegments are children
+//! of the segment that Lambda records for the invocation, or of the closest marked span they
+//! are in. Spans are only sent for invocations that X-Ray samples.
+//!
+//! The `xray` field is either `true`, or the namespace of the subsegment: `"aws"` for calls to
+//! AWS services, and `"remote"` for calls to other services. The other fields of the span are
+//! the metadata of the subsegment, and error events in the span mark it as a fault.
+//!
+//! ```no_run
+//! use lamedh_runtime::{handler_fn, xray, Context, Error};
+//! use serde_json::Value;
+//! use tracing::Instrument;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     xray::init()?;
+//!     lamedh_runtime::run(handler_fn(func)).await
+//! }
+//!
+//! async fn func(event: Value, _: Context) -> Result<Value, Error> {
+//!     let span = tracing::info_span!("DynamoDB", xray = "aws", table = "orders");
+//!     let order = fetch_order().instrument(span).await;
+//!     Ok(order)
+//! }
+//!
+//! # async fn fetch_order() -> Value { Value::Null }
+//! ```
+//!
+//! The daemon listens on the address in `AWS_XRAY_DAEMON_ADDRESS`, which Lambda sets when
+//! active tracing is enabled, and on `127.0.0.1:2000` by default.
+//!
+//! This module requires the `xray` feature.
+//!
+//! [`XRayLayer`]: struct.XRayLayer.html
+//! [`tracing`]: https://docs.rs/tracing/0.1
+use crate::{is_invocation_span, ConfigError, Error};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::hash_map::RandomState,
+    env, fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context as LayerContext, Layer},
+    prelude::*,
+    registry::LookupSpan,
+};
+
+/// The environment variable with the address of the X-Ray daemon.
+pub const DAEMON_ADDRESS_ENV_VAR: &str = "AWS_XRAY_DAEMON_ADDRESS";
+
+/// The address of the X-Ray daemon when `AWS_XRAY_DAEMON_ADDRESS` isn't set.
+pub const DEFAULT_DAEMON_ADDRESS: &str = "127.0.0.1:2000";
+
+/// The header of every datagram sent to the daemon.
+const DATAGRAM_HEADER: &str = "{\"format\": \"json\", \"version\": 1}\n";
+
+/// The field that marks the spans that are sent as subsegments.
+const MARKER_FIELD: &str = "xray";
+
+/// The field of the invocation span with the X-Ray trace header.
+const TRACE_ID_FIELD: &str = "xrayTraceId";
+
+/// Installs an [`XRayLayer`] configured from the environment as the global subscriber.
+///
+/// [`XRayLayer`]: struct.XRayLayer.html
+pub fn init() -> Result<(), Error> {
+    tracing_subscriber::registry().with(XRayLayer::from_env()?).try_init()?;
+    Ok(())
+}
+
+/// The X-Ray trace header of an invocation, such as
+/// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`.
+///
+/// The header of an invocation is in [`Context::xray_trace_id`].
+///
+/// [`Context::xray_trace_id`]: ../struct.Context.html#structfield.xray_trace_id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceHeader {
+    /// The id of the trace.
+    pub root: String,
+    /// The id of the segment that the invocation is part of.
+    pub parent: Option<String>,
+    /// Whether the trace is sampled, or `None` when the decision is left to the receiver.
+    pub sampled: Option<bool>,
+}
+
+impl FromStr for TraceHeader {
+    type Err = Error;
+
+    fn from_str(header: &str) -> Result<Self, Self::Err> {
+        let (mut root, mut parent, mut sampled) = (None, None, None);
+        for field in header.split(';') {
+            match field.trim().split_once('=') {
+                Some(("Root", value)) => root = Some(value.to_owned()),
+                Some(("Parent", value)) => parent = Some(value.to_owned()),
+                Some(("Sampled", "1")) => sampled = Some(true),
+                Some(("Sampled", "0")) => sampled = Some(false),
+                _ => {}
+            }
+        }
+        match root {
+            Some(root) => Ok(TraceHeader { root, parent, sampled }),
+            None => Err(format!("X-Ray trace header without a root: {}", header).into()),
+        }
+    }
+}
+
+/// Reads the address of the daemon from a value of `AWS_XRAY_DAEMON_ADDRESS`.
+///
+/// The value is either a single address for UDP and TCP, like `127.0.0.1:2000`, or
+/// separate addresses like `tcp:127.0.0.1:2000 udp:127.0.0.1:2001`.
+pub fn parse_daemon_address(value: &str) -> Result<SocketAddr, ConfigError> {
+    let invalid = |reason: &str| ConfigError::Invalid {
+        name: DAEMON_ADDRESS_ENV_VAR,
+        value: value.to_owned(),
+        reason: reason.to_owned(),
+    };
+    let address = if value.contains(' ') {
+        value
+            .split_whitespace()
+            .find_map(|address| address.strip_prefix("udp:"))
+            .ok_or_else(|| invalid("expected an address prefixed with `udp:`"))?
+    } else {
+        value.trim()
+    };
+    address
+        .to_socket_addrs()
+        .map_err(|e| invalid(&e.to_string()))?
+        .next()
+        .ok_or_else(|| invalid("the address doesn't resolve"))
+}
+
+/// A [`tracing`] layer that sends spans marked with an `xray` field as X-Ray subsegments.
+///
+/// [`tracing`]: https://docs.rs/tracing/0.1
+pub struct XRayLayer {
+    socket: UdpSocket,
+    daemon: SocketAddr,
+    ids: IdGenerator,
+}
+
+impl fmt::Debug for XRayLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XRayLayer").field("daemon", &self.daemon).finish()
+    }
+}
+
+impl XRayLayer {
+    /// Creates a layer that sends subsegments to the daemon at `daemon`.
+    pub fn new(daemon: SocketAddr) -> io::Result<Self> {
+        let local: SocketAddr = if daemon.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        Ok(XRayLayer {
+            socket: UdpSocket::bind(local)?,
+            daemon,
+            ids: IdGenerator::default(),
+        })
+    }
+
+    /// Creates a layer that sends subsegments to the daemon in `AWS_XRAY_DAEMON_ADDRESS`.
+    pub fn from_env() -> Result<Self, Error> {
+        let address = env::var(DAEMON_ADDRESS_ENV_VAR).unwrap_or_else(|_| DEFAULT_DAEMON_ADDRESS.to_owned());
+        Ok(Self::new(parse_daemon_address(&address)?)?)
+    }
+
+    fn send(&self, subsegment: Subsegment, end_time: f64) {
+        let mut document = json!({
+            "name": subsegment.name,
+            "id": subsegment.id,
+            "trace_id": subsegment.trace_id,
+            "parent_id": subsegment.parent_id,
+            "start_time": subsegment.start_time,
+            "end_time": end_time,
+            "type": "subsegment",
+        });
+        if let Some(namespace) = subsegment.namespace {
+            document["namespace"] = Value::from(namespace);
+        }
+        if subsegment.fault {
+            document["fault"] = Value::from(true);
+        }
+        if !subsegment.metadata.is_empty() {
+            document["metadata"] = json!({ "default": subsegment.metadata });
+        }
+
+        let mut datagram = DATAGRAM_HEADER.as_bytes().to_vec();
+        if serde_json::to_writer(&mut datagram, &document).is_err() {
+            return;
+        }
+        // There's nowhere left to report a subsegment that can't be sent, and the
+        // daemon doesn't acknowledge the ones it receives anyway.
+        let _ = self.socket.send_to(&datagram, self.daemon);
+    }
+}
+
+/// Generates the random ids of subsegments.
+#[derive(Default)]
+struct IdGenerator {
+    keys: RandomState,
+    count: AtomicU64,
+}
+
+impl IdGenerator {
+    /// Returns a new id of 16 hexadecimal digits.
+    fn next(&self) -> String {
+        let mut hasher = self.keys.build_hasher();
+        hasher.write_u64(self.count.fetch_add(1, Ordering::Relaxed));
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+/// A subsegment in progress, in the extensions of its span.
+struct Subsegment {
+    name: &'static str,
+    id: String,
+    trace_id: String,
+    parent_id: String,
+    namespace: Option<&'static str>,
+    start_time: f64,
+    metadata: Map<String, Value>,
+    fault: bool,
+}
+
+/// The trace of an invocation that X-Ray samples, in the extensions of the invocation span.
+struct Trace {
+    root: String,
+    parent: String,
+}
+
+impl<S> Layer<S> for XRayLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: LayerContext<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut fields = SpanVisitor::default();
+        attrs.record(&mut fields);
+
+        if is_invocation_span(attrs.metadata()) {
+            let header = fields
+                .trace_id
+                .as_deref()
+                .and_then(|header| header.parse::<TraceHeader>().ok());
+            if let Some(TraceHeader {
+                root,
+                parent: Some(parent),
+                sampled: Some(true),
+            }) = header
+            {
+                span.extensions_mut().insert(Trace { root, parent });
+            }
+            return;
+        }
+        let namespace = match fields.marker {
+            Some(namespace) => namespace,
+            None => return,
+        };
+
+        // The subsegment is a child of the closest subsegment or invocation that it's in.
+        let parent = span.scope().skip(1).find_map(|ancestor| {
+            let extensions = ancestor.extensions();
+            if let Some(subsegment) = extensions.get::<Subsegment>() {
+                Some(Some((subsegment.trace_id.clone(), subsegment.id.clone())))
+            } else if let Some(trace) = extensions.get::<Trace>() {
+                Some(Some((trace.root.clone(), trace.parent.clone())))
+            } else if is_invocation_span(ancestor.metadata()) {
+                // The invocation isn't sampled.
+                Some(None)
+            } else {
+                None
+            }
+        });
+        if let Some(Some((trace_id, parent_id))) = parent {
+            span.extensions_mut().insert(Subsegment {
+                name: attrs.metadata().name(),
+                id: self.ids.next(),
+                trace_id,
+                parent_id,
+                namespace,
+                start_time: seconds_since_epoch(SystemTime::now()),
+                metadata: fields.metadata,
+                fault: false,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(subsegment) = span.extensions_mut().get_mut::<Subsegment>() {
+                let mut fields = SpanVisitor::default();
+                values.record(&mut fields);
+                subsegment.metadata.extend(fields.metadata);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(subsegment) = span.extensions_mut().get_mut::<Subsegment>() {
+                    subsegment.fault = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: LayerContext<'_, S>) {
+        let subsegment = ctx
+            .span(&id)
+            .and_then(|span| span.extensions_mut().remove::<Subsegment>());
+        if let Some(subsegment) = subsegment {
+            self.send(subsegment, seconds_since_epoch(SystemTime::now()));
+        }
+    }
+}
+
+/// Records the marker, the trace header and the metadata of a span.
+#[derive(Default)]
+struct SpanVisitor {
+    marker: Option<Option<&'static str>>,
+    trace_id: Option<String>,
+    metadata: Map<String, Value>,
+}
+
+impl SpanVisitor {
+    fn record(&mut self, field: &Field, value: Value) {
+        match (field.name(), value) {
+            (MARKER_FIELD, Value::Bool(true)) => self.marker = Some(None),
+            (MARKER_FIELD, Value::String(namespace)) if namespace == "aws" => self.marker = Some(Some("aws")),
+            (MARKER_FIELD, Value::String(namespace)) if namespace == "remote" => self.marker = Some(Some("remote")),
+            (MARKER_FIELD, _) => {}
+            (TRACE_ID_FIELD, Value::String(header)) => self.trace_id = Some(header),
+            (name, value) => {
+                self.metadata.insert(name.to_owned(), value);
+            }
+        }
+    }
+}
+
+impl Visit for SpanVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, Value::from(format!("{:?}", value)));
+    }
+}
+
+/// Returns a time in seconds since the epoch, the way X-Ray documents represent it.
+fn seconds_since_epoch(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::INVOCATION_SPAN;
+    use std::time::Duration;
+    use tracing::{error, info_span};
+
+    pub(crate) const TRACE_HEADER: &str = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+    /// A socket that plays the X-Ray daemon.
+    pub(crate) struct Daemon(UdpSocket);
+
+    impl Daemon {
+        pub(crate) fn bind() -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            Daemon(socket)
+        }
+
+        pub(crate) fn layer(&self) -> XRayLayer {
+            XRayLayer::new(self.0.local_addr().unwrap()).unwrap()
+        }
+
+        /// Returns the subsegments received, until none arrives before the timeout.
+        pub(crate) fn subsegments(&self) -> Vec<Value> {
+            let mut subsegments = Vec::new();
+            let mut buf = [0; 65_536];
+            while let Ok(len) = self.0.recv(&mut buf) {
+                let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+                let document = datagram
+                    .strip_prefix(DATAGRAM_HEADER)
+                    .expect("every datagram starts with the header");
+                subsegments.push(serde_json::from_str(document).unwrap());
+            }
+            subsegments
+        }
+    }
+
+    fn invocation(trace_header: &str) -> tracing::Span {
+        info_span!(target: "lamedh_runtime", INVOCATION_SPAN, requestId = "52fdfc07", xrayTraceId = %trace_header)
+    }
+
+    #[test]
+    fn trace_headers_are_parsed() {
+        let header: TraceHeader = TRACE_HEADER.parse().unwrap();
+        assert_eq!(
+            TraceHeader {
+                root: "1-5759e988-bd862e3fe1be46a994272793".to_owned(),
+                parent: Some("53995c3f42cd8ad8".to_owned()),
+                sampled: Some(true),
+            },
+            header
+        );
+
+        let header: TraceHeader = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=?".parse().unwrap();
+        assert_eq!(None, header.parent);
+        assert_eq!(None, header.sampled);
+        assert!("Parent=53995c3f42cd8ad8;Sampled=1".parse::<TraceHeader>().is_err());
+    }
+
+    #[test]
+    fn daemon_addresses_are_parsed() {
+        let address: SocketAddr = "169.254.79.129:2000".parse().unwrap();
+        assert_eq!(address, parse_daemon_address("169.254.79.129:2000").unwrap());
+
+        let address: SocketAddr = "127.0.0.1:2001".parse().unwrap();
+        assert_eq!(
+            address,
+            parse_daemon_address("tcp:127.0.0.1:2000 udp:127.0.0.1:2001").unwrap()
+        );
+
+        for value in &["tcp:127.0.0.1:2000 tcp:127.0.0.1:2001", "127.0.0.1", ""] {
+            let err = parse_daemon_address(value).unwrap_err();
+            assert!(err.to_string().contains(DAEMON_ADDRESS_ENV_VAR), "{}", err);
+        }
+    }
+
+    #[test]
+    fn marked_spans_are_sent_as_subsegments() {
+        let daemon = Daemon::bind();
+        let subscriber = tracing_subscriber::registry().with(daemon.layer());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _invocation = invocation(TRACE_HEADER).entered();
+            let _unmarked = info_span!("handler").entered();
+            let orders = info_span!("orders", xray = true, count = 2).entered();
+            let query = info_span!("DynamoDB", xray = "aws", table = tracing::field::Empty).entered();
+            query.record("table", "orders");
+            error!("Throttled");
+            drop(query);
+            drop(orders);
+        });
+
+        let subsegments = daemon.subsegments();
+        assert_eq!(2, subsegments.len());
+        let (query, orders) = (&subsegments[0], &subsegments[1]);
+
+        assert_eq!("orders", orders["name"]);
+        assert_eq!("subsegment", orders["type"]);
+        assert_eq!("1-5759e988-bd862e3fe1be46a994272793", orders["trace_id"]);
+        assert_eq!("53995c3f42cd8ad8", orders["parent_id"]);
+        assert_eq!(16, orders["id"].as_str().unwrap().len());
+        assert_eq!(json!({ "default": { "count": 2 } }), orders["metadata"]);
+        assert_eq!(None, orders.get("namespace"));
+        assert_eq!(None, orders.get("fault"));
+        assert!(orders["start_time"].as_f64().unwrap() <= orders["end_time"].as_f64().unwrap());
+
+        assert_eq!("DynamoDB", query["name"]);
+        assert_eq!(orders["trace_id"], query["trace_id"]);
+        assert_eq!(orders["id"], query["parent_id"]);
+        assert_ne!(orders["id"], query["id"]);
+        assert_eq!("aws", query["namespace"]);
+        assert_eq!(true, query["fault"]);
+        assert_eq!(json!({ "default": { "table": "orders" } }), query["metadata"]);
+    }
+
+    #[test]
+    fn unsampled_invocations_send_nothing() {
+        let daemon = Daemon::bind();
+        let subscriber = tracing_subscriber::registry().with(daemon.layer());
+
+        tracing::subscriber::with_default(subscriber, || {
+            for header in &[
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=0",
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8",
+                "",
+            ] {
+                let _invocation = invocation(header).entered();
+                info_span!("orders", xray = true).in_scope(|| {});
+            }
+            info_span!("outside", xray = true).in_scope(|| {});
+        });
+
+        assert!(daemon.subsegments().is_empty());
+    }
+}
+
+#[cfg(all(test, feature = "simulated"))]
+mod runtime_tests {
+    use super::tests::{Daemon, TRACE_HEADER};
+    use super::*;
+    use crate::{
+        handler_fn,
+        testing::{MockEvent, MockRuntime},
+        Context,
+    };
+    use tracing::{info_span, Instrument};
+
+    #[tokio::test]
+    async fn subsegments_are_children_of_the_invocation() -> Result<(), Error> {
+        let daemon = Daemon::bind();
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(daemon.layer()));
+
+        let mut runtime = MockRuntime::new();
+        runtime
+            .push_event(MockEvent::new(&json!({}))?.xray_trace_id(TRACE_HEADER))
+            .push_event(MockEvent::new(&json!({}))?.xray_trace_id(TRACE_HEADER.replace("Sampled=1", "Sampled=0")));
+        runtime
+            .run(handler_fn(|_: Value, _: Context| async {
+                async {}.instrument(info_span!("S3", xray = "aws")).await;
+                Ok::<_, Error>(json!({}))
+            }))
+            .await?;
+
+        let subsegments = daemon.subsegments();
+        assert_eq!(1, subsegments.len());
+        assert_eq!("S3", subsegments[0]["name"]);
+        assert_eq!("1-5759e988-bd862e3fe1be46a994272793", subsegments[0]["trace_id"]);
+        assert_eq!("53995c3f42cd8ad8", subsegments[0]["parent_id"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use lamedh_runtime::{lambda_test, Context};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Deserialize)]
+struct Greeting {
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    message: String,
+    function: String,
+}
+
+#[derive(Debug)]
+struct MissingName;
+
+impl fmt::Display for MissingName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "missing name")
+    }
+}
+
+impl std::error::Error for MissingName {}
+
+#[lambda_test("tests/events/*.json")]
+async fn greet(event: Greeting, ctx: Context) -> Result<Response, MissingName> {
+    match event.name {
+        Some(name) => Ok(Response {
+            message: format!("Hello, {}!", name),
+            function: ctx.env_config.function_name.clone(),
+        }),
+        None => Err(MissingName),
+    }
+}
+
+#[lambda_test("tests/events/shout/*.json")]
+fn shout(event: Greeting) -> String {
+    event.name.unwrap_or_default().to_uppercase()
+}